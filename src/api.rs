@@ -1,15 +1,21 @@
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, SocketAddr};
 
-use actix_web::{dev, get, web, App, HttpServer, Responder};
+use actix_web::{
+    dev, get, http::header, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    dns::{store::RecordStore, Record},
+    config::Zones,
+    dns::{doh, store::RecordStore, Record, ServerState},
     sources::SourceRecords,
     ServerId,
 };
 
+const DNS_MESSAGE: &str = "application/dns-message";
+
 #[derive(Debug, Clone, PartialEq, Deserialize)]
 pub(crate) struct ApiConfig {
     pub(crate) address: SocketAddr,
@@ -19,6 +25,7 @@ pub(crate) struct ApiConfig {
 struct AppData {
     server_id: ServerId,
     record_store: RecordStore,
+    server_state: ServerState<Zones>,
 }
 
 #[get("/records")]
@@ -63,6 +70,77 @@ async fn v2_records(app_data: web::Data<AppData>) -> impl Responder {
     web::Json(api_records)
 }
 
+#[get("/v2/conflicts")]
+async fn v2_conflicts(app_data: web::Data<AppData>) -> impl Responder {
+    web::Json(app_data.record_store.ptr_conflicts())
+}
+
+#[get("/v2/rate_limit")]
+async fn v2_rate_limit(app_data: web::Data<AppData>) -> impl Responder {
+    web::Json(app_data.server_state.rate_limit_counters())
+}
+
+async fn dns_query(app_data: &AppData, request: &HttpRequest, message: &[u8]) -> HttpResponse {
+    let source = request
+        .peer_addr()
+        .unwrap_or_else(|| SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0));
+
+    match doh::handle_message(&app_data.server_state, message, source).await {
+        Ok(response) => {
+            let mut builder = HttpResponse::Ok();
+            builder.content_type(DNS_MESSAGE);
+
+            if let Some(max_age) = response.max_age {
+                builder.insert_header((header::CACHE_CONTROL, format!("max-age={max_age}")));
+            }
+
+            builder.body(response.message)
+        }
+        Err(e) => {
+            tracing::debug!(error = %e, "Invalid DNS query");
+            HttpResponse::BadRequest().finish()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DnsQueryParams {
+    dns: String,
+}
+
+#[get("/dns-query")]
+async fn dns_query_get(
+    app_data: web::Data<AppData>,
+    request: HttpRequest,
+    params: web::Query<DnsQueryParams>,
+) -> impl Responder {
+    match URL_SAFE_NO_PAD.decode(params.dns.trim_end_matches('=')) {
+        Ok(message) => dns_query(&app_data, &request, &message).await,
+        Err(e) => {
+            tracing::debug!(error = %e, "Invalid base64 in DNS query");
+            HttpResponse::BadRequest().finish()
+        }
+    }
+}
+
+#[post("/dns-query")]
+async fn dns_query_post(
+    app_data: web::Data<AppData>,
+    request: HttpRequest,
+    body: web::Bytes,
+) -> impl Responder {
+    let content_type = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+
+    if content_type != Some(DNS_MESSAGE) {
+        return HttpResponse::UnsupportedMediaType().finish();
+    }
+
+    dns_query(&app_data, &request, &body).await
+}
+
 fn create_server(config: &ApiConfig, app_data: AppData) -> Option<(dev::Server, u16)> {
     tracing::info!(address = %config.address, "Starting API server");
 
@@ -71,6 +149,10 @@ fn create_server(config: &ApiConfig, app_data: AppData) -> Option<(dev::Server,
             .app_data(web::Data::new(app_data.clone()))
             .service(records)
             .service(v2_records)
+            .service(v2_conflicts)
+            .service(v2_rate_limit)
+            .service(dns_query_get)
+            .service(dns_query_post)
     })
     .disable_signals()
     .bind(config.address)
@@ -98,10 +180,12 @@ impl ApiServer {
         config: &ApiConfig,
         server_id: ServerId,
         record_store: RecordStore,
+        server_state: ServerState<Zones>,
     ) -> Option<Self> {
         let data = AppData {
             server_id,
             record_store,
+            server_state,
         };
 
         create_server(config, data).map(|(api_server, _port)| {
@@ -120,3 +204,191 @@ impl ApiServer {
         self.api_server.stop(!cfg!(test)).await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use hickory_server::proto::{
+        op::{Message, Query, ResponseCode},
+        rr::RecordType,
+    };
+    use reqwest::{header, Client, StatusCode};
+    use uuid::Uuid;
+
+    use crate::{
+        api::{ApiConfig, ApiServer, DNS_MESSAGE},
+        config::Zones,
+        dns::{store::RecordStore, RData, Record, RecordSet, ServerState},
+        sources::{SourceId, SourceType},
+        test::{fqdn, name, rdata_a},
+        ServerId,
+    };
+
+    fn query_message(query: &str, record_type: RecordType) -> Vec<u8> {
+        let mut message = Message::new();
+        message.add_query(Query::query(name(query), record_type));
+        message.to_vec().unwrap()
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dns_query() {
+        let record_store = RecordStore::new();
+        let source_id = SourceId::new(&Uuid::new_v4(), SourceType::File, "test");
+
+        let mut records = RecordSet::new();
+        records.insert(Record::new(
+            fqdn("www.home.local"),
+            RData::A("10.10.4.5".parse().unwrap()),
+        ));
+        record_store.add_source_records(&source_id, records).await;
+
+        let api_config = ApiConfig {
+            address: SocketAddr::from(([127, 0, 0, 1], 0)),
+        };
+
+        let api = ApiServer::new(
+            &api_config,
+            ServerId::new_v4(),
+            record_store.clone(),
+            ServerState::new(record_store.receiver(), Zones::default()),
+        )
+        .unwrap();
+        let url = format!("http://127.0.0.1:{}/dns-query", api.port);
+        let client = Client::new();
+
+        let response = client
+            .get(&url)
+            .query(&[(
+                "dns",
+                URL_SAFE_NO_PAD.encode(query_message("www.home.local.", RecordType::A)),
+            )])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            DNS_MESSAGE
+        );
+        assert_eq!(
+            response.headers().get(header::CACHE_CONTROL).unwrap(),
+            "max-age=300"
+        );
+
+        let message = Message::from_vec(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(message.response_code(), ResponseCode::NoError);
+        assert_eq!(message.answers().len(), 1);
+        assert_eq!(message.answers()[0].data().unwrap(), &rdata_a("10.10.4.5"));
+
+        let response = client
+            .post(&url)
+            .header(header::CONTENT_TYPE, DNS_MESSAGE)
+            .body(query_message("missing.home.local.", RecordType::A))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let message = Message::from_vec(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(message.response_code(), ResponseCode::NXDomain);
+        assert!(message.answers().is_empty());
+
+        let response = client
+            .post(&url)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(query_message("www.home.local.", RecordType::A))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let response = client
+            .get(&url)
+            .query(&[("dns", "not a message")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Responses over HTTPS are never rate limited.
+        let response = client
+            .get(format!("http://127.0.0.1:{}/v2/rate_limit", api.port))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.text().await.unwrap(),
+            r#"{"dropped":0,"slipped":0}"#
+        );
+
+        api.shutdown().await;
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn conflicts() {
+        let record_store = RecordStore::new();
+        let server_id = Uuid::new_v4();
+
+        let mut records = RecordSet::new();
+        records.insert(Record::new(
+            fqdn("www.home.local"),
+            RData::A("10.10.4.5".parse().unwrap()),
+        ));
+        records.insert(Record::new(
+            fqdn("unique.home.local"),
+            RData::A("10.10.4.6".parse().unwrap()),
+        ));
+        record_store
+            .add_source_records(
+                &SourceId::new(&server_id, SourceType::File, "test"),
+                records,
+            )
+            .await;
+
+        let mut records = RecordSet::new();
+        records.insert(Record::new(
+            fqdn("other.home.local"),
+            RData::A("10.10.4.5".parse().unwrap()),
+        ));
+        record_store
+            .add_source_records(
+                &SourceId::new(&server_id, SourceType::Dhcp, "test"),
+                records,
+            )
+            .await;
+
+        let api_config = ApiConfig {
+            address: SocketAddr::from(([127, 0, 0, 1], 0)),
+        };
+
+        let api = ApiServer::new(
+            &api_config,
+            ServerId::new_v4(),
+            record_store.clone(),
+            ServerState::new(record_store.receiver(), Zones::default()),
+        )
+        .unwrap();
+
+        let response = Client::new()
+            .get(format!("http://127.0.0.1:{}/v2/conflicts", api.port))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.text().await.unwrap(),
+            concat!(
+                r#"[{"address":"10.10.4.5","names":["#,
+                r#"{"name":"other.home.local.","sources":["dhcp"]},"#,
+                r#"{"name":"www.home.local.","sources":["file"]}]}]"#
+            )
+        );
+
+        assert!(logs_contain("Multiple names share an address"));
+    }
+}
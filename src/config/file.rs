@@ -1,5 +1,6 @@
 use std::{collections::HashMap, fmt};
 
+use anyhow::Error;
 use figment::value::magic::RelativePathBuf;
 use reqwest::Url;
 use serde::{
@@ -9,8 +10,9 @@ use serde::{
 
 use crate::{
     api::ApiConfig,
-    dns::{Fqdn, ServerConfig, Upstream},
-    sources::SourcesConfig,
+    dns::{DnssecConfig, Fqdn, PtrNames, ServerConfig, TsigKey, Upstream, UpstreamMode},
+    sources::{SourceSelector, SourcesConfig},
+    util::{Address, Network},
 };
 
 struct UrlVisitor;
@@ -37,13 +39,41 @@ where
     de.deserialize_str(UrlVisitor)
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub(super) enum UpstreamOneOrMany {
+    List(Vec<Upstream>),
+    Upstream(Upstream),
+}
+
+impl UpstreamOneOrMany {
+    pub(super) fn upstreams(&self) -> &[Upstream] {
+        match self {
+            UpstreamOneOrMany::List(upstreams) => upstreams,
+            UpstreamOneOrMany::Upstream(upstream) => std::slice::from_ref(upstream),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
 pub(super) struct DefaultZoneConfig {
     #[serde(default)]
-    pub(super) upstream: Option<Upstream>,
+    pub(super) upstream: Option<UpstreamOneOrMany>,
+
+    #[serde(default)]
+    pub(super) upstream_mode: Option<UpstreamMode>,
 
     #[serde(default)]
     pub(super) ttl: Option<u32>,
+
+    #[serde(default)]
+    pub(super) ptr_names: Option<PtrNames>,
+
+    #[serde(default)]
+    pub(super) allow_query: Option<Vec<Network>>,
+
+    #[serde(default)]
+    pub(super) allow_recursion: Option<Vec<Network>>,
 }
 
 #[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
@@ -53,6 +83,59 @@ pub(super) struct PartialZoneConfig {
 
     #[serde(default)]
     pub(super) authoritative: Option<bool>,
+
+    #[serde(default)]
+    pub(super) allow_transfer: Option<Vec<Network>>,
+
+    #[serde(default)]
+    pub(super) notify: Option<Vec<Address>>,
+
+    #[serde(default)]
+    pub(super) transfer_keys: Option<Vec<Fqdn>>,
+
+    #[serde(default)]
+    pub(super) update_keys: Option<Vec<Fqdn>>,
+
+    #[serde(default)]
+    pub(super) notify_key: Option<Fqdn>,
+
+    #[serde(default)]
+    pub(super) dnssec: Option<DnssecConfig>,
+}
+
+/// The key of a zone in the configuration, either its origin or a network in
+/// CIDR notation for the zone holding that network's reverse lookups.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub(super) struct ZoneOrigin(pub(super) Fqdn);
+
+impl TryFrom<String> for ZoneOrigin {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if value.contains('/') {
+            Ok(Self(value.parse::<Network>()?.reverse_zone()?))
+        } else {
+            Ok(Self(Fqdn::try_from(value)?))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub(super) struct ViewZoneConfig {
+    #[serde(default)]
+    pub(super) upstream: Option<UpstreamOneOrMany>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub(super) struct ViewConfig {
+    pub(super) networks: Vec<Network>,
+
+    #[serde(default)]
+    pub(super) sources: Option<Vec<SourceSelector>>,
+
+    #[serde(default)]
+    pub(super) zones: HashMap<ZoneOrigin, ViewZoneConfig>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -60,6 +143,9 @@ pub(super) struct ConfigFile {
     #[serde(default)]
     pub(super) pid_file: Option<RelativePathBuf>,
 
+    #[serde(default)]
+    pub(super) data_dir: Option<RelativePathBuf>,
+
     #[serde(default)]
     pub(super) defaults: DefaultZoneConfig,
 
@@ -73,5 +159,11 @@ pub(super) struct ConfigFile {
     pub(super) sources: SourcesConfig,
 
     #[serde(default)]
-    pub(super) zones: HashMap<Fqdn, PartialZoneConfig>,
+    pub(super) zones: HashMap<ZoneOrigin, PartialZoneConfig>,
+
+    #[serde(default)]
+    pub(super) tsig_keys: HashMap<Fqdn, TsigKey>,
+
+    #[serde(default)]
+    pub(super) views: HashMap<String, ViewConfig>,
 }
@@ -1,22 +1,29 @@
 use std::{
     collections::{HashMap, VecDeque},
     fmt, fs,
-    path::Path,
+    net::IpAddr,
+    path::{Path, PathBuf},
     process,
 };
 
+use anyhow::bail;
 use figment::{
     providers::{Env, Format, Yaml},
     value::{Uncased, UncasedStr},
     Figment,
 };
-use hickory_server::proto::{rr, rr::rdata::SOA};
+use hickory_server::proto::rr::{
+    self,
+    domain::usage::{IN_ADDR_ARPA, IP6_ARPA},
+    rdata::SOA,
+};
 use tracing::instrument;
 
 use crate::{
     api::ApiConfig,
-    dns::{Fqdn, ServerConfig, Upstream},
-    sources::SourcesConfig,
+    dns::{DnssecConfig, Fqdn, PtrNames, ServerConfig, TsigKey, Upstream, UpstreamMode},
+    sources::{SourceId, SourceSelector, SourcesConfig},
+    util::{Address, Network},
     Error,
 };
 
@@ -27,8 +34,29 @@ pub(crate) use file::deserialize_url;
 pub(crate) struct ZoneConfig {
     pub(crate) origin: Option<Fqdn>,
     pub(crate) upstreams: VecDeque<Upstream>,
+    pub(crate) upstream_mode: UpstreamMode,
     pub(crate) ttl: u32,
     pub(crate) authoritative: bool,
+    /// Which names to answer reverse lookups with.
+    pub(crate) ptr_names: PtrNames,
+    /// Networks that may query the zone, or anyone if not set.
+    pub(crate) allow_query: Option<Vec<Network>>,
+    /// Networks that may have queries for the zone sent upstream, or anyone
+    /// if not set.
+    pub(crate) allow_recursion: Option<Vec<Network>>,
+    /// Networks that may request zone transfers.
+    pub(crate) allow_transfer: Vec<Network>,
+    /// Secondaries to notify when the zone changes.
+    pub(crate) notify: Vec<Address>,
+    /// TSIG keys that may be used to request zone transfers.
+    pub(crate) transfer_keys: Vec<Fqdn>,
+    /// TSIG keys that may be used to update the zone.
+    pub(crate) update_keys: Vec<Fqdn>,
+    /// The TSIG key to sign NOTIFY messages with.
+    pub(crate) notify_key: Option<Fqdn>,
+    /// The keys to sign the zone with.
+    pub(crate) dnssec: Option<DnssecConfig>,
+    pub(crate) serial: u32,
 }
 
 impl Default for ZoneConfig {
@@ -36,8 +64,19 @@ impl Default for ZoneConfig {
         Self {
             origin: None,
             upstreams: VecDeque::new(),
+            upstream_mode: UpstreamMode::default(),
             ttl: 300,
             authoritative: false,
+            ptr_names: PtrNames::default(),
+            allow_query: None,
+            allow_recursion: None,
+            allow_transfer: Vec::new(),
+            notify: Vec::new(),
+            transfer_keys: Vec::new(),
+            update_keys: Vec::new(),
+            notify_key: None,
+            dnssec: None,
+            serial: 0,
         }
     }
 }
@@ -46,14 +85,62 @@ impl From<&file::DefaultZoneConfig> for ZoneConfig {
     fn from(defaults: &file::DefaultZoneConfig) -> Self {
         Self {
             origin: None,
-            upstreams: VecDeque::from_iter(defaults.upstream.iter().cloned()),
+            upstreams: defaults
+                .upstream
+                .iter()
+                .flat_map(|upstream| upstream.upstreams())
+                .cloned()
+                .collect(),
+            upstream_mode: defaults.upstream_mode.unwrap_or_default(),
             ttl: defaults.ttl.unwrap_or(300),
             authoritative: false,
+            ptr_names: defaults.ptr_names.clone().unwrap_or_default(),
+            allow_query: defaults.allow_query.clone(),
+            allow_recursion: defaults.allow_recursion.clone(),
+            allow_transfer: Vec::new(),
+            notify: Vec::new(),
+            transfer_keys: Vec::new(),
+            update_keys: Vec::new(),
+            notify_key: None,
+            dnssec: None,
+            serial: 0,
         }
     }
 }
 
+fn allowed(networks: &Option<Vec<Network>>, source: &IpAddr) -> bool {
+    networks
+        .as_ref()
+        .is_none_or(|networks| networks.iter().any(|network| network.contains(source)))
+}
+
 impl ZoneConfig {
+    /// Whether a client may query the zone.
+    pub(crate) fn allows_query(&self, source: &IpAddr) -> bool {
+        allowed(&self.allow_query, source)
+    }
+
+    /// Whether a client may have its queries for the zone sent upstream.
+    pub(crate) fn allows_recursion(&self, source: &IpAddr) -> bool {
+        allowed(&self.allow_recursion, source)
+    }
+
+    /// Whether unknown names in the zone are looked up upstream. An
+    /// authoritative reverse zone answers for every address in its network
+    /// itself so private addresses never leak upstream.
+    pub(crate) fn forwards(&self) -> bool {
+        if self.upstreams.is_empty() {
+            return false;
+        }
+
+        let is_reverse = self
+            .origin
+            .as_ref()
+            .is_some_and(|origin| IN_ADDR_ARPA.zone_of(origin) || IP6_ARPA.zone_of(origin));
+
+        !(self.authoritative && is_reverse)
+    }
+
     pub(crate) fn soa(&self) -> Option<rr::Record> {
         if !self.authoritative {
             return None;
@@ -67,7 +154,7 @@ impl ZoneConfig {
             rr::RData::SOA(SOA::new(
                 origin.child("ns").ok()?.name(),
                 origin.child("hostmaster").ok()?.name(),
-                0,
+                self.serial,
                 self.ttl.try_into().unwrap(),
                 self.ttl.try_into().unwrap(),
                 (self.ttl * 10).try_into().unwrap(),
@@ -79,13 +166,45 @@ impl ZoneConfig {
     fn apply_config(&mut self, origin: Fqdn, config: &file::PartialZoneConfig) {
         self.origin = Some(origin);
 
+        // A zone's own upstreams are tried before any that it inherits.
         if let Some(ref upstream) = config.config.upstream {
-            self.upstreams.push_front(upstream.clone());
+            for upstream in upstream.upstreams().iter().rev() {
+                self.upstreams.push_front(upstream.clone());
+            }
+        }
+        if let Some(upstream_mode) = config.config.upstream_mode {
+            self.upstream_mode = upstream_mode;
         }
         if let Some(ttl) = config.config.ttl {
             self.ttl = ttl;
         }
+        if let Some(ref ptr_names) = config.config.ptr_names {
+            self.ptr_names = ptr_names.clone();
+        }
+        if let Some(ref allow_query) = config.config.allow_query {
+            self.allow_query = Some(allow_query.clone());
+        }
+        if let Some(ref allow_recursion) = config.config.allow_recursion {
+            self.allow_recursion = Some(allow_recursion.clone());
+        }
         self.authoritative = config.authoritative.unwrap_or(true);
+        if let Some(ref allow_transfer) = config.allow_transfer {
+            self.allow_transfer = allow_transfer.clone();
+        }
+        if let Some(ref notify) = config.notify {
+            self.notify = notify.clone();
+        }
+        if let Some(ref transfer_keys) = config.transfer_keys {
+            self.transfer_keys = transfer_keys.clone();
+        }
+        if let Some(ref update_keys) = config.update_keys {
+            self.update_keys = update_keys.clone();
+        }
+        if let Some(ref notify_key) = config.notify_key {
+            self.notify_key = Some(notify_key.clone());
+        }
+        // Signing keys belong to a single zone so are not inherited.
+        self.dnssec = config.dnssec.clone();
     }
 }
 
@@ -102,32 +221,151 @@ impl fmt::Debug for ZoneConfig {
         if !self.upstreams.is_empty() {
             let strings: Vec<String> = self.upstreams.iter().map(|u| format!("{u:?}")).collect();
             parts.push(format!("upstream={:?}", strings.join(",")));
+            parts.push(format!("upstream_mode={:?}", self.upstream_mode));
         }
 
         f.pad(&format!("[{}]", parts.join(" ")))
     }
 }
 
+/// What clients from some networks see, the sources whose records are visible
+/// and the upstreams to use for zones.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct View {
+    pub(crate) name: String,
+    networks: Vec<Network>,
+    /// The sources to include, or all sources if not set.
+    pub(crate) sources: Option<Vec<SourceSelector>>,
+    upstreams: Vec<(Fqdn, VecDeque<Upstream>)>,
+}
+
+impl View {
+    fn new(name: String, config: file::ViewConfig) -> Self {
+        let mut upstreams: Vec<(Fqdn, VecDeque<Upstream>)> = config
+            .zones
+            .into_iter()
+            .filter_map(|(origin, config)| {
+                Some((
+                    origin.0,
+                    config.upstream?.upstreams().iter().cloned().collect(),
+                ))
+            })
+            .collect();
+        upstreams.sort_by(|(n1, _), (n2, _)| n1.cmp(n2));
+
+        Self {
+            name,
+            networks: config.networks,
+            sources: config.sources,
+            upstreams,
+        }
+    }
+
+    /// The longest prefix of the view's networks that contains the client.
+    fn client_prefix(&self, client: &IpAddr) -> Option<u8> {
+        self.networks
+            .iter()
+            .filter(|network| network.contains(client))
+            .map(|network| network.prefix)
+            .max()
+    }
+
+    pub(crate) fn includes_source(&self, source_id: &SourceId) -> bool {
+        self.sources
+            .as_ref()
+            .is_none_or(|sources| sources.iter().any(|selector| selector.matches(source_id)))
+    }
+
+    /// The upstreams that the view uses for a name in place of the zone's.
+    pub(crate) fn upstreams(&self, name: &Fqdn) -> Option<&VecDeque<Upstream>> {
+        self.upstreams
+            .iter()
+            .rev()
+            .find(|(origin, _)| origin.zone_of(name))
+            .map(|(_, upstreams)| upstreams)
+    }
+}
+
 #[derive(Clone, Default, Debug, PartialEq, Eq)]
 pub(crate) struct Zones {
     defaults: file::DefaultZoneConfig,
     zones: Vec<(Fqdn, file::PartialZoneConfig)>,
+    tsig_keys: HashMap<Fqdn, TsigKey>,
+    views: Vec<View>,
 }
 
 impl Zones {
     fn new(
         defaults: file::DefaultZoneConfig,
-        mut zones: HashMap<Fqdn, file::PartialZoneConfig>,
+        zones: HashMap<file::ZoneOrigin, file::PartialZoneConfig>,
+        tsig_keys: HashMap<Fqdn, TsigKey>,
+        views: HashMap<String, file::ViewConfig>,
     ) -> Self {
-        let mut zones: Vec<(Fqdn, file::PartialZoneConfig)> = zones.drain().collect();
+        let mut zones: Vec<(Fqdn, file::PartialZoneConfig)> = zones
+            .into_iter()
+            .map(|(origin, config)| (origin.0, config))
+            .collect();
         zones.sort_by(|(n1, _), (n2, _)| n1.cmp(n2));
 
-        Self { defaults, zones }
+        let mut views: Vec<View> = views
+            .into_iter()
+            .map(|(name, config)| View::new(name, config))
+            .collect();
+        views.sort_by(|v1, v2| v1.name.cmp(&v2.name));
+
+        Self {
+            defaults,
+            zones,
+            tsig_keys,
+            views,
+        }
+    }
+
+    /// The keys that may be used to sign requests, by name.
+    pub(crate) fn tsig_keys(&self) -> &HashMap<Fqdn, TsigKey> {
+        &self.tsig_keys
+    }
+
+    /// Checks that every TSIG key that a zone refers to exists.
+    fn validate(&self) -> Result<(), Error> {
+        for (origin, config) in &self.zones {
+            let keys = config
+                .transfer_keys
+                .iter()
+                .flatten()
+                .chain(config.update_keys.iter().flatten())
+                .chain(config.notify_key.iter());
+
+            for key in keys {
+                if !self.tsig_keys.contains_key(key) {
+                    bail!("Zone {origin} refers to unknown TSIG key {key}");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The origins of all configured zones.
+    pub(crate) fn origins(&self) -> Vec<Fqdn> {
+        self.zones
+            .iter()
+            .map(|(origin, _)| origin.clone())
+            .collect()
     }
 }
 
 pub(crate) trait ZoneConfigProvider {
     fn zone_config(&self, fqdn: &Fqdn) -> ZoneConfig;
+
+    fn tsig_key(&self, _name: &Fqdn) -> Option<TsigKey> {
+        None
+    }
+
+    /// The view that a client belongs to.
+    fn view(&self, _client: &IpAddr) -> Option<View> {
+        None
+    }
 }
 
 impl ZoneConfigProvider for Zones {
@@ -142,6 +380,21 @@ impl ZoneConfigProvider for Zones {
 
         config
     }
+
+    fn tsig_key(&self, name: &Fqdn) -> Option<TsigKey> {
+        self.tsig_keys.get(name).cloned()
+    }
+
+    /// Clients belong to the view with the most specific network containing
+    /// them.
+    fn view(&self, client: &IpAddr) -> Option<View> {
+        self.views
+            .iter()
+            .filter_map(|view| Some((view.client_prefix(client)?, view)))
+            .rev()
+            .max_by_key(|(prefix, _)| *prefix)
+            .map(|(_, view)| view.clone())
+    }
 }
 
 fn map_env(key: &UncasedStr) -> Uncased<'_> {
@@ -167,6 +420,8 @@ pub(crate) struct Config {
     pub api: Option<ApiConfig>,
     pub sources: SourcesConfig,
     pub(crate) zones: Zones,
+    /// Where to store state that should survive a restart.
+    pub(crate) data_dir: Option<PathBuf>,
 }
 
 impl Config {
@@ -174,10 +429,7 @@ impl Config {
     pub(crate) fn from_file(config_file: &Path) -> Result<Config, Error> {
         tracing::info!("Reading configuration");
 
-        let config: file::ConfigFile = Figment::new()
-            .join(Env::prefixed("LOCALNS_").map(map_env).lowercase(false))
-            .join(Yaml::file_exact(config_file))
-            .extract()?;
+        let config = Self::read_file(config_file)?;
 
         if let Some(path) = config.pid_file {
             let id = process::id();
@@ -186,22 +438,56 @@ impl Config {
             }
         }
 
+        let zones = Zones::new(
+            config.defaults,
+            config.zones,
+            config.tsig_keys,
+            config.views,
+        );
+        zones.validate()?;
+
         Ok(Config {
             server: config.server,
             api: config.api,
             sources: config.sources,
-            zones: Zones::new(config.defaults, config.zones),
+            zones,
+            data_dir: config.data_dir.map(|path| path.relative()),
         })
     }
+
+    /// Reads just the zones from the configuration without any side effects.
+    pub(crate) fn zones_from_file(config_file: &Path) -> Result<Zones, Error> {
+        let config = Self::read_file(config_file)?;
+
+        let zones = Zones::new(
+            config.defaults,
+            config.zones,
+            config.tsig_keys,
+            config.views,
+        );
+        zones.validate()?;
+
+        Ok(zones)
+    }
+
+    fn read_file(config_file: &Path) -> Result<file::ConfigFile, Error> {
+        Ok(Figment::new()
+            .join(Env::prefixed("LOCALNS_").map(map_env).lowercase(false))
+            .join(Yaml::file_exact(config_file))
+            .extract()?)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use tempfile::TempDir;
 
+    use uuid::Uuid;
+
     use crate::{
         config::{Config, ZoneConfigProvider},
-        sources::docker,
+        dns::UpstreamMode,
+        sources::{docker, SourceId, SourceType},
         test::{fqdn, write_file},
     };
 
@@ -234,6 +520,12 @@ zones:
   home.local: {}
   other.local:
     upstream: 10.10.15.250:5353
+  race.local:
+    upstream_mode: race
+    upstream:
+      - tls://1.1.1.1#cloudflare-dns.com
+      - address: 10.10.16.250
+        timeout_ms: 500
 "#,
         )
         .await;
@@ -261,10 +553,143 @@ zones:
             zone_config.upstreams.get(1).unwrap().config.address(5324),
             "10.10.14.250:5324"
         );
+        assert_eq!(zone_config.upstream_mode, UpstreamMode::Failover);
+
+        let zone_config = config.zones.zone_config(&fqdn("www.race.local"));
+
+        assert_eq!(zone_config.upstream_mode, UpstreamMode::Race);
+        let upstreams: Vec<String> = zone_config
+            .upstreams
+            .iter()
+            .map(|upstream| upstream.config.to_string())
+            .collect();
+        assert_eq!(
+            upstreams,
+            vec![
+                "tls://1.1.1.1#cloudflare-dns.com",
+                "10.10.16.250",
+                "10.10.14.250"
+            ]
+        );
 
         assert_eq!(config.sources.docker.len(), 1);
         let (name, docker_config) = config.sources.docker.iter().next().unwrap();
         assert_eq!(name, "local");
         assert!(matches!(docker_config, docker::DockerConfig::Local {}));
     }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn views() {
+        let temp = TempDir::new().unwrap();
+
+        let config_file = temp.path().join("config.yml");
+        write_file(
+            &config_file,
+            r#"
+defaults:
+  upstream: 10.10.14.250
+
+zones:
+  mossop.dev:
+    upstream: 10.10.15.250
+
+views:
+  vpn:
+    networks: [10.8.0.0/24, "fd00:8::/64"]
+    sources: [traefik:vpn, file]
+    zones:
+      mossop.dev:
+        upstream: 10.8.0.1
+  lan:
+    networks: [10.0.0.0/8]
+"#,
+        )
+        .await;
+
+        let config = Config::from_file(&config_file).unwrap();
+
+        let view = config.zones.view(&"10.8.0.5".parse().unwrap()).unwrap();
+        assert_eq!(view.name, "vpn");
+        let upstreams: Vec<String> = view
+            .upstreams(&fqdn("app.mossop.dev."))
+            .unwrap()
+            .iter()
+            .map(|upstream| upstream.config.to_string())
+            .collect();
+        assert_eq!(upstreams, vec!["10.8.0.1"]);
+        assert!(view.upstreams(&fqdn("www.example.org.")).is_none());
+
+        let server_id = Uuid::new_v4();
+        assert!(view.includes_source(&SourceId::new(&server_id, SourceType::Traefik, "vpn")));
+        assert!(!view.includes_source(&SourceId::new(&server_id, SourceType::Traefik, "lan")));
+        assert!(view.includes_source(&SourceId::new(&server_id, SourceType::File, "lan")));
+
+        let view = config.zones.view(&"fd00:8::5".parse().unwrap()).unwrap();
+        assert_eq!(view.name, "vpn");
+
+        let view = config.zones.view(&"10.10.0.5".parse().unwrap()).unwrap();
+        assert_eq!(view.name, "lan");
+        assert!(view.includes_source(&SourceId::new(&server_id, SourceType::Docker, "local")));
+
+        assert!(config.zones.view(&"192.168.0.5".parse().unwrap()).is_none());
+
+        write_file(
+            &config_file,
+            r#"
+views:
+  vpn:
+    networks: [10.8.0.0/24]
+    sources: [unknown:vpn]
+"#,
+        )
+        .await;
+
+        assert!(Config::from_file(&config_file).is_err());
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn reverse_zones() {
+        let temp = TempDir::new().unwrap();
+
+        let config_file = temp.path().join("config.yml");
+        write_file(
+            &config_file,
+            r#"
+zones:
+  10.10.0.0/16:
+    authoritative: true
+  "fd12:3400::/24":
+    ttl: 60
+"#,
+        )
+        .await;
+
+        let config = Config::from_file(&config_file).unwrap();
+
+        let zone_config = config.zones.zone_config(&fqdn("3.5.10.10.in-addr.arpa."));
+        assert_eq!(zone_config.origin, Some(fqdn("10.10.in-addr.arpa.")));
+        assert!(zone_config.authoritative);
+
+        let zone_config = config.zones.zone_config(&fqdn(
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.4.3.2.1.d.f.ip6.arpa.",
+        ));
+        assert_eq!(zone_config.origin, Some(fqdn("4.3.2.1.d.f.ip6.arpa.")));
+        assert_eq!(zone_config.ttl, 60);
+
+        let zone_config = config.zones.zone_config(&fqdn("3.5.11.10.in-addr.arpa."));
+        assert_eq!(zone_config.origin, None);
+
+        write_file(
+            &config_file,
+            r#"
+zones:
+  10.10.0.0/20: {}
+"#,
+        )
+        .await;
+
+        assert!(Config::from_file(&config_file).is_err());
+    }
 }
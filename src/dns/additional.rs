@@ -0,0 +1,281 @@
+use std::collections::HashSet;
+
+use hickory_server::proto::{
+    rr::{self, DNSClass, Name, RecordType},
+    serialize::binary::BinEncodable,
+};
+
+use crate::{
+    config::ZoneConfigProvider,
+    dns::{query::QueryState, Fqdn, LockedServerState, RData},
+};
+
+/// The size of a DNS message header.
+const HEADER_SIZE: usize = 12;
+
+fn encoded_len<'a>(records: impl IntoIterator<Item = &'a rr::Record>) -> usize {
+    records
+        .into_iter()
+        .map(|record| record.to_bytes().map(|bytes| bytes.len()).unwrap_or(0))
+        .sum()
+}
+
+impl<Z: ZoneConfigProvider> LockedServerState<Z> {
+    /// Adds the locally known addresses of the targets of SRV, MX and NS
+    /// answers to the additional section so that clients don't need to look
+    /// them up separately.
+    pub(super) fn add_target_addresses(&self, query_state: &mut QueryState) {
+        let mut seen: HashSet<Name> = query_state
+            .answers()
+            .iter()
+            .chain(query_state.additionals())
+            .filter(|record| matches!(record.record_type(), RecordType::A | RecordType::AAAA))
+            .map(|record| record.name().clone())
+            .collect();
+
+        let targets: Vec<Name> = query_state
+            .answers()
+            .iter()
+            .filter_map(|record| match record.data()? {
+                rr::RData::SRV(srv) => Some(srv.target().clone()),
+                rr::RData::MX(mx) => Some(mx.exchange().clone()),
+                rr::RData::NS(ns) => Some(ns.0.clone()),
+                _ => None,
+            })
+            .filter(|target| !target.is_root())
+            .collect();
+
+        let mut additionals = Vec::new();
+
+        for target in targets {
+            if !seen.insert(target.clone()) {
+                continue;
+            }
+
+            let config = self.zone_config(&Fqdn::from(target.clone()));
+
+            for record_type in [RecordType::A, RecordType::AAAA] {
+                additionals.extend(
+                    self.records
+                        .lookup(&target, DNSClass::IN, record_type)
+                        .filter(|record| matches!(record.rdata(), RData::A(_) | RData::Aaaa(_)))
+                        .filter_map(|record| {
+                            // Wildcard records are returned with the wildcard
+                            // as their name.
+                            let mut record = record.raw(&config)?;
+                            record.set_name(target.clone());
+                            Some(record)
+                        }),
+                );
+            }
+        }
+
+        query_state.add_additionals(additionals);
+    }
+
+    /// Makes a response fit within the size that the client can accept. The
+    /// answers for names outside of the queried name's zone, reached by
+    /// following a CNAME chain, are moved to the additional section and then
+    /// additional records are dropped until the response fits. The response
+    /// is marked as truncated if any of the moved answers had to be dropped
+    /// or it still doesn't fit.
+    pub(super) fn limit_response_size(&self, query_state: &mut QueryState, max_size: usize) {
+        let size = |query_state: &QueryState| {
+            HEADER_SIZE
+                + query_state.query.to_bytes().map(|b| b.len()).unwrap_or(0)
+                + encoded_len(query_state.answers())
+                + encoded_len(query_state.name_servers())
+                + encoded_len(query_state.soa())
+                + encoded_len(query_state.additionals())
+        };
+
+        if size(query_state) <= max_size {
+            return;
+        }
+
+        let origin = self
+            .zone_config(&Fqdn::from(query_state.query.name().clone()))
+            .origin;
+        let query_name = query_state.query.name().clone();
+
+        let moved = query_state.move_to_additionals(|record| {
+            *record.name() != query_name
+                && self.zone_config(&Fqdn::from(record.name().clone())).origin != origin
+        });
+
+        while size(query_state) > max_size && query_state.pop_additional().is_some() {
+            if query_state.additionals().len() < moved {
+                query_state.truncated = true;
+            }
+        }
+
+        if size(query_state) > max_size {
+            query_state.truncated = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_server::proto::{
+        op::Query,
+        rr::{self, RecordType},
+    };
+    use tokio::sync::watch::channel;
+
+    use crate::{
+        config::{ZoneConfig, ZoneConfigProvider},
+        dns::{query::QueryState, Fqdn, RData, Record, RecordSet, ServerState},
+        test::{fqdn, name, rdata_a},
+    };
+
+    #[derive(Clone)]
+    struct Zones {}
+
+    impl ZoneConfigProvider for Zones {
+        fn zone_config(&self, name: &Fqdn) -> ZoneConfig {
+            let mut config = ZoneConfig::default();
+
+            for origin in ["home.local.", "other.local."] {
+                let origin = fqdn(origin);
+                if origin.zone_of(name) {
+                    config.origin = Some(origin);
+                    config.authoritative = true;
+                }
+            }
+
+            config
+        }
+    }
+
+    fn names(records: &[rr::Record]) -> Vec<(String, RecordType)> {
+        let mut records: Vec<(String, RecordType)> = records
+            .iter()
+            .map(|record| (record.name().to_string(), record.record_type()))
+            .collect();
+        records.sort();
+        records
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn target_addresses() {
+        let mut records = RecordSet::new();
+        records.insert(Record::new(
+            fqdn("_http._tcp.home.local."),
+            RData::Srv {
+                priority: 10,
+                weight: 5,
+                port: 80,
+                target: fqdn("www.home.local."),
+            },
+        ));
+        records.insert(Record::new(
+            fqdn("home.local."),
+            RData::Mx {
+                preference: 10,
+                exchange: fqdn("mail.home.local."),
+            },
+        ));
+        records.insert(Record::new(
+            fqdn("home.local."),
+            RData::Mx {
+                preference: 20,
+                exchange: fqdn("mail.example.org."),
+            },
+        ));
+        records.insert(Record::new(
+            fqdn("www.home.local."),
+            RData::A("10.10.5.3".parse().unwrap()),
+        ));
+        records.insert(Record::new(
+            fqdn("www.home.local."),
+            RData::Aaaa("fd00::3".parse().unwrap()),
+        ));
+        records.insert(Record::new(
+            fqdn("*.home.local."),
+            RData::A("10.10.5.4".parse().unwrap()),
+        ));
+
+        let (_, receiver) = channel(records);
+        let server_state = ServerState::new(receiver, Zones {}).locked().await;
+
+        let mut query_state = QueryState::new(
+            Query::query(name("_http._tcp.home.local."), RecordType::SRV),
+            false,
+        );
+        server_state.perform_query(&mut query_state).await;
+        assert_eq!(query_state.answers().len(), 1);
+        assert_eq!(
+            names(query_state.additionals()),
+            vec![
+                ("www.home.local.".to_owned(), RecordType::A),
+                ("www.home.local.".to_owned(), RecordType::AAAA),
+            ]
+        );
+
+        // Wildcard matches take the target's name and unknown targets are
+        // skipped.
+        let mut query_state =
+            QueryState::new(Query::query(name("home.local."), RecordType::MX), false);
+        server_state.perform_query(&mut query_state).await;
+        assert_eq!(query_state.answers().len(), 2);
+        assert_eq!(
+            names(query_state.additionals()),
+            vec![("mail.home.local.".to_owned(), RecordType::A)]
+        );
+        assert_eq!(
+            *query_state.additionals().first().unwrap().data().unwrap(),
+            rdata_a("10.10.5.4")
+        );
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn limit_response_size() {
+        let mut records = RecordSet::new();
+        records.insert(Record::new(
+            fqdn("www.home.local."),
+            RData::Cname(fqdn("www.other.local.")),
+        ));
+        for i in 1..=20 {
+            records.insert(Record::new(
+                fqdn("www.other.local."),
+                RData::A(format!("10.10.5.{i}").parse().unwrap()),
+            ));
+        }
+
+        let (_, receiver) = channel(records);
+        let server_state = ServerState::new(receiver, Zones {}).locked().await;
+
+        let query = || QueryState::new(Query::query(name("www.home.local."), RecordType::A), false);
+
+        let mut query_state = query();
+        server_state.perform_query(&mut query_state).await;
+        assert_eq!(query_state.answers().len(), 21);
+
+        // Responses that fit are left alone.
+        server_state.limit_response_size(&mut query_state, 4096);
+        assert_eq!(query_state.answers().len(), 21);
+        assert!(query_state.additionals().is_empty());
+        assert!(!query_state.truncated);
+
+        // Otherwise the out of zone part of the chain is moved to the
+        // additional section and trimmed.
+        server_state.limit_response_size(&mut query_state, 200);
+        assert_eq!(
+            names(query_state.answers()),
+            vec![("www.home.local.".to_owned(), RecordType::CNAME)]
+        );
+        let additionals = query_state.additionals().len();
+        assert!(additionals > 0 && additionals < 20);
+        assert!(query_state.truncated);
+
+        let mut query_state = query();
+        server_state.perform_query(&mut query_state).await;
+        server_state.limit_response_size(&mut query_state, 50);
+        assert_eq!(query_state.answers().len(), 1);
+        assert!(query_state.additionals().is_empty());
+        assert!(query_state.truncated);
+    }
+}
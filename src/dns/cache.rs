@@ -0,0 +1,478 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::Duration,
+};
+
+use hickory_server::proto::{
+    op::{Message, ResponseCode},
+    rr::{self, DNSClass, Name, RecordType},
+};
+use tokio::time::Instant;
+
+pub(super) const DEFAULT_CACHE_SIZE: usize = 1000;
+/// The TTL given to stale answers, as recommended by RFC 8767.
+const STALE_TTL: u32 = 30;
+
+type CacheKey = (Name, DNSClass, RecordType);
+
+#[derive(Debug)]
+struct CacheEntry {
+    message: Message,
+    inserted: Instant,
+    expires: Instant,
+    /// Distinguishes entries that expire at the same instant.
+    id: u64,
+}
+
+/// Rewrites the TTL of every record in the message.
+fn map_ttls<F>(mut message: Message, f: F) -> Message
+where
+    F: Fn(u32) -> u32,
+{
+    let update = |records: &mut Vec<rr::Record>| {
+        for record in records {
+            record.set_ttl(f(record.ttl()));
+        }
+    };
+
+    update(message.answers_mut());
+    update(message.name_servers_mut());
+    update(message.additionals_mut());
+
+    message
+}
+
+impl CacheEntry {
+    /// Returns the cached response with its TTLs reduced by the time it has
+    /// spent in the cache.
+    fn response(&self, now: Instant) -> Message {
+        let elapsed = now.duration_since(self.inserted).as_secs() as u32;
+        map_ttls(self.message.clone(), |ttl| ttl.saturating_sub(elapsed))
+    }
+
+    /// Returns the cached response with short TTLs so that clients check
+    /// again soon.
+    fn stale_response(&self) -> Message {
+        map_ttls(self.message.clone(), |_| STALE_TTL)
+    }
+}
+
+/// How long a response from an upstream may be cached for, if at all.
+fn cache_ttl(message: &Message) -> Option<u32> {
+    match message.response_code() {
+        ResponseCode::NoError if !message.answers().is_empty() => message
+            .answers()
+            .iter()
+            .chain(message.name_servers())
+            .map(|record| record.ttl())
+            .min(),
+        ResponseCode::NoError | ResponseCode::NXDomain => {
+            // Negative answers are cached according to the SOA (RFC 2308).
+            message
+                .name_servers()
+                .iter()
+                .find_map(|record| match record.data() {
+                    Some(rr::RData::SOA(soa)) => Some(record.ttl().min(soa.minimum())),
+                    _ => None,
+                })
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CacheSettings {
+    size: usize,
+    /// How long after expiry an answer may still be served if the upstreams
+    /// cannot be reached.
+    stale_window: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    entries: HashMap<CacheKey, CacheEntry>,
+    expiry: BTreeMap<(Instant, u64), CacheKey>,
+    next_id: u64,
+}
+
+impl Entries {
+    fn get(&self, key: &CacheKey) -> Option<&CacheEntry> {
+        self.entries.get(key)
+    }
+
+    fn insert(&mut self, key: CacheKey, message: Message, inserted: Instant, expires: Instant) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.expiry.insert((expires, id), key.clone());
+        let entry = CacheEntry {
+            message,
+            inserted,
+            expires,
+            id,
+        };
+
+        if let Some(previous) = self.entries.insert(key, entry) {
+            self.expiry.remove(&(previous.expires, previous.id));
+        }
+    }
+
+    /// Removes the entry closest to expiring if it expires before the cutoff.
+    fn pop_expiring(&mut self, cutoff: Option<Instant>) -> bool {
+        let Some(entry) = self.expiry.first_entry() else {
+            return false;
+        };
+
+        if cutoff.is_some_and(|cutoff| entry.key().0 > cutoff) {
+            return false;
+        }
+
+        let key = entry.remove();
+        self.entries.remove(&key);
+        true
+    }
+
+    fn retain<F>(&mut self, mut cb: F)
+    where
+        F: FnMut(&CacheKey) -> bool,
+    {
+        let expiry = &mut self.expiry;
+        self.entries.retain(|key, entry| {
+            let keep = cb(key);
+            if !keep {
+                expiry.remove(&(entry.expires, entry.id));
+            }
+            keep
+        });
+    }
+}
+
+/// A bounded cache of responses from upstream servers.
+#[derive(Debug)]
+pub(super) struct ResponseCache {
+    settings: Mutex<CacheSettings>,
+    entries: Mutex<Entries>,
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        Self {
+            settings: Mutex::new(CacheSettings {
+                size: DEFAULT_CACHE_SIZE,
+                stale_window: Duration::ZERO,
+            }),
+            entries: Default::default(),
+        }
+    }
+}
+
+impl ResponseCache {
+    pub(super) fn configure(&self, size: usize, stale_window: Duration) {
+        let settings = CacheSettings { size, stale_window };
+        *self.settings.lock().unwrap() = settings;
+
+        let mut entries = self.entries.lock().unwrap();
+        Self::evict(&mut entries, settings);
+    }
+
+    /// Removes entries until there are fewer than the maximum, entries that
+    /// are too old to be served stale first and then those closest to expiring.
+    fn evict(entries: &mut Entries, settings: CacheSettings) {
+        let size = settings.size;
+        if entries.entries.len() < size {
+            return;
+        }
+
+        if let Some(cutoff) = Instant::now().checked_sub(settings.stale_window) {
+            while entries.pop_expiring(Some(cutoff)) {}
+        }
+
+        while entries.entries.len() >= size && entries.pop_expiring(None) {}
+    }
+
+    pub(super) fn get(
+        &self,
+        name: &Name,
+        query_class: DNSClass,
+        query_type: RecordType,
+    ) -> Option<Message> {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+
+        let entry = entries.get(&(name.clone(), query_class, query_type))?;
+        if entry.expires <= now {
+            return None;
+        }
+
+        Some(entry.response(now))
+    }
+
+    /// Returns an expired response if it is still within the stale window.
+    pub(super) fn get_stale(
+        &self,
+        name: &Name,
+        query_class: DNSClass,
+        query_type: RecordType,
+    ) -> Option<Message> {
+        let stale_window = self.settings.lock().unwrap().stale_window;
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+
+        let entry = entries.get(&(name.clone(), query_class, query_type))?;
+        if entry.expires > now || entry.expires + stale_window <= now {
+            return None;
+        }
+
+        Some(entry.stale_response())
+    }
+
+    pub(super) fn insert(
+        &self,
+        name: &Name,
+        query_class: DNSClass,
+        query_type: RecordType,
+        message: &Message,
+    ) {
+        let settings = *self.settings.lock().unwrap();
+        if settings.size == 0 {
+            return;
+        }
+
+        let Some(ttl) = cache_ttl(message) else {
+            return;
+        };
+
+        if ttl == 0 {
+            return;
+        }
+
+        let key = (name.clone(), query_class, query_type);
+        let mut entries = self.entries.lock().unwrap();
+
+        // Replacing an entry doesn't need any more room.
+        if entries.get(&key).is_none() {
+            Self::evict(&mut entries, settings);
+        }
+
+        let inserted = Instant::now();
+        entries.insert(
+            key,
+            message.clone(),
+            inserted,
+            inserted + Duration::from_secs(ttl.into()),
+        );
+    }
+
+    /// Removes any entries for which the callback returns true.
+    pub(super) fn remove_names<F>(&self, mut cb: F)
+    where
+        F: FnMut(&Name) -> bool,
+    {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|(name, _, _)| !cb(name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use hickory_server::proto::{
+        op::{Message, ResponseCode},
+        rr::{rdata::SOA, DNSClass, RData, Record, RecordType},
+    };
+
+    use crate::{
+        dns::cache::ResponseCache,
+        test::{name, rdata_a},
+    };
+
+    fn answer(ttl: u32) -> Message {
+        let mut message = Message::new();
+        message.add_answer(Record::from_rdata(
+            name("www.example.org."),
+            ttl,
+            rdata_a("10.10.10.5"),
+        ));
+        message
+    }
+
+    fn negative(ttl: u32, minimum: u32) -> Message {
+        let mut message = Message::new();
+        message.set_response_code(ResponseCode::NXDomain);
+        message.add_name_server(Record::from_rdata(
+            name("example.org."),
+            ttl,
+            RData::SOA(SOA::new(
+                name("ns.example.org."),
+                name("hostmaster.example.org."),
+                1,
+                7200,
+                3600,
+                1209600,
+                minimum,
+            )),
+        ));
+        message
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test(start_paused = true)]
+    async fn expiry() {
+        let cache = ResponseCache::default();
+        let www = name("www.example.org.");
+        let missing = name("missing.example.org.");
+
+        cache.insert(&www, DNSClass::IN, RecordType::A, &answer(60));
+        cache.insert(&missing, DNSClass::IN, RecordType::A, &negative(3600, 30));
+
+        let response = cache.get(&www, DNSClass::IN, RecordType::A).unwrap();
+        assert_eq!(response.answers().first().unwrap().ttl(), 60);
+        assert!(cache.get(&www, DNSClass::IN, RecordType::AAAA).is_none());
+
+        let response = cache.get(&missing, DNSClass::IN, RecordType::A).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+
+        tokio::time::advance(Duration::from_secs(20)).await;
+
+        let response = cache.get(&www, DNSClass::IN, RecordType::A).unwrap();
+        assert_eq!(response.answers().first().unwrap().ttl(), 40);
+
+        // Negative answers only last for the SOA minimum.
+        tokio::time::advance(Duration::from_secs(20)).await;
+        assert!(cache.get(&missing, DNSClass::IN, RecordType::A).is_none());
+        assert!(cache.get(&www, DNSClass::IN, RecordType::A).is_some());
+
+        tokio::time::advance(Duration::from_secs(20)).await;
+        assert!(cache.get(&www, DNSClass::IN, RecordType::A).is_none());
+
+        // Failures and responses without a TTL are never cached.
+        let mut failure = answer(60);
+        failure.set_response_code(ResponseCode::ServFail);
+        cache.insert(&www, DNSClass::IN, RecordType::A, &failure);
+        assert!(cache.get(&www, DNSClass::IN, RecordType::A).is_none());
+
+        let mut nodata = Message::new();
+        nodata.set_response_code(ResponseCode::NXDomain);
+        cache.insert(&www, DNSClass::IN, RecordType::A, &nodata);
+        assert!(cache.get(&www, DNSClass::IN, RecordType::A).is_none());
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test(start_paused = true)]
+    async fn stale() {
+        let cache = ResponseCache::default();
+        cache.configure(10, Duration::from_secs(600));
+        let www = name("www.example.org.");
+
+        cache.insert(&www, DNSClass::IN, RecordType::A, &answer(60));
+        assert!(cache.get_stale(&www, DNSClass::IN, RecordType::A).is_none());
+
+        tokio::time::advance(Duration::from_secs(90)).await;
+        assert!(cache.get(&www, DNSClass::IN, RecordType::A).is_none());
+
+        let response = cache.get_stale(&www, DNSClass::IN, RecordType::A).unwrap();
+        assert_eq!(response.answers().first().unwrap().ttl(), 30);
+
+        tokio::time::advance(Duration::from_secs(600)).await;
+        assert!(cache.get_stale(&www, DNSClass::IN, RecordType::A).is_none());
+
+        // Without a window nothing is served stale.
+        cache.configure(10, Duration::ZERO);
+        cache.insert(&www, DNSClass::IN, RecordType::A, &answer(60));
+        tokio::time::advance(Duration::from_secs(90)).await;
+        assert!(cache.get_stale(&www, DNSClass::IN, RecordType::A).is_none());
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test(start_paused = true)]
+    async fn bounded() {
+        let cache = ResponseCache::default();
+        cache.configure(2, Duration::ZERO);
+
+        cache.insert(
+            &name("a.example.org."),
+            DNSClass::IN,
+            RecordType::A,
+            &answer(30),
+        );
+        cache.insert(
+            &name("b.example.org."),
+            DNSClass::IN,
+            RecordType::A,
+            &answer(60),
+        );
+        cache.insert(
+            &name("c.example.org."),
+            DNSClass::IN,
+            RecordType::A,
+            &answer(90),
+        );
+
+        // The entry closest to expiry is evicted to make room.
+        assert!(cache
+            .get(&name("a.example.org."), DNSClass::IN, RecordType::A)
+            .is_none());
+        assert!(cache
+            .get(&name("b.example.org."), DNSClass::IN, RecordType::A)
+            .is_some());
+        assert!(cache
+            .get(&name("c.example.org."), DNSClass::IN, RecordType::A)
+            .is_some());
+
+        cache.remove_names(|name| name.to_string().starts_with("b."));
+        assert!(cache
+            .get(&name("b.example.org."), DNSClass::IN, RecordType::A)
+            .is_none());
+
+        cache.configure(0, Duration::ZERO);
+        assert!(cache
+            .get(&name("c.example.org."), DNSClass::IN, RecordType::A)
+            .is_none());
+        cache.insert(
+            &name("a.example.org."),
+            DNSClass::IN,
+            RecordType::A,
+            &answer(30),
+        );
+        assert!(cache
+            .get(&name("a.example.org."), DNSClass::IN, RecordType::A)
+            .is_none());
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test(start_paused = true)]
+    async fn replaced() {
+        let cache = ResponseCache::default();
+        cache.configure(2, Duration::ZERO);
+        let a = name("a.example.org.");
+        let b = name("b.example.org.");
+        let c = name("c.example.org.");
+
+        cache.insert(&a, DNSClass::IN, RecordType::A, &answer(30));
+        cache.insert(&b, DNSClass::IN, RecordType::A, &answer(60));
+
+        // Replacing an entry doesn't evict anything else.
+        cache.insert(&b, DNSClass::IN, RecordType::A, &answer(60));
+        assert!(cache.get(&a, DNSClass::IN, RecordType::A).is_some());
+        assert!(cache.get(&b, DNSClass::IN, RecordType::A).is_some());
+
+        // Replacing an entry moves it to its new expiry.
+        cache.insert(&a, DNSClass::IN, RecordType::A, &answer(120));
+        cache.insert(&c, DNSClass::IN, RecordType::A, &answer(90));
+
+        let response = cache.get(&a, DNSClass::IN, RecordType::A).unwrap();
+        assert_eq!(response.answers().first().unwrap().ttl(), 120);
+        assert!(cache.get(&b, DNSClass::IN, RecordType::A).is_none());
+        assert!(cache.get(&c, DNSClass::IN, RecordType::A).is_some());
+
+        // Expired entries are evicted before those that are still fresh.
+        tokio::time::advance(Duration::from_secs(100)).await;
+        cache.insert(&b, DNSClass::IN, RecordType::A, &answer(60));
+        assert!(cache.get(&a, DNSClass::IN, RecordType::A).is_some());
+        assert!(cache.get(&b, DNSClass::IN, RecordType::A).is_some());
+    }
+}
@@ -0,0 +1,814 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    iter::once,
+    path::Path,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Error};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use figment::value::magic::RelativePathBuf;
+use hickory_server::proto::{
+    op::ResponseCode,
+    rr::{
+        self,
+        dnssec::{
+            rdata::{DNSSECRData, DNSKEY, DS, NSEC, NSEC3, RRSIG},
+            tbs::rrset_tbs,
+            Algorithm, DigestType, KeyFormat, KeyPair, Nsec3HashAlgorithm, Private,
+        },
+        DNSClass, Name, RecordType,
+    },
+};
+use serde::Deserialize;
+
+use crate::{
+    config::{ZoneConfig, ZoneConfigProvider},
+    dns::{query::QueryState, Fqdn, LockedServerState},
+};
+
+/// Signatures are valid from an hour ago to allow for clock differences.
+const INCEPTION_OFFSET: u32 = 60 * 60;
+/// Signatures are created on demand so can be short lived.
+const VALIDITY: u32 = 7 * 24 * 60 * 60;
+
+const BASE32HEX: &[u8] = b"0123456789abcdefghijklmnopqrstuv";
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as u32)
+        .unwrap_or_default()
+}
+
+/// How non-existent names and types are proven.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Denial {
+    #[default]
+    Nsec,
+    Nsec3,
+}
+
+#[derive(Deserialize)]
+struct DnssecConfigFile {
+    zsk: RelativePathBuf,
+    #[serde(default)]
+    ksk: Option<RelativePathBuf>,
+    #[serde(default)]
+    denial: Denial,
+}
+
+/// Reads a PKCS#8 private key in either PEM or DER format.
+fn read_key(path: &Path) -> Result<Vec<u8>, Error> {
+    let data = fs::read(path)?;
+
+    if !data.starts_with(b"-----BEGIN") {
+        return Ok(data);
+    }
+
+    let pem = String::from_utf8(data)?;
+    let base64: String = pem
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+
+    Ok(STANDARD.decode(base64.trim())?)
+}
+
+struct ZoneKey {
+    key_pair: KeyPair<Private>,
+    dnskey: DNSKEY,
+    key_tag: u16,
+}
+
+impl ZoneKey {
+    fn load(path: &Path, secure_entry_point: bool) -> Result<Self, Error> {
+        let der = read_key(path)?;
+
+        for algorithm in [
+            Algorithm::ECDSAP256SHA256,
+            Algorithm::ECDSAP384SHA384,
+            Algorithm::ED25519,
+        ] {
+            if let Ok(key_pair) = KeyFormat::Pkcs8.decode_key(&der, None, algorithm) {
+                let dnskey = DNSKEY::new(
+                    true,
+                    secure_entry_point,
+                    false,
+                    algorithm,
+                    key_pair.to_public_bytes()?,
+                );
+                let key_tag = dnskey.calculate_key_tag()?;
+
+                return Ok(Self {
+                    key_pair,
+                    dnskey,
+                    key_tag,
+                });
+            }
+        }
+
+        bail!(
+            "{} is not a PKCS#8 ECDSA or Ed25519 private key",
+            path.display()
+        )
+    }
+
+    /// Signs an RRset, all records must have the same name, class and type.
+    fn sign(&self, signer_name: &Name, rrset: &[rr::Record]) -> Result<rr::Record, Error> {
+        let first = rrset.first().ok_or_else(|| anyhow!("Empty RRset"))?;
+        let ttl = rrset.iter().map(|record| record.ttl()).min().unwrap_or(0);
+        let algorithm = self.dnskey.algorithm();
+        let now = now();
+
+        let tbs = rrset_tbs(
+            first.name(),
+            first.dns_class(),
+            first.name().num_labels(),
+            first.record_type(),
+            algorithm,
+            ttl,
+            now + VALIDITY,
+            now - INCEPTION_OFFSET,
+            self.key_tag,
+            signer_name,
+            rrset,
+        )?;
+        let signature = self.key_pair.sign(algorithm, &tbs)?;
+
+        Ok(rr::Record::from_rdata(
+            first.name().clone(),
+            ttl,
+            rr::RData::DNSSEC(DNSSECRData::RRSIG(RRSIG::new(
+                first.record_type(),
+                algorithm,
+                first.name().num_labels(),
+                ttl,
+                now + VALIDITY,
+                now - INCEPTION_OFFSET,
+                self.key_tag,
+                signer_name.clone(),
+                signature,
+            ))),
+        ))
+    }
+}
+
+/// The keys used to sign a zone. Without a separate key signing key the zone
+/// signing key is used for everything.
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "DnssecConfigFile")]
+pub(crate) struct DnssecConfig {
+    zsk: Arc<ZoneKey>,
+    ksk: Option<Arc<ZoneKey>>,
+    denial: Denial,
+}
+
+impl TryFrom<DnssecConfigFile> for DnssecConfig {
+    type Error = Error;
+
+    fn try_from(config: DnssecConfigFile) -> Result<Self, Self::Error> {
+        let ksk = config
+            .ksk
+            .map(|path| ZoneKey::load(&path.relative(), true))
+            .transpose()?;
+
+        Ok(Self {
+            zsk: Arc::new(ZoneKey::load(&config.zsk.relative(), ksk.is_none())?),
+            ksk: ksk.map(Arc::new),
+            denial: config.denial,
+        })
+    }
+}
+
+impl PartialEq for DnssecConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.zsk.dnskey == other.zsk.dnskey
+            && self.ksk.as_ref().map(|key| &key.dnskey) == other.ksk.as_ref().map(|key| &key.dnskey)
+            && self.denial == other.denial
+    }
+}
+
+impl Eq for DnssecConfig {}
+
+impl fmt::Debug for DnssecConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DnssecConfig")
+            .field("zsk", &self.zsk.key_tag)
+            .field("ksk", &self.ksk.as_ref().map(|key| key.key_tag))
+            .field("denial", &self.denial)
+            .finish()
+    }
+}
+
+impl DnssecConfig {
+    fn ksk(&self) -> &ZoneKey {
+        self.ksk.as_deref().unwrap_or(&self.zsk)
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &ZoneKey> {
+        once(self.zsk.as_ref()).chain(self.ksk.as_deref())
+    }
+
+    /// The DNSKEY records to publish at the zone's apex.
+    pub(crate) fn dnskeys(&self, origin: &Fqdn, ttl: u32) -> Vec<rr::Record> {
+        self.keys()
+            .map(|key| {
+                rr::Record::from_rdata(
+                    origin.name(),
+                    ttl,
+                    rr::RData::DNSSEC(DNSSECRData::DNSKEY(key.dnskey.clone())),
+                )
+            })
+            .collect()
+    }
+
+    /// The DS records for the parent zone to publish.
+    pub(crate) fn ds_records(&self, origin: &Fqdn, ttl: u32) -> Result<Vec<rr::Record>, Error> {
+        let key = self.ksk();
+        let digest = key.dnskey.to_digest(&origin.name(), DigestType::SHA256)?;
+
+        let ds = DS::new(
+            key.key_tag,
+            key.dnskey.algorithm(),
+            DigestType::SHA256,
+            digest.as_ref().to_vec(),
+        );
+
+        Ok(vec![rr::Record::from_rdata(
+            origin.name(),
+            ttl,
+            rr::RData::DNSSEC(DNSSECRData::DS(ds)),
+        )])
+    }
+
+    fn sign(&self, origin: &Fqdn, rrset: &[rr::Record]) -> Option<rr::Record> {
+        let key = match rrset.first()?.record_type() {
+            RecordType::DNSKEY => self.ksk(),
+            _ => &self.zsk,
+        };
+
+        key.sign(&origin.name(), rrset)
+            .inspect_err(|e| tracing::error!(error = %e, "Failed to sign RRset"))
+            .ok()
+    }
+}
+
+fn base32hex(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    let mut buffer: u16 = 0;
+    let mut bits = 0;
+
+    for byte in data {
+        buffer = (buffer << 8) | u16::from(*byte);
+        bits += 8;
+
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32HEX[usize::from((buffer >> bits) & 0x1f)] as char);
+        }
+    }
+
+    if bits > 0 {
+        encoded.push(BASE32HEX[usize::from((buffer << (5 - bits)) & 0x1f)] as char);
+    }
+
+    encoded
+}
+
+/// Adds one to or subtracts one from a hash, wrapping around.
+fn adjust_hash(hash: &[u8], increment: bool) -> Vec<u8> {
+    let mut hash = hash.to_vec();
+
+    for byte in hash.iter_mut().rev() {
+        let (value, overflow) = if increment {
+            byte.overflowing_add(1)
+        } else {
+            byte.overflowing_sub(1)
+        };
+
+        *byte = value;
+        if !overflow {
+            break;
+        }
+    }
+
+    hash
+}
+
+/// The name immediately following a name in canonical order (RFC 4470).
+fn successor(name: &Name) -> Result<Name, Error> {
+    Ok(Name::from_labels(once(&[0_u8][..]))?.append_domain(name)?)
+}
+
+/// A name that comes before a name in canonical order but after any name
+/// that is likely to exist (RFC 4470).
+fn predecessor(name: &Name) -> Result<Name, Error> {
+    let mut labels = name.iter();
+    let Some(first) = labels.next() else {
+        return Ok(name.clone());
+    };
+    let base = name.base_name();
+
+    let mut label = first.to_vec();
+    match label.pop() {
+        Some(0) | None => {}
+        Some(last) => {
+            label.push(last - 1);
+            label.push(0xff);
+        }
+    }
+
+    if label.is_empty() {
+        Ok(base)
+    } else {
+        Ok(Name::from_labels(once(label))?.append_domain(&base)?)
+    }
+}
+
+/// Groups records into RRsets, keeping the order that they first appear in.
+fn rrsets(records: &[rr::Record]) -> Vec<Vec<rr::Record>> {
+    let mut sets: Vec<Vec<rr::Record>> = Vec::new();
+    let mut index: HashMap<(Name, DNSClass, RecordType), usize> = HashMap::new();
+
+    for record in records {
+        // Signatures are never signed themselves.
+        if record.record_type() == RecordType::RRSIG {
+            continue;
+        }
+
+        let key = (
+            record.name().to_lowercase(),
+            record.dns_class(),
+            record.record_type(),
+        );
+
+        match index.get(&key) {
+            Some(position) => sets[*position].push(record.clone()),
+            None => {
+                index.insert(key, sets.len());
+                sets.push(vec![record.clone()]);
+            }
+        }
+    }
+
+    sets
+}
+
+impl<Z: ZoneConfigProvider> LockedServerState<Z> {
+    /// The record types that exist for a name in a signed zone.
+    fn signed_types(&self, name: &Name, config: &ZoneConfig) -> Vec<RecordType> {
+        let mut types = self.records.record_types(name);
+
+        if config.origin.as_ref().map(|origin| origin.name()).as_ref() == Some(name) {
+            types.push(RecordType::SOA);
+            types.push(RecordType::DNSKEY);
+        }
+
+        if !types.is_empty() {
+            types.push(RecordType::RRSIG);
+        }
+
+        types
+    }
+
+    fn name_exists(&self, name: &Name, origin: &Fqdn) -> bool {
+        *name == origin.name()
+            || self.records.name_exists(name)
+            || !self.records.record_types(name).is_empty()
+    }
+
+    /// The closest ancestor of a name that exists in the zone.
+    fn closest_encloser(&self, name: &Name, origin: &Fqdn) -> Name {
+        let mut encloser = name.base_name();
+        while encloser.num_labels() > origin.num_labels() && !self.name_exists(&encloser, origin) {
+            encloser = encloser.base_name();
+        }
+
+        encloser
+    }
+
+    fn nsec(&self, owner: Name, next: Name, types: Vec<RecordType>, ttl: u32) -> rr::Record {
+        rr::Record::from_rdata(
+            owner,
+            ttl,
+            rr::RData::DNSSEC(DNSSECRData::NSEC(NSEC::new_cover_self(next, types))),
+        )
+    }
+
+    fn nsec3(
+        &self,
+        owner: &[u8],
+        next: Vec<u8>,
+        types: Vec<RecordType>,
+        origin: &Fqdn,
+        ttl: u32,
+    ) -> Result<rr::Record, Error> {
+        let owner = Name::from_labels(once(base32hex(owner).as_str()))?.append_domain(origin)?;
+
+        Ok(rr::Record::from_rdata(
+            owner,
+            ttl,
+            rr::RData::DNSSEC(DNSSECRData::NSEC3(NSEC3::new(
+                Nsec3HashAlgorithm::SHA1,
+                false,
+                0,
+                Vec::new(),
+                next,
+                types,
+            ))),
+        ))
+    }
+
+    /// Builds the records proving that a name or type does not exist. These
+    /// are generated for each query so that they only cover the name asked
+    /// for and reveal nothing about the other names in the zone.
+    fn denial_records(
+        &self,
+        name: &Name,
+        config: &ZoneConfig,
+        dnssec: &DnssecConfig,
+    ) -> Result<Vec<rr::Record>, Error> {
+        let origin = config
+            .origin
+            .as_ref()
+            .ok_or_else(|| anyhow!("Zone has no origin"))?;
+        let ttl = config.ttl.min(60);
+        let exists = self.name_exists(name, origin);
+
+        match dnssec.denial {
+            Denial::Nsec if exists => Ok(vec![self.nsec(
+                name.clone(),
+                successor(name)?,
+                self.signed_types(name, config),
+                ttl,
+            )]),
+            Denial::Nsec => {
+                let wildcard = Name::from_labels(once("*"))?
+                    .append_domain(&self.closest_encloser(name, origin))?;
+
+                Ok(vec![
+                    self.nsec(predecessor(name)?, successor(name)?, Vec::new(), ttl),
+                    self.nsec(
+                        predecessor(&wildcard)?,
+                        successor(&wildcard)?,
+                        Vec::new(),
+                        ttl,
+                    ),
+                ])
+            }
+            Denial::Nsec3 => {
+                let hash = |name: &Name| -> Result<Vec<u8>, Error> {
+                    Ok(Nsec3HashAlgorithm::SHA1
+                        .hash(&[], name, 0)?
+                        .as_ref()
+                        .to_vec())
+                };
+
+                let matching = |name: &Name| -> Result<rr::Record, Error> {
+                    let hashed = hash(name)?;
+                    self.nsec3(
+                        &hashed,
+                        adjust_hash(&hashed, true),
+                        self.signed_types(name, config),
+                        origin,
+                        ttl,
+                    )
+                };
+
+                let covering = |name: &Name| -> Result<rr::Record, Error> {
+                    let hashed = hash(name)?;
+                    self.nsec3(
+                        &adjust_hash(&hashed, false),
+                        adjust_hash(&hashed, true),
+                        Vec::new(),
+                        origin,
+                        ttl,
+                    )
+                };
+
+                if exists {
+                    return Ok(vec![matching(name)?]);
+                }
+
+                let encloser = self.closest_encloser(name, origin);
+                let next_closer = name.trim_to(encloser.num_labels() as usize + 1);
+                let wildcard = Name::from_labels(once("*"))?.append_domain(&encloser)?;
+
+                Ok(vec![
+                    matching(&encloser)?,
+                    covering(&next_closer)?,
+                    covering(&wildcard)?,
+                ])
+            }
+        }
+    }
+
+    /// Adds signatures to the records of signed zones in a response and
+    /// proves the non-existence of names or types that were asked for.
+    pub(super) fn add_dnssec_records(&self, query_state: &mut QueryState) {
+        let name = query_state.query.name().clone();
+        let config = self.zone_config(&Fqdn::from(name.clone()));
+
+        if let (Some(ref dnssec), Some(soa)) = (&config.dnssec, config.soa()) {
+            if !query_state
+                .answers()
+                .iter()
+                .any(|record| *record.name() == name)
+            {
+                match self.denial_records(&name, &config, dnssec) {
+                    Ok(records) => {
+                        if self.name_exists(&name, config.origin.as_ref().unwrap()) {
+                            query_state.response_code = ResponseCode::NoError;
+                        }
+
+                        query_state.name_servers.extend(records);
+                        query_state.soa = Some(soa);
+                    }
+                    Err(e) => tracing::error!(error = %e, "Failed to build denial records"),
+                }
+            }
+        }
+
+        let answers = self.signatures(query_state.answers());
+        query_state.add_signatures(answers);
+
+        let authority: Vec<rr::Record> = query_state
+            .name_servers
+            .iter()
+            .chain(query_state.soa.iter())
+            .cloned()
+            .collect();
+        let signatures = self.signatures(&authority);
+        query_state.name_servers.extend(signatures);
+
+        let signatures = self.signatures(query_state.additionals());
+        query_state.add_additionals(signatures);
+    }
+
+    /// Signs the RRsets in a section that belong to signed zones.
+    fn signatures(&self, records: &[rr::Record]) -> Vec<rr::Record> {
+        rrsets(records)
+            .into_iter()
+            .filter_map(|rrset| {
+                let name = rrset.first()?.name().clone();
+                let config = self.zone_config(&Fqdn::from(name));
+
+                match (&config.dnssec, &config.origin) {
+                    (Some(dnssec), Some(origin)) if config.authoritative => {
+                        dnssec.sign(origin, &rrset)
+                    }
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use hickory_server::proto::{
+        op::{Query, ResponseCode},
+        rr::{
+            self,
+            dnssec::{
+                rdata::{DNSSECRData, DNSKEY},
+                Algorithm, KeyPair, Nsec3HashAlgorithm, Verifier,
+            },
+            DNSClass, RecordType,
+        },
+    };
+    use tempfile::TempDir;
+    use tokio::sync::watch::channel;
+
+    use crate::{
+        config::Config,
+        dns::{
+            dnssec::{base32hex, predecessor, successor},
+            query::QueryState,
+            RData, Record, RecordSet, ServerState,
+        },
+        test::{fqdn, name, write_file},
+    };
+
+    async fn write_key(path: &Path) {
+        let key = KeyPair::generate_pkcs8(Algorithm::ECDSAP256SHA256).unwrap();
+        tokio::fs::write(path, key).await.unwrap();
+    }
+
+    fn dnskeys(records: &[rr::Record]) -> Vec<DNSKEY> {
+        records
+            .iter()
+            .filter_map(|record| match record.data() {
+                Some(rr::RData::DNSSEC(DNSSECRData::DNSKEY(dnskey))) => Some(dnskey.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Checks that every RRset in the records is signed by one of the keys.
+    fn verify(records: &[rr::Record], keys: &[DNSKEY]) {
+        let signed: Vec<&rr::Record> = records
+            .iter()
+            .filter(|record| record.record_type() != RecordType::RRSIG)
+            .collect();
+        assert!(!signed.is_empty());
+
+        for record in signed {
+            let verified = records.iter().any(|signature| match signature.data() {
+                Some(rr::RData::DNSSEC(DNSSECRData::RRSIG(rrsig)))
+                    if signature.name() == record.name()
+                        && rrsig.type_covered() == record.record_type() =>
+                {
+                    keys.iter().any(|key| {
+                        key.verify_rrsig(record.name(), DNSClass::IN, rrsig, records)
+                            .is_ok()
+                    })
+                }
+                _ => false,
+            });
+
+            assert!(verified, "{record} was not signed");
+        }
+    }
+
+    fn types(records: &[rr::Record], record_type: RecordType) -> usize {
+        records
+            .iter()
+            .filter(|record| record.record_type() == record_type)
+            .count()
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(base32hex(&[0xff; 5]), "vvvvvvvv");
+        assert_eq!(base32hex(b"f"), "co");
+
+        let www = name("www.home.local.");
+        assert!(predecessor(&www).unwrap() < www);
+        assert!(successor(&www).unwrap() > www);
+        assert!(www.zone_of(&successor(&www).unwrap()));
+        assert_eq!(
+            predecessor(&successor(&www).unwrap()).unwrap(),
+            www,
+            "The predecessor of a name's successor is the name"
+        );
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn signing() {
+        let temp = TempDir::new().unwrap();
+        write_key(&temp.path().join("zsk.der")).await;
+        write_key(&temp.path().join("ksk.der")).await;
+
+        let config_file = temp.path().join("config.yml");
+        write_file(
+            &config_file,
+            r#"
+zones:
+  home.local:
+    dnssec:
+      zsk: zsk.der
+      ksk: ksk.der
+  nsec3.local:
+    dnssec:
+      zsk: zsk.der
+      denial: nsec3
+  unsigned.local: {}
+"#,
+        )
+        .await;
+
+        let config = Config::from_file(&config_file).unwrap();
+
+        let mut records = RecordSet::new();
+        records.insert(Record::new(
+            fqdn("www.home.local."),
+            RData::A("10.10.5.3".parse().unwrap()),
+        ));
+        records.insert(Record::new(
+            fqdn("alias.home.local."),
+            RData::Cname(fqdn("www.home.local.")),
+        ));
+        records.insert(Record::new(
+            fqdn("www.nsec3.local."),
+            RData::A("10.10.5.4".parse().unwrap()),
+        ));
+        records.insert(Record::new(
+            fqdn("www.unsigned.local."),
+            RData::A("10.10.5.5".parse().unwrap()),
+        ));
+
+        let (_, receiver) = channel(records);
+        let server_state = ServerState::new(receiver, config.zones).locked().await;
+
+        let query = |name: &str, record_type: RecordType| {
+            let server_state = &server_state;
+            let name = self::name(name);
+
+            async move {
+                let mut query_state = QueryState::new(Query::query(name, record_type), false);
+                server_state.perform_query(&mut query_state).await;
+                server_state.add_dnssec_records(&mut query_state);
+                query_state
+            }
+        };
+
+        let query_state = query("home.local.", RecordType::DNSKEY).await;
+        assert_eq!(query_state.response_code, ResponseCode::NoError);
+        let keys = dnskeys(query_state.answers());
+        assert_eq!(keys.len(), 2);
+        assert_eq!(
+            keys.iter().filter(|key| key.secure_entry_point()).count(),
+            1
+        );
+        verify(query_state.answers(), &keys);
+
+        let query_state = query("alias.home.local.", RecordType::A).await;
+        assert_eq!(query_state.response_code, ResponseCode::NoError);
+        assert_eq!(types(query_state.answers(), RecordType::RRSIG), 2);
+        verify(query_state.answers(), &keys);
+
+        // The name exists but has no records of the type.
+        let query_state = query("www.home.local.", RecordType::AAAA).await;
+        assert_eq!(query_state.response_code, ResponseCode::NoError);
+        let authority: Vec<rr::Record> = query_state
+            .name_servers()
+            .iter()
+            .chain(query_state.soa())
+            .cloned()
+            .collect();
+        assert_eq!(types(&authority, RecordType::NSEC), 1);
+        assert_eq!(types(&authority, RecordType::SOA), 1);
+        verify(&authority, &keys);
+
+        let query_state = query("missing.home.local.", RecordType::A).await;
+        assert_eq!(query_state.response_code, ResponseCode::NXDomain);
+        let authority: Vec<rr::Record> = query_state
+            .name_servers()
+            .iter()
+            .chain(query_state.soa())
+            .cloned()
+            .collect();
+        assert_eq!(types(&authority, RecordType::NSEC), 2);
+        verify(&authority, &keys);
+
+        let nsec3_keys = dnskeys(query("nsec3.local.", RecordType::DNSKEY).await.answers());
+        assert_eq!(nsec3_keys.len(), 1);
+        assert!(nsec3_keys[0].secure_entry_point());
+
+        let query_state = query("missing.nsec3.local.", RecordType::A).await;
+        assert_eq!(query_state.response_code, ResponseCode::NXDomain);
+        let authority: Vec<rr::Record> = query_state
+            .name_servers()
+            .iter()
+            .chain(query_state.soa())
+            .cloned()
+            .collect();
+        assert_eq!(types(&authority, RecordType::NSEC3), 3);
+        verify(&authority, &nsec3_keys);
+
+        // The closest encloser is the apex.
+        let apex_hash = Nsec3HashAlgorithm::SHA1
+            .hash(&[], &name("nsec3.local."), 0)
+            .unwrap();
+        let apex_owner = format!("{}.nsec3.local.", base32hex(apex_hash.as_ref()));
+        assert!(authority
+            .iter()
+            .any(|record| record.name().to_string() == apex_owner));
+
+        let query_state = query("www.unsigned.local.", RecordType::A).await;
+        assert_eq!(types(query_state.answers(), RecordType::RRSIG), 0);
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn ds_records() {
+        let temp = TempDir::new().unwrap();
+        write_key(&temp.path().join("zsk.der")).await;
+
+        let config_file = temp.path().join("config.yml");
+        write_file(
+            &config_file,
+            r#"
+zones:
+  home.local:
+    dnssec:
+      zsk: zsk.der
+"#,
+        )
+        .await;
+
+        let records = crate::ds_records(&config_file, "home.local").unwrap();
+        assert_eq!(records.len(), 1);
+        assert!(records[0].starts_with("home.local. 300 IN DS "));
+
+        assert!(crate::ds_records(&config_file, "other.local").is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Error};
+use hickory_server::{
+    authority::{MessageRequest, MessageResponse},
+    proto::{
+        op::Message,
+        rr::Record,
+        serialize::binary::{BinDecodable, BinEncoder},
+    },
+    server::{Protocol, Request, ResponseHandler, ResponseInfo},
+};
+
+use crate::{
+    config::Zones,
+    dns::{handler::Handler, ServerState},
+};
+
+/// Captures the encoded response rather than sending it over a socket.
+#[derive(Clone, Default)]
+struct BufferedResponse {
+    buffer: Arc<Mutex<Option<Vec<u8>>>>,
+}
+
+#[async_trait::async_trait]
+impl ResponseHandler for BufferedResponse {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> io::Result<ResponseInfo> {
+        let mut buffer = Vec::with_capacity(512);
+
+        let info = {
+            let mut encoder = BinEncoder::new(&mut buffer);
+            response.destructive_emit(&mut encoder)?
+        };
+
+        self.buffer.lock().unwrap().replace(buffer);
+
+        Ok(info)
+    }
+}
+
+pub(crate) struct DohResponse {
+    pub(crate) message: Vec<u8>,
+    /// The smallest TTL in the response, suitable for HTTP caching.
+    pub(crate) max_age: Option<u32>,
+}
+
+/// Answers an RFC 8484 DNS message using the same handler as the DNS listeners.
+pub(crate) async fn handle_message(
+    server_state: &ServerState<Zones>,
+    message: &[u8],
+    source: SocketAddr,
+) -> Result<DohResponse, Error> {
+    let request = Request::new(
+        MessageRequest::from_bytes(message)?,
+        source,
+        Protocol::Https,
+    );
+
+    let handler = Handler {
+        server_state: server_state.clone(),
+    };
+
+    let response = BufferedResponse::default();
+    handler
+        .handle_request(&request, message, response.clone())
+        .await;
+
+    let message = response
+        .buffer
+        .lock()
+        .unwrap()
+        .take()
+        .ok_or_else(|| anyhow!("No response was generated"))?;
+
+    let max_age = Message::from_vec(&message).ok().and_then(|parsed| {
+        parsed
+            .answers()
+            .iter()
+            .chain(parsed.name_servers())
+            .map(|record| record.ttl())
+            .min()
+    });
+
+    Ok(DohResponse { message, max_age })
+}
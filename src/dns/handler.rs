@@ -1,28 +1,296 @@
-use hickory_client::op::{Edns, Header, MessageType, OpCode, ResponseCode};
+use hickory_client::{
+    op::{Edns, Header, MessageType, OpCode, Query, ResponseCode},
+    rr::{self, RecordType},
+    serialize::binary::BinEncodable,
+};
 use hickory_server::{
     authority::MessageResponseBuilder,
-    server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
+    server::{Protocol, Request, ResponseHandler, ResponseInfo},
 };
 use tracing::instrument;
 
 use crate::{
-    config::Zones,
-    dns::{query::QueryState, ServerState},
+    config::{ZoneConfigProvider, Zones},
+    dns::{
+        query::QueryState,
+        rate_limit::{RateLimitAction, RateLimitKey},
+        tsig::{self, SignedRequest},
+        Fqdn, ServerState,
+    },
+    sources::update::Update,
 };
 
+/// Space left in unsigned UDP responses for the EDNS record.
+const EDNS_SIZE: usize = 11;
+/// The largest message that can be sent over TCP.
+const MAX_TCP_MESSAGE_SIZE: usize = u16::MAX as usize;
+/// The size of the message header.
+const HEADER_SIZE: usize = 12;
+
 fn serve_failed() -> ResponseInfo {
     let mut header = Header::new();
     header.set_response_code(ResponseCode::ServFail);
     header.into()
 }
 
+/// The sections of a response.
+struct Response {
+    header: Header,
+    answers: Vec<rr::Record>,
+    name_servers: Vec<rr::Record>,
+    additionals: Vec<rr::Record>,
+}
+
+impl Response {
+    /// What the response is rate limited by. Negative answers are counted
+    /// against their zone so that queries for random names share a limit and
+    /// other responses without answers, such as refusals, are counted together
+    /// by their response code.
+    fn rate_limit_key(&self, request: &Request) -> RateLimitKey {
+        if self.answers.is_empty() {
+            if let Some(soa) = self
+                .name_servers
+                .iter()
+                .find(|record| record.record_type() == RecordType::SOA)
+            {
+                return RateLimitKey::Name(soa.name().clone());
+            }
+
+            if self.header.response_code() != ResponseCode::NoError {
+                return RateLimitKey::Error(self.header.response_code());
+            }
+        }
+
+        RateLimitKey::Name(request.query().name().into())
+    }
+
+    fn new(header: Header) -> Self {
+        Self {
+            header,
+            answers: Vec::new(),
+            name_servers: Vec::new(),
+            additionals: Vec::new(),
+        }
+    }
+
+    fn with_code(request: &Request, response_code: ResponseCode) -> Self {
+        let mut header = Header::response_from_request(request.header());
+        header.set_response_code(response_code);
+        Self::new(header)
+    }
+
+    /// Splits the answers of a zone transfer across as many messages as are
+    /// needed to keep each one within the TCP message size, leaving the given
+    /// space for the final record of each message.
+    fn split_transfer(self, query: &Query, reserved: usize) -> Vec<Self> {
+        let query_size = query
+            .to_bytes()
+            .map(|bytes| bytes.len())
+            .unwrap_or_default();
+        let available = MAX_TCP_MESSAGE_SIZE - HEADER_SIZE - query_size - reserved;
+
+        let mut responses = vec![Self::new(self.header)];
+        let mut size = 0;
+
+        for record in self.answers {
+            // Records are measured without name compression so messages may
+            // end up smaller than this.
+            let record_size = record
+                .to_bytes()
+                .map(|bytes| bytes.len())
+                .unwrap_or_default();
+
+            let current = responses.last_mut().unwrap();
+            if size + record_size > available && !current.answers.is_empty() {
+                responses.push(Self::new(self.header));
+                size = 0;
+            }
+
+            responses.last_mut().unwrap().answers.push(record);
+            size += record_size;
+        }
+
+        responses
+    }
+}
+
 #[derive(Clone)]
 pub(crate) struct Handler {
     pub server_state: ServerState<Zones>,
 }
 
-#[async_trait::async_trait]
-impl RequestHandler for Handler {
+impl Handler {
+    async fn zone_transfer(
+        &self,
+        request: &Request,
+        signed: Option<&SignedRequest>,
+    ) -> Result<Vec<rr::Record>, ResponseCode> {
+        let query_type = request.query().query_type();
+        let over_udp = matches!(request.request_info().protocol, Protocol::Udp);
+
+        if query_type == RecordType::AXFR && over_udp {
+            tracing::warn!("Rejecting AXFR over UDP");
+            return Err(ResponseCode::FormErr);
+        }
+
+        // For IXFR the client includes the SOA that it currently has.
+        let client_serial = request
+            .name_servers()
+            .iter()
+            .find_map(|record| match record.data() {
+                Some(rr::RData::SOA(soa)) => Some(soa.serial()),
+                _ => None,
+            });
+
+        let mut records = self.server_state.locked().await.zone_transfer(
+            &request.query().name().into(),
+            request.request_info().src.ip(),
+            signed.map(|signed| &signed.key_name),
+            client_serial,
+        )?;
+
+        // A UDP response only carries the SOA, letting the client know to
+        // retry over TCP if it is out of date.
+        if over_udp {
+            records.truncate(1);
+        }
+
+        Ok(records)
+    }
+
+    /// Passes a dynamic update (RFC 2136) to the source that accepts updates
+    /// for the zone.
+    async fn update(&self, request: &Request, signed: Option<&SignedRequest>) -> ResponseCode {
+        if request.query().query_type() != RecordType::SOA {
+            return ResponseCode::FormErr;
+        }
+
+        let Some(signed) = signed else {
+            tracing::warn!("Refusing unsigned update");
+            return ResponseCode::Refused;
+        };
+
+        let zone = Fqdn::from(rr::Name::from(request.query().name()));
+
+        let config = self.server_state.zones.read().await.zone_config(&zone);
+        if !config.update_keys.contains(&signed.key_name) {
+            tracing::warn!(%zone, key = %signed.key_name, "Key is not allowed to update the zone");
+            return ResponseCode::Refused;
+        }
+
+        self.server_state
+            .updates
+            .update(
+                &zone,
+                Update {
+                    key: signed.key_name.clone(),
+                    prerequisites: request.answers().to_vec(),
+                    updates: request.name_servers().to_vec(),
+                },
+            )
+            .await
+    }
+
+    /// Whether the client may make this request. Only queries are limited,
+    /// zone transfers and updates have their own access controls.
+    async fn allows_request(&self, request: &Request) -> bool {
+        if request.message_type() != MessageType::Query
+            || request.op_code() != OpCode::Query
+            || matches!(
+                request.query().query_type(),
+                RecordType::AXFR | RecordType::IXFR
+            )
+        {
+            return true;
+        }
+
+        let zone = Fqdn::from(rr::Name::from(request.query().name()));
+        self.server_state
+            .zones
+            .read()
+            .await
+            .zone_config(&zone)
+            .allows_query(&request.request_info().src.ip())
+    }
+
+    async fn respond(&self, request: &Request, signed: Option<&SignedRequest>) -> Response {
+        match request.message_type() {
+            MessageType::Query => match request.op_code() {
+                OpCode::Query
+                    if matches!(
+                        request.query().query_type(),
+                        RecordType::AXFR | RecordType::IXFR
+                    ) =>
+                {
+                    match self.zone_transfer(request, signed).await {
+                        Ok(records) => {
+                            let mut header = Header::response_from_request(request.header());
+                            header.set_authoritative(true);
+
+                            let mut response = Response::new(header);
+                            response.answers = records;
+                            response
+                        }
+                        Err(response_code) => Response::with_code(request, response_code),
+                    }
+                }
+                OpCode::Query => {
+                    let server_state = self
+                        .server_state
+                        .locked_for(&request.request_info().src.ip())
+                        .await;
+                    let mut query_state = QueryState::new(
+                        request.query().original().clone(),
+                        request.recursion_desired(),
+                    );
+                    server_state.perform_query(&mut query_state).await;
+
+                    if request.edns().is_some_and(|edns| edns.dnssec_ok()) {
+                        server_state.add_dnssec_records(&mut query_state);
+                    }
+
+                    if matches!(request.request_info().protocol, Protocol::Udp) {
+                        let max_size = request
+                            .edns()
+                            .map(|edns| edns.max_payload().max(512))
+                            .unwrap_or(512);
+                        // Signed responses carry a TSIG record instead of EDNS.
+                        let reserved = signed.map_or(EDNS_SIZE, SignedRequest::signature_len);
+                        server_state.limit_response_size(
+                            &mut query_state,
+                            usize::from(max_size) - reserved,
+                        );
+                    }
+
+                    Response {
+                        header: query_state.header(request.header()),
+                        answers: query_state.answers().clone(),
+                        name_servers: query_state
+                            .name_servers()
+                            .iter()
+                            .chain(query_state.soa())
+                            .cloned()
+                            .collect(),
+                        additionals: query_state.additionals().clone(),
+                    }
+                }
+                OpCode::Update => Response::with_code(request, self.update(request, signed).await),
+                c => {
+                    tracing::warn!(op_code = ?c, "Unimplemented op_code");
+                    Response::with_code(request, ResponseCode::NotImp)
+                }
+            },
+            MessageType::Response => {
+                tracing::warn!("Got a response as a request from id");
+                Response::with_code(request, ResponseCode::FormErr)
+            }
+        }
+    }
+}
+
+impl Handler {
+    /// Answers a decoded request. The original message is needed to check
+    /// any TSIG signature.
     #[instrument(level = "trace", name = "handle_dns_request", fields(
         request.id = request.id(),
         request.protocol = %request.request_info().protocol,
@@ -30,24 +298,30 @@ impl RequestHandler for Handler {
         request.source_port = %request.request_info().src.port(),
         request.qflags = request.header().flags().to_string(),
     ), skip_all)]
-    async fn handle_request<R: ResponseHandler>(
+    pub(super) async fn handle_request<R: ResponseHandler>(
         &self,
         request: &Request,
+        message: &[u8],
         mut response_handle: R,
     ) -> ResponseInfo {
-        let mut builder = MessageResponseBuilder::from_message_request(request);
+        let mut edns = None;
 
-        // check if it's edns
-        if let Some(req_edns) = request.edns() {
+        let verified = tsig::verify_request(
+            self.server_state.zones.read().await.tsig_keys(),
+            request,
+            message,
+        );
+
+        // The TSIG record must be the final record of a signed response so
+        // EDNS is only used for unsigned requests.
+        if let (Ok(None), Some(req_edns)) = (&verified, request.edns()) {
             let mut resp_edns: Edns = Edns::new();
 
-            // check our version against the request
-            // TODO: what version are we?
+            // We only support EDNS version 0.
             let our_version = 0;
-            resp_edns.set_dnssec_ok(false);
+            resp_edns.set_dnssec_ok(req_edns.dnssec_ok());
             resp_edns.set_max_payload(req_edns.max_payload().max(512));
             resp_edns.set_version(our_version);
-            builder.edns(resp_edns);
 
             if req_edns.version() > our_version {
                 tracing::warn!(
@@ -56,6 +330,9 @@ impl RequestHandler for Handler {
                     "Invalid request edns version",
                 );
 
+                let mut builder = MessageResponseBuilder::from_message_request(request);
+                builder.edns(resp_edns);
+
                 // TODO: should ResponseHandle consume self?
                 let result = response_handle
                     .send_response(builder.error_msg(request.header(), ResponseCode::BADVERS))
@@ -70,49 +347,346 @@ impl RequestHandler for Handler {
                     Ok(info) => info,
                 };
             }
+
+            edns = Some(resp_edns);
         }
 
-        let result = match request.message_type() {
-            MessageType::Query => match request.op_code() {
-                OpCode::Query => {
-                    let server_state = self.server_state.locked().await;
-                    let mut query_state = QueryState::new(
-                        request.query().original().clone(),
-                        request.recursion_desired(),
-                    );
-                    server_state.perform_query(&mut query_state).await;
+        let protocol = request.request_info().protocol;
 
-                    response_handle
-                        .send_response(builder.build(
-                            query_state.header(request.header()),
-                            query_state.answers(),
-                            query_state.name_servers(),
-                            query_state.soa(),
-                            query_state.additionals(),
-                        ))
-                        .await
-                }
-                c => {
-                    tracing::warn!(op_code = ?c, "Unimplemented op_code");
-                    response_handle
-                        .send_response(builder.error_msg(request.header(), ResponseCode::NotImp))
-                        .await
+        let mut responses = match verified {
+            Ok(_) if !self.allows_request(request).await => {
+                tracing::debug!("Refusing query from a client that is not allowed");
+                vec![Response::with_code(request, ResponseCode::Refused)]
+            }
+            Ok(signed) => {
+                let response = self.respond(request, signed.as_ref()).await;
+
+                // Zone transfers over TCP may need several messages.
+                let is_transfer = matches!(
+                    request.query().query_type(),
+                    RecordType::AXFR | RecordType::IXFR
+                );
+                let mut responses =
+                    if is_transfer && matches!(protocol, Protocol::Tcp | Protocol::Tls) {
+                        let reserved = signed
+                            .as_ref()
+                            .map_or(EDNS_SIZE, SignedRequest::signature_len);
+                        response.split_transfer(request.query().original(), reserved)
+                    } else {
+                        vec![response]
+                    };
+
+                // Responses to signed requests must be signed with the same key.
+                if let Some(signed) = signed {
+                    let mut signer = signed.response_signer();
+
+                    for response in responses.iter_mut() {
+                        match signer.sign(
+                            &response.header,
+                            request.query().original(),
+                            &response.answers,
+                            &response.name_servers,
+                            &response.additionals,
+                        ) {
+                            Ok(signature) => response.additionals.push(signature),
+                            Err(e) => tracing::error!(error = %e, "Failed to sign response"),
+                        }
+                    }
                 }
-            },
-            MessageType::Response => {
-                tracing::warn!("Got a response as a request from id");
-                response_handle
-                    .send_response(builder.error_msg(request.header(), ResponseCode::FormErr))
-                    .await
+
+                responses
+            }
+            Err(rejected) => {
+                let mut response = Response::with_code(request, rejected.response_code);
+                response
+                    .additionals
+                    .extend(rejected.signature.map(|signature| *signature));
+                vec![response]
             }
         };
 
-        match result {
-            Err(e) => {
-                tracing::error!(error = %e, "Request failed");
-                serve_failed()
+        // Only UDP responses can be sent to a spoofed address. These are
+        // always a single message.
+        if matches!(protocol, Protocol::Udp) {
+            for response in responses.iter_mut() {
+                let key = response.rate_limit_key(request);
+                match self
+                    .server_state
+                    .rate_limiter
+                    .check(&request.request_info().src.ip(), &key)
+                {
+                    RateLimitAction::Send => {}
+                    RateLimitAction::Slip => {
+                        let mut header = response.header;
+                        header.set_truncated(true);
+                        *response = Response::new(header);
+                    }
+                    RateLimitAction::Drop => {
+                        tracing::debug!(?key, "Dropping rate limited response");
+                        return response.header.into();
+                    }
+                }
+            }
+        }
+
+        let mut info = serve_failed();
+
+        for response in responses {
+            let mut builder = MessageResponseBuilder::from_message_request(request);
+            if let Some(ref edns) = edns {
+                builder.edns(edns.clone());
+            }
+
+            let result = response_handle
+                .send_response(builder.build(
+                    response.header,
+                    response.answers.iter(),
+                    response.name_servers.iter(),
+                    &[],
+                    response.additionals.iter(),
+                ))
+                .await;
+
+            match result {
+                Err(e) => {
+                    tracing::error!(error = %e, "Request failed");
+                    return serve_failed();
+                }
+                Ok(sent) => info = sent,
+            }
+        }
+
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::ErrorKind, net::SocketAddr, sync::Arc};
+
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use futures::TryStreamExt;
+    use hickory_client::{
+        client::{AsyncClient, ClientHandle, Signer},
+        proto::{
+            iocompat::AsyncIoTokioAsStd,
+            rr::dnssec::{rdata::tsig::TsigAlgorithm, tsig::TSigner},
+        },
+        tcp::TcpClientStream,
+    };
+    use hickory_server::proto::{
+        op::{Message, Query, ResponseCode},
+        rr::RecordType,
+    };
+    use tempfile::TempDir;
+    use tokio::{
+        net::{TcpListener, TcpStream, UdpSocket},
+        sync::watch::channel,
+    };
+
+    use crate::{
+        config::Config,
+        dns::{doh, DnsServer, RData, Record, RecordSet, ServerState},
+        test::{fqdn, name, write_file},
+    };
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn access_control() {
+        let temp = TempDir::new().unwrap();
+
+        // Never answers, it only shows whether a query was sent upstream.
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let config_file = temp.path().join("config.yml");
+        write_file(
+            &config_file,
+            &format!(
+                r#"
+defaults:
+  allow_recursion: [10.10.0.0/16]
+
+zones:
+  home.local:
+    allow_query: [10.10.0.0/16, 10.12.0.5]
+  private.local:
+    allow_query: [10.12.0.0/16]
+  example.org:
+    upstream: {}
+    allow_recursion: [10.12.0.0/16]
+"#,
+                upstream.local_addr().unwrap()
+            ),
+        )
+        .await;
+
+        let config = Config::from_file(&config_file).unwrap();
+
+        let mut records = RecordSet::new();
+        records.insert(Record::new(
+            fqdn("www.home.local."),
+            RData::A("10.10.5.3".parse().unwrap()),
+        ));
+        records.insert(Record::new(
+            fqdn("secret.home.local."),
+            RData::Cname(fqdn("www.private.local.")),
+        ));
+        records.insert(Record::new(
+            fqdn("www.private.local."),
+            RData::A("10.10.5.4".parse().unwrap()),
+        ));
+        records.insert(Record::new(
+            fqdn("external.home.local."),
+            RData::Cname(fqdn("www.example.org.")),
+        ));
+
+        let (_, receiver) = channel(records);
+        let server_state = ServerState::new(receiver, config.zones);
+
+        let query = |query: &str, source: &str| {
+            let server_state = &server_state;
+
+            let mut message = Message::new();
+            message.add_query(Query::query(name(query), RecordType::A));
+            message.set_recursion_desired(true);
+            let source = SocketAddr::new(source.parse().unwrap(), 5353);
+
+            async move {
+                let response =
+                    doh::handle_message(server_state, &message.to_vec().unwrap(), source)
+                        .await
+                        .unwrap();
+                Message::from_vec(&response.message).unwrap()
             }
-            Ok(info) => info,
+        };
+
+        let response = query("www.home.local.", "10.10.1.1").await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(response.answers().len(), 1);
+        assert!(response.recursion_available());
+
+        let response = query("www.home.local.", "10.12.0.5").await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(!response.recursion_available());
+
+        let response = query("www.home.local.", "10.11.1.1").await;
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+        assert!(response.answers().is_empty());
+
+        // Other zones may still be queried but names that would need
+        // recursion are refused.
+        let response = query("www.example.org.", "10.11.1.1").await;
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+        assert!(response.answers().is_empty());
+        assert!(!response.recursion_available());
+
+        // The access controls of the zones that a CNAME leads to apply too.
+        let response = query("secret.home.local.", "10.10.1.1").await;
+        assert_eq!(response.answers().len(), 1);
+        assert_eq!(response.answers()[0].record_type(), RecordType::CNAME);
+
+        let response = query("secret.home.local.", "10.12.0.5").await;
+        assert_eq!(response.answers().len(), 2);
+
+        let response = query("external.home.local.", "10.10.1.1").await;
+        assert_eq!(response.answers().len(), 1);
+        assert!(response.recursion_available());
+
+        let mut buffer = [0; 512];
+        assert_eq!(
+            upstream.try_recv_from(&mut buffer).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn large_transfer() {
+        const SECRET: &[u8] = b"a secret used for testing large transfers";
+        const HOSTS: usize = 3000;
+
+        let temp = TempDir::new().unwrap();
+
+        let address: SocketAddr = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+
+        let config_file = temp.path().join("config.yml");
+        write_file(
+            &config_file,
+            &format!(
+                r#"
+server:
+  listen:
+    - "{address}"
+
+tsig_keys:
+  transfer.key.:
+    algorithm: hmac-sha512
+    secret: {secret}
+
+zones:
+  home.local:
+    allow_transfer: [127.0.0.0/8]
+    transfer_keys: [transfer.key]
+"#,
+                secret = STANDARD.encode(SECRET)
+            ),
+        )
+        .await;
+
+        let config = Config::from_file(&config_file).unwrap();
+
+        let mut records = RecordSet::new();
+        for host in 0..HOSTS {
+            records.insert(Record::new(
+                fqdn(&format!("host-{host:04}-with-a-longer-name.home.local.")),
+                RData::A(
+                    format!("10.10.{}.{}", host / 256, host % 256)
+                        .parse()
+                        .unwrap(),
+                ),
+            ));
+        }
+
+        let (_sender, receiver) = channel(records);
+        let server_state = ServerState::new(receiver, config.zones.clone());
+        let mut dns_server = DnsServer::new(&config.server, server_state).await.unwrap();
+
+        let signer = TSigner::new(
+            SECRET.to_vec(),
+            TsigAlgorithm::HmacSha512,
+            name("transfer.key."),
+            300,
+        )
+        .unwrap();
+
+        // The client verifies the signature chain across the messages.
+        for signer in [None, Some(Arc::new(Signer::from(signer)))] {
+            let (stream, sender) = TcpClientStream::<AsyncIoTokioAsStd<TcpStream>>::new(address);
+            let (mut client, bg) = AsyncClient::new(stream, sender, signer).await.unwrap();
+            tokio::spawn(bg);
+
+            let responses: Vec<_> = client
+                .zone_transfer(name("home.local."), None)
+                .try_collect()
+                .await
+                .unwrap();
+            assert!(responses.len() > 1);
+
+            let answers: Vec<_> = responses
+                .iter()
+                .flat_map(|response| response.answers())
+                .collect();
+            assert_eq!(answers.len(), HOSTS + 2);
+            assert_eq!(answers.first().unwrap().record_type(), RecordType::SOA);
+            assert_eq!(answers.last().unwrap().record_type(), RecordType::SOA);
+            assert!(answers[1..=HOSTS]
+                .iter()
+                .all(|record| record.record_type() == RecordType::A));
         }
+
+        dns_server.shutdown().await;
     }
 }
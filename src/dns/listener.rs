@@ -0,0 +1,195 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use futures::StreamExt;
+use hickory_server::{
+    authority::MessageRequest,
+    proto::{
+        iocompat::AsyncIoTokioAsStd,
+        op::{Message, MessageType, ResponseCode},
+        serialize::binary::{BinDecodable, BinDecoder, BinEncodable},
+        tcp::{DnsTcpStream, TcpStream},
+        udp::UdpStream,
+        xfer::{DnsStreamHandle, SerialMessage},
+        BufDnsStreamHandle,
+    },
+    server::{Protocol, Request, ResponseHandle, TimeoutStream},
+};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    sync::Semaphore,
+    task::JoinSet,
+    time::timeout,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::dns::handler::Handler;
+
+/// How long a TCP connection may stay idle before it is closed.
+const TCP_TIMEOUT: Duration = Duration::from_millis(500);
+/// How many TCP or TLS connections a single listener serves at once.
+const MAX_CONNECTIONS: usize = 512;
+
+fn reap_tasks(tasks: &mut JoinSet<()>) {
+    while tasks.try_join_next().is_some() {}
+}
+
+fn is_unrecoverable(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::NotConnected | io::ErrorKind::ConnectionAborted
+    )
+}
+
+/// Decodes and answers a single request. The request is decoded here rather
+/// than by hickory-server so that the original bytes are still available to
+/// check TSIG signatures against.
+async fn handle_message(
+    handler: &Handler,
+    message: SerialMessage,
+    protocol: Protocol,
+    mut stream_handle: BufDnsStreamHandle,
+) {
+    let source = message.addr();
+
+    match MessageRequest::read(&mut BinDecoder::new(message.bytes())) {
+        Ok(request) => {
+            // Responses are ignored to avoid being used for reflection.
+            if request.message_type() == MessageType::Response {
+                return;
+            }
+
+            let request = Request::new(request, source, protocol);
+            let response_handle = ResponseHandle::new(source, stream_handle, protocol);
+            handler
+                .handle_request(&request, message.bytes(), response_handle)
+                .await;
+        }
+        Err(e) => {
+            let Some((header, error)) = e.kind().as_form_error() else {
+                tracing::debug!(%source, error = %e, "Unable to decode request");
+                return;
+            };
+            tracing::debug!(%source, %error, "Malformed request");
+
+            let response = Message::error_msg(header.id(), header.op_code(), ResponseCode::FormErr);
+            let result = response
+                .to_bytes()
+                .and_then(|bytes| stream_handle.send(SerialMessage::new(bytes, source)));
+            if let Err(e) = result {
+                tracing::error!(error = %e, "Request error");
+            }
+        }
+    }
+}
+
+/// Serves requests from a UDP socket until the task is aborted.
+pub(super) async fn serve_udp(socket: UdpSocket, handler: Arc<Handler>) {
+    // The remote address is replaced for every response.
+    let (mut stream, stream_handle) =
+        UdpStream::with_bound(socket, ([127, 255, 255, 254], 0).into());
+    let mut tasks = JoinSet::new();
+
+    while let Some(message) = stream.next().await {
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                tracing::warn!(error = %e, "Error receiving UDP message");
+                if is_unrecoverable(&e) {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        if message.addr().port() == 0 || message.addr().ip().is_unspecified() {
+            continue;
+        }
+
+        let handler = handler.clone();
+        let stream_handle = stream_handle.with_remote_addr(message.addr());
+        tasks.spawn(async move {
+            handle_message(&handler, message, Protocol::Udp, stream_handle).await;
+        });
+
+        reap_tasks(&mut tasks);
+    }
+
+    tracing::error!("UDP socket closed unexpectedly");
+}
+
+/// Answers requests from a single TCP or TLS connection in turn.
+async fn serve_connection<S: DnsTcpStream>(
+    stream: S,
+    source: SocketAddr,
+    protocol: Protocol,
+    handler: &Handler,
+) {
+    let (stream, stream_handle) = TcpStream::from_stream(stream, source);
+    let mut stream = TimeoutStream::new(stream, TCP_TIMEOUT);
+
+    while let Some(message) = stream.next().await {
+        match message {
+            Ok(message) => {
+                handle_message(handler, message, protocol, stream_handle.clone()).await;
+            }
+            Err(e) => {
+                tracing::debug!(%source, error = %e, "Closing connection");
+                return;
+            }
+        }
+    }
+}
+
+/// Serves requests from connections to a TCP socket, optionally using TLS,
+/// until the task is aborted.
+pub(super) async fn serve_tcp(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    handler: Arc<Handler>,
+) {
+    let mut tasks = JoinSet::new();
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+
+    loop {
+        // New connections wait in the backlog while the limit is reached.
+        let Ok(permit) = connections.clone().acquire_owned().await else {
+            break;
+        };
+
+        let (stream, source) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::debug!(error = %e, "Error accepting TCP connection");
+                if is_unrecoverable(&e) {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        let handler = handler.clone();
+        let tls = tls.clone();
+        tasks.spawn(async move {
+            let _permit = permit;
+
+            match tls {
+                Some(acceptor) => match timeout(TCP_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        serve_connection(AsyncIoTokioAsStd(stream), source, Protocol::Tls, &handler)
+                            .await
+                    }
+                    Ok(Err(e)) => tracing::debug!(%source, error = %e, "TLS handshake failed"),
+                    Err(_) => tracing::debug!(%source, "TLS handshake timed out"),
+                },
+                None => {
+                    serve_connection(AsyncIoTokioAsStd(stream), source, Protocol::Tcp, &handler)
+                        .await
+                }
+            }
+        });
+
+        reap_tasks(&mut tasks);
+    }
+
+    tracing::error!("TCP socket closed unexpectedly");
+}
@@ -1,47 +1,72 @@
-use std::{net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
+use std::{
+    collections::VecDeque,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
 
 use anyhow::Error;
 use futures::FutureExt;
-use hickory_server::{
-    proto::{
-        op::Query,
-        rr::{self, Name, RecordType},
-    },
-    ServerFuture,
+use hickory_server::proto::{
+    op::{Query, ResponseCode},
+    rr::{self, Name, RecordType},
 };
-use serde::Deserialize;
 use tokio::{
     join,
-    net::{TcpListener, UdpSocket},
     sync::{watch::Receiver, RwLock},
 };
 use tracing::{instrument, Span};
 
+mod additional;
+mod cache;
+mod dnssec;
+pub(crate) mod doh;
 mod handler;
+mod listener;
+pub(crate) mod notify;
+mod policy;
 mod query;
+mod rate_limit;
 mod record;
+mod serial;
+mod server;
 pub(crate) mod store;
+mod tls;
+mod transfer;
+mod tsig;
 mod upstream;
 
-pub(crate) use record::{Fqdn, RData, Record, RecordSet};
-pub(crate) use upstream::Upstream;
+pub(crate) use dnssec::DnssecConfig;
+pub(crate) use rate_limit::RateLimitCounters;
+pub(crate) use record::{Fqdn, PtrConflict, PtrNames, RData, Record, RecordSet};
+pub(crate) use server::{DnsServer, ServerConfig};
+pub(crate) use tsig::TsigKey;
+pub(crate) use upstream::{Upstream, UpstreamMode};
 
-use self::handler::Handler;
 use crate::{
-    config::{ZoneConfigProvider, Zones},
-    dns::query::QueryState,
+    config::{View, ZoneConfig, ZoneConfigProvider},
+    dns::{
+        cache::ResponseCache, query::QueryState, rate_limit::RateLimiter, serial::ZoneSerials,
+        store::RecordStore,
+    },
+    sources::{
+        blocklist::{BlockResponse, Blocklists},
+        rpz::ResponsePolicies,
+        update::UpdateZones,
+    },
 };
 
-#[derive(Clone, Debug, PartialEq, Eq, Default, Deserialize)]
-pub(crate) struct ServerConfig {
-    #[serde(default)]
-    port: Option<u16>,
-}
-
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub(crate) struct ServerState<Z> {
     pub(crate) receiver: Receiver<RecordSet>,
     pub(crate) zones: Arc<RwLock<Z>>,
+    cache: Arc<ResponseCache>,
+    rate_limiter: Arc<RateLimiter>,
+    serials: Arc<ZoneSerials>,
+    updates: Arc<UpdateZones>,
+    blocklists: Arc<Blocklists>,
+    policies: Arc<ResponsePolicies>,
+    record_store: Option<RecordStore>,
 }
 
 async fn resolve_name<Z: ZoneConfigProvider + Clone>(
@@ -65,8 +90,15 @@ impl<Z: ZoneConfigProvider + Clone + Send + Sync + 'static> reqwest::dns::Resolv
 }
 
 pub(crate) struct LockedServerState<Z> {
-    pub(crate) records: RecordSet,
+    pub(crate) records: Arc<RecordSet>,
     pub(crate) zones: Z,
+    /// The client being answered and its view.
+    client: Option<IpAddr>,
+    view: Option<View>,
+    cache: Arc<ResponseCache>,
+    serials: Arc<ZoneSerials>,
+    blocklists: Arc<Blocklists>,
+    policies: Arc<ResponsePolicies>,
 }
 
 impl<Z: Clone> ServerState<Z> {
@@ -74,23 +106,108 @@ impl<Z: Clone> ServerState<Z> {
         Self {
             receiver,
             zones: Arc::new(RwLock::new(zones)),
+            cache: Default::default(),
+            rate_limiter: Default::default(),
+            serials: Default::default(),
+            updates: Default::default(),
+            blocklists: Default::default(),
+            policies: Default::default(),
+            record_store: None,
         }
     }
 
-    pub(crate) async fn replace_zones(&self, zones: Z) {
-        let mut locked = self.zones.write().await;
-        *locked = zones;
+    /// Uses the zone serials tracked by a record store in SOA records, passes
+    /// dynamic updates to its sources, applies its blocklists and response
+    /// policies and builds the records for views from it.
+    pub(crate) fn with_record_store(mut self, record_store: &RecordStore) -> Self {
+        self.serials = record_store.serials.clone();
+        self.updates = record_store.updates.clone();
+        self.blocklists = record_store.blocklists.clone();
+        self.policies = record_store.policies.clone();
+        self.record_store = Some(record_store.clone());
+        self
+    }
+
+    /// The number of responses that rate limiting has dropped or truncated.
+    pub(crate) fn rate_limit_counters(&self) -> RateLimitCounters {
+        self.rate_limiter.counters()
     }
 
     pub(crate) async fn locked(&self) -> LockedServerState<Z> {
         let zones = self.zones.read().await.clone();
-        let records = self.receiver.borrow().clone();
+        let records = Arc::new(self.receiver.borrow().clone());
+
+        self.lock(zones, records)
+    }
+
+    fn lock(&self, zones: Z, records: Arc<RecordSet>) -> LockedServerState<Z> {
+        LockedServerState {
+            zones,
+            records,
+            client: None,
+            view: None,
+            cache: self.cache.clone(),
+            serials: self.serials.clone(),
+            blocklists: self.blocklists.clone(),
+            policies: self.policies.clone(),
+        }
+    }
+}
+
+impl<Z: ZoneConfigProvider + Clone> ServerState<Z> {
+    /// Locks the state as seen by a client, with the records and upstreams of
+    /// the view that it belongs to.
+    pub(crate) async fn locked_for(&self, client: &IpAddr) -> LockedServerState<Z> {
+        let zones = self.zones.read().await.clone();
+        let view = zones.view(client);
+
+        // The full set of records is only copied when the view doesn't select
+        // its own sources.
+        let view_records = match (&view, &self.record_store) {
+            (Some(view), Some(record_store)) => record_store.view_records(view).await,
+            _ => None,
+        };
+        let records = view_records.unwrap_or_else(|| Arc::new(self.receiver.borrow().clone()));
+
+        let mut locked = self.lock(zones, records);
+        locked.client = Some(*client);
+        locked.view = view;
+        locked
+    }
+}
 
-        LockedServerState { zones, records }
+impl<Z: ZoneConfigProvider> ServerState<Z> {
+    /// Replaces the zone configuration. Any upstream connections held by the
+    /// previous configuration are closed once in-flight queries complete and
+    /// cached upstream answers are dropped for names whose upstreams changed.
+    pub(crate) async fn replace_zones(&self, zones: Z) {
+        let mut locked = self.zones.write().await;
+
+        self.cache.remove_names(|name| {
+            let fqdn = Fqdn::from(name.clone());
+            locked.zone_config(&fqdn).upstreams != zones.zone_config(&fqdn).upstreams
+        });
+
+        *locked = zones;
     }
 }
 
 impl<Z: ZoneConfigProvider> LockedServerState<Z> {
+    fn zone_config(&self, fqdn: &Fqdn) -> ZoneConfig {
+        let mut config = self.zones.zone_config(fqdn);
+        if let Some(ref origin) = config.origin {
+            config.serial = self.serials.serial(origin);
+        }
+        if let Some(upstreams) = self.view_upstreams(fqdn) {
+            config.upstreams = upstreams.clone();
+        }
+        config
+    }
+
+    fn view_upstreams(&self, fqdn: &Fqdn) -> Option<&VecDeque<Upstream>> {
+        self.view.as_ref()?.upstreams(fqdn)
+    }
+
     #[instrument(level = "trace", skip(self))]
     async fn resolve_http_address(&self, name: String) -> Result<Vec<SocketAddr>, Error> {
         let mut name = Name::from_str(&name)?;
@@ -128,16 +245,37 @@ impl<Z: ZoneConfigProvider> LockedServerState<Z> {
         Ok(results)
     }
 
-    #[instrument(level = "trace", fields(%name), skip(self, query_state))]
+    #[instrument(level = "trace", fields(%name, stale), skip(self, query_state))]
     async fn resolve_name(&self, name: &Name, query_state: &mut QueryState) {
         let fqdn = Fqdn::from(name.clone());
-        let config = self.zones.zone_config(&fqdn);
+        let config = self.zone_config(&fqdn);
+
+        // Names reached by following a CNAME may be in a zone with different
+        // access controls to the queried name.
+        if self
+            .client
+            .is_some_and(|client| !config.allows_query(&client))
+        {
+            tracing::debug!("Client may not query the zone");
+            return;
+        }
+
+        let recursion_allowed = self
+            .client
+            .is_none_or(|client| config.allows_recursion(&client));
 
         let mut needs_recursion = true;
 
-        let records: Vec<rr::Record> = self
+        let mut found: Vec<Record> = self
             .records
             .lookup(name, query_state.query_class(), query_state.query_type())
+            .collect();
+        if query_state.query_type() == RecordType::PTR {
+            found = self.records.choose_ptrs(found, &config.ptr_names);
+        }
+
+        let mut records: Vec<rr::Record> = found
+            .into_iter()
             .filter_map(|record| {
                 needs_recursion = false;
 
@@ -162,8 +300,19 @@ impl<Z: ZoneConfigProvider> LockedServerState<Z> {
             })
             .collect();
 
-        if !config.upstreams.is_empty() && name == query_state.query.name() {
-            query_state.recursion_available = true;
+        if let (Some(ref dnssec), Some(ref origin)) = (&config.dnssec, &config.origin) {
+            if query_state.query_type() == RecordType::DNSKEY && *origin == fqdn {
+                needs_recursion = false;
+                records.extend(dnssec.dnskeys(origin, config.ttl));
+            }
+        }
+
+        if name == query_state.query.name() {
+            query_state.recursion_allowed = recursion_allowed;
+
+            if !config.upstreams.is_empty() {
+                query_state.recursion_available = true;
+            }
         }
 
         if !records.is_empty() {
@@ -172,15 +321,140 @@ impl<Z: ZoneConfigProvider> LockedServerState<Z> {
             if name == query_state.query.name() {
                 query_state.soa = config.soa();
             }
-        };
+        } else if needs_recursion {
+            if let Some(response) = self.blocklists.blocked(name, self.view.as_ref()) {
+                self.answer_blocked(name, response, &config, query_state);
+                return;
+            }
+
+            let is_apex = config.authoritative && config.origin.as_ref() == Some(&fqdn);
+
+            // A name that exists without records of the requested type, or an
+            // alias to resolve, gets an empty answer rather than NXDOMAIN.
+            if is_apex
+                || self.records.name_exists(name)
+                || !self.records.record_types(name).is_empty()
+            {
+                query_state.response_code = ResponseCode::NoError;
+            }
+
+            if name == query_state.query.name() {
+                query_state.soa = config.soa();
+            }
+        }
+
+        if needs_recursion
+            && query_state.recursion_desired
+            && !recursion_allowed
+            && config.forwards()
+            && name == query_state.query.name()
+        {
+            // The answer would come from an upstream that the client may not
+            // use. An NXDOMAIN here would be cached as if the name were
+            // missing.
+            tracing::debug!("Client may not recurse for the zone");
+            query_state.response_code = ResponseCode::Refused;
+            query_state.soa = None;
+            return;
+        }
+
+        if needs_recursion
+            && query_state.recursion_desired
+            && recursion_allowed
+            && config.forwards()
+        {
+            let query_class = query_state.query_class();
+            let query_type = query_state.query_type();
+
+            // Answers from a view's own upstreams aren't shared with other
+            // clients.
+            let cache = self.view_upstreams(&fqdn).is_none().then_some(&self.cache);
+
+            if let Some(message) = cache.and_then(|cache| cache.get(name, query_class, query_type))
+            {
+                tracing::trace!("Using cached upstream response");
+                query_state.add_response(name, message);
+                return;
+            }
 
-        if needs_recursion && query_state.recursion_desired {
-            for upstream in &config.upstreams {
-                upstream.resolve(name, query_state).await;
+            let stale = cache.and_then(|cache| cache.get_stale(name, query_class, query_type));
+
+            // Don't wait for upstreams that are known to be down when there is
+            // something to answer with.
+            let response = if stale.is_some() && upstream::all_unavailable(&config.upstreams) {
+                None
+            } else {
+                upstream::resolve(
+                    &config.upstreams,
+                    config.upstream_mode,
+                    name,
+                    query_class,
+                    query_type,
+                )
+                .await
+            };
+
+            match (response, stale) {
+                (Some(message), _) if upstream::is_answer(&message) => {
+                    if let Some(cache) = cache {
+                        cache.insert(name, query_class, query_type, &message);
+                    }
+                    query_state.add_response(name, message);
+                }
+                (_, Some(stale)) => {
+                    tracing::info!(%name, "Upstreams unavailable, serving stale answer");
+                    Span::current().record("stale", true);
+                    query_state.add_response(name, stale);
+                }
+                (Some(message), None) => {
+                    query_state.add_response(name, message);
+                }
+                (None, None) => {}
             }
         }
     }
 
+    /// Answers for a name that a blocklist blocks instead of asking upstream.
+    fn answer_blocked(
+        &self,
+        name: &Name,
+        response: BlockResponse,
+        config: &ZoneConfig,
+        query_state: &mut QueryState,
+    ) {
+        tracing::debug!(%name, "Blocked name");
+
+        if name == query_state.query.name() {
+            query_state.soa = config.soa();
+        }
+
+        let address = match response {
+            BlockResponse::NxDomain => {
+                query_state.response_code = ResponseCode::NXDomain;
+                return;
+            }
+            BlockResponse::Null => match query_state.query_type() {
+                RecordType::AAAA => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                _ => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            },
+            BlockResponse::Sink(address) => address,
+        };
+
+        query_state.response_code = ResponseCode::NoError;
+
+        let rdata = match (query_state.query_type(), address) {
+            (RecordType::A, IpAddr::V4(ip)) => rr::RData::A(ip.into()),
+            (RecordType::AAAA, IpAddr::V6(ip)) => rr::RData::AAAA(ip.into()),
+            _ => return,
+        };
+
+        query_state.add_answers(vec![rr::Record::from_rdata(
+            name.clone(),
+            config.ttl,
+            rdata,
+        )]);
+    }
+
     async fn lookup_names(&self, query_state: &mut QueryState) {
         while let Some(name) = query_state.next_unknown() {
             self.resolve_name(&name, query_state).await;
@@ -204,7 +478,7 @@ impl<Z: ZoneConfigProvider> LockedServerState<Z> {
 
             for (name, alias) in query_state.aliases.iter() {
                 let fqdn = Fqdn::from(name.clone());
-                let config = self.zones.zone_config(&fqdn);
+                let config = self.zone_config(&fqdn);
 
                 for rdata in alias_query_state.resolve_name(alias) {
                     if rdata.record_type() == query_state.query_type() {
@@ -216,89 +490,43 @@ impl<Z: ZoneConfigProvider> LockedServerState<Z> {
             query_state.add_answers(records);
         }
 
+        self.apply_policies(query_state).await;
+        self.add_target_addresses(query_state);
+
         let span = Span::current();
         span.record("response_code", query_state.response_code.to_str());
     }
 }
 
-pub(crate) struct DnsServer {
-    server_state: ServerState<Zones>,
-    server: ServerFuture<Handler>,
-}
-
-impl DnsServer {
-    pub(crate) async fn new(
-        server_config: &ServerConfig,
-        server_state: ServerState<Zones>,
-    ) -> Self {
-        Self {
-            server_state: server_state.clone(),
-            server: Self::build_server(server_config, server_state).await,
-        }
-    }
-
-    pub(crate) async fn shutdown(&mut self) {
-        tracing::debug!("Shutting down DNS service");
-
-        if let Err(e) = self.server.shutdown_gracefully().await {
-            tracing::error!(error = %e, "Failure while shutting down DNS server.");
-        }
-    }
-
-    pub(crate) async fn restart(&mut self, server_config: &ServerConfig) {
-        tracing::debug!("Restarting DNS service");
-
-        if let Err(e) = self.server.block_until_done().await {
-            tracing::error!(error = %e, "Failure while shutting down DNS server.");
-        }
-
-        self.server = Self::build_server(server_config, self.server_state.clone()).await;
-    }
-
-    async fn build_server(
-        server_config: &ServerConfig,
-        server_state: ServerState<Zones>,
-    ) -> ServerFuture<Handler> {
-        let handler = Handler { server_state };
-
-        let port = server_config.port.unwrap_or(53);
-
-        let mut server = ServerFuture::new(handler);
-
-        match UdpSocket::bind(("0.0.0.0", port)).await {
-            Ok(socket) => {
-                tracing::info!("Server listening on udp://0.0.0.0:{}", port);
-                server.register_socket(socket);
-            }
-            Err(e) => tracing::error!(error = %e, "Unable to open UDP socket"),
-        }
-
-        match TcpListener::bind(("0.0.0.0", port)).await {
-            Ok(socket) => {
-                tracing::info!("Server listening on tcp://0.0.0.0:{}", port);
-                server.register_listener(socket, Duration::from_millis(500));
-            }
-            Err(e) => tracing::error!(error = %e, "Unable to open TCP socket"),
-        }
-
-        server
-    }
-}
-
 #[cfg(test)]
 mod tests {
-    use std::str::FromStr;
+    use std::{
+        str::FromStr,
+        sync::{atomic::Ordering, Arc},
+        time::Duration,
+    };
 
     use hickory_server::proto::{
-        op::{Query, ResponseCode},
-        rr::{DNSClass, RecordType},
+        op::{Message, Query, ResponseCode},
+        rr::{self, DNSClass, RecordType},
     };
-    use tokio::sync::watch::channel;
+    use reqwest::Client;
+    use tokio::{net::UdpSocket, sync::watch::channel, time::sleep};
+
+    use tempfile::TempDir;
+    use uuid::Uuid;
 
     use crate::{
-        config::{ZoneConfig, ZoneConfigProvider},
-        dns::{query::QueryState, Fqdn, RData, Record, RecordSet, ServerState, Upstream},
-        test::{coredns_container, fqdn, name, rdata_a, rdata_aaaa, rdata_aname, rdata_cname},
+        config::{Config, ZoneConfig, ZoneConfigProvider},
+        dns::{
+            query::QueryState, store::RecordStore, Fqdn, RData, Record, RecordSet, ServerState,
+            Upstream,
+        },
+        sources::{SourceId, SourceType, Sources},
+        test::{
+            coredns_container, fqdn, name, rdata_a, rdata_aaaa, rdata_aname, rdata_cname, timeout,
+            udp_server, write_file,
+        },
         util::{Address, Host},
     };
 
@@ -321,8 +549,8 @@ mod tests {
             ZoneConfig {
                 origin: None,
                 upstreams: [self.upstream.clone()].into(),
-                ttl: 300,
                 authoritative: true,
+                ..Default::default()
             }
         }
     }
@@ -362,7 +590,7 @@ mod tests {
         ));
 
         let mut query_state = QueryState::new(query.clone(), true);
-        server_state.records = records.clone();
+        server_state.records = Arc::new(records.clone());
         server_state.perform_query(&mut query_state).await;
 
         assert_eq!(query_state.response_code, ResponseCode::NoError);
@@ -384,6 +612,608 @@ mod tests {
         assert_eq!(*record.data().unwrap(), rdata_a("10.10.45.23"));
     }
 
+    #[derive(Clone)]
+    struct AuthoritativeZone {}
+
+    impl ZoneConfigProvider for AuthoritativeZone {
+        fn zone_config(&self, _: &Fqdn) -> ZoneConfig {
+            ZoneConfig {
+                origin: Some(fqdn("home.local.")),
+                authoritative: true,
+                ..Default::default()
+            }
+        }
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn negative_answers() {
+        let mut records = RecordSet::new();
+        records.insert(Record::new(
+            fqdn("www.home.local."),
+            RData::Aaaa("2a00:1450:4009:81e::200e".parse().unwrap()),
+        ));
+        records.insert(Record::new(
+            fqdn("a.b.home.local."),
+            RData::A("10.10.5.3".parse().unwrap()),
+        ));
+        records.insert(Record::new(
+            fqdn("*.dev.home.local."),
+            RData::A("10.10.5.4".parse().unwrap()),
+        ));
+
+        let (_, receiver) = channel(records);
+        let server_state = ServerState::new(receiver, AuthoritativeZone {})
+            .locked()
+            .await;
+
+        for (name, response_code) in [
+            ("www.home.local.", ResponseCode::NoError),
+            ("b.home.local.", ResponseCode::NoError),
+            ("home.local.", ResponseCode::NoError),
+            ("foo.dev.home.local.", ResponseCode::NoError),
+            ("missing.home.local.", ResponseCode::NXDomain),
+            ("www.b.home.local.", ResponseCode::NXDomain),
+        ] {
+            let mut query_state =
+                QueryState::new(Query::query(self::name(name), RecordType::MX), false);
+            server_state.perform_query(&mut query_state).await;
+
+            assert_eq!(query_state.response_code, response_code, "{name}");
+            assert!(query_state.answers().is_empty());
+
+            let soa = query_state.soa().as_ref().unwrap();
+            assert_eq!(soa.record_type(), RecordType::SOA);
+            assert_eq!(*soa.name(), self::name("home.local."));
+        }
+
+        let mut query_state = QueryState::new(
+            Query::query(name("www.home.local."), RecordType::AAAA),
+            false,
+        );
+        server_state.perform_query(&mut query_state).await;
+        assert_eq!(query_state.response_code, ResponseCode::NoError);
+        assert_eq!(query_state.answers().len(), 1);
+    }
+
+    #[derive(Clone)]
+    struct ReverseZone {
+        upstream: Upstream,
+    }
+
+    impl ZoneConfigProvider for ReverseZone {
+        fn zone_config(&self, name: &Fqdn) -> ZoneConfig {
+            let origin = fqdn("10.in-addr.arpa.");
+
+            ZoneConfig {
+                authoritative: origin.zone_of(name),
+                origin: origin.zone_of(name).then_some(origin),
+                upstreams: [self.upstream.clone()].into(),
+                ..Default::default()
+            }
+        }
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn reverse_zone() {
+        let (address, received) = udp_server("10.10.10.5", Duration::ZERO).await;
+        let upstream = Upstream::from(Address {
+            host: Host::from_str("127.0.0.1").unwrap(),
+            port: Some(address.port()),
+        });
+
+        let mut records = RecordSet::new();
+        records.insert(Record::new(
+            fqdn("www.home.local."),
+            RData::A("10.10.5.3".parse().unwrap()),
+        ));
+
+        let (_, receiver) = channel(records);
+        let server_state = ServerState::new(receiver, ReverseZone { upstream })
+            .locked()
+            .await;
+
+        let mut query_state = QueryState::new(
+            Query::query(name("3.5.10.10.in-addr.arpa."), RecordType::PTR),
+            true,
+        );
+        server_state.perform_query(&mut query_state).await;
+        assert_eq!(query_state.response_code, ResponseCode::NoError);
+        assert_eq!(
+            *query_state.answers().first().unwrap().data().unwrap(),
+            rr::RData::PTR(rr::rdata::PTR(name("www.home.local.")))
+        );
+
+        // Unknown addresses in the zone are not sent upstream.
+        let mut query_state = QueryState::new(
+            Query::query(name("4.5.10.10.in-addr.arpa."), RecordType::PTR),
+            true,
+        );
+        server_state.perform_query(&mut query_state).await;
+        assert_eq!(query_state.response_code, ResponseCode::NXDomain);
+        assert!(query_state.answers().is_empty());
+        assert_eq!(
+            *query_state.soa().as_ref().unwrap().name(),
+            name("10.in-addr.arpa.")
+        );
+        assert_eq!(received.load(Ordering::SeqCst), 0);
+
+        // Addresses outside of the zone still are.
+        let mut query_state = QueryState::new(
+            Query::query(name("4.5.168.192.in-addr.arpa."), RecordType::PTR),
+            true,
+        );
+        server_state.perform_query(&mut query_state).await;
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn views() {
+        let temp = TempDir::new().unwrap();
+
+        let config_file = temp.path().join("config.yml");
+        write_file(
+            &config_file,
+            r#"
+views:
+  lan:
+    networks: [10.10.0.0/16]
+    sources: [file:lan, docker]
+  vpn:
+    networks: [10.0.0.0/8, 10.8.0.0/24]
+    sources: [file:vpn]
+"#,
+        )
+        .await;
+
+        let config = Config::from_file(&config_file).unwrap();
+
+        let record_store = RecordStore::new();
+        let server_id = Uuid::new_v4();
+
+        for (source, address) in [("lan", "10.10.5.3"), ("vpn", "10.8.0.3")] {
+            let mut records = RecordSet::new();
+            records.insert(Record::new(
+                fqdn("app.mossop.dev."),
+                RData::A(address.parse().unwrap()),
+            ));
+            record_store
+                .add_source_records(
+                    &SourceId::new(&server_id, SourceType::File, source),
+                    records,
+                )
+                .await;
+        }
+
+        let server_state = ServerState::new(record_store.receiver(), config.zones)
+            .with_record_store(&record_store);
+
+        let lookup = |client: &str| {
+            let server_state = &server_state;
+            let client = client.parse().unwrap();
+
+            async move {
+                let mut query_state =
+                    QueryState::new(Query::query(name("app.mossop.dev."), RecordType::A), false);
+                server_state
+                    .locked_for(&client)
+                    .await
+                    .perform_query(&mut query_state)
+                    .await;
+
+                let mut addresses: Vec<String> = query_state
+                    .answers()
+                    .iter()
+                    .map(|record| record.data().unwrap().to_string())
+                    .collect();
+                addresses.sort();
+                addresses
+            }
+        };
+
+        assert_eq!(lookup("10.10.1.1").await, vec!["10.10.5.3"]);
+        assert_eq!(lookup("10.8.0.5").await, vec!["10.8.0.3"]);
+        assert_eq!(lookup("10.11.0.5").await, vec!["10.8.0.3"]);
+        assert_eq!(lookup("192.168.1.1").await, vec!["10.10.5.3", "10.8.0.3"]);
+
+        // Clients in the same view share its records.
+        let client = "10.10.1.1".parse().unwrap();
+        assert!(Arc::ptr_eq(
+            &server_state.locked_for(&client).await.records,
+            &server_state.locked_for(&client).await.records
+        ));
+
+        // Views see changes to their sources.
+        let mut records = RecordSet::new();
+        records.insert(Record::new(
+            fqdn("app.mossop.dev."),
+            RData::A("10.10.5.4".parse().unwrap()),
+        ));
+        record_store
+            .add_source_records(
+                &SourceId::new(&server_id, SourceType::Docker, "local"),
+                records,
+            )
+            .await;
+
+        assert_eq!(lookup("10.10.1.1").await, vec!["10.10.5.3", "10.10.5.4"]);
+        assert_eq!(lookup("10.8.0.5").await, vec!["10.8.0.3"]);
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn blocklists() {
+        let temp = TempDir::new().unwrap();
+
+        write_file(
+            &temp.path().join("hosts.txt"),
+            r#"
+0.0.0.0 ads.example.com
+0.0.0.0 www.home.local
+"#,
+        )
+        .await;
+        write_file(
+            &temp.path().join("trackers.txt"),
+            "||tracker.example.org^\n",
+        )
+        .await;
+
+        let config_file = temp.path().join("config.yml");
+        write_file(
+            &config_file,
+            r#"
+sources:
+  blocklist:
+    ads:
+      lists:
+        - file: hosts.txt
+    trackers:
+      lists:
+        - file: trackers.txt
+      response: nxdomain
+"#,
+        )
+        .await;
+
+        let config = Config::from_file(&config_file).unwrap();
+
+        let record_store = RecordStore::new();
+        let mut sources = Sources::new(record_store.clone(), Client::new());
+        sources.install_sources(config, None).await;
+
+        let mut records = RecordSet::new();
+        records.insert(Record::new(
+            fqdn("www.home.local."),
+            RData::A("10.10.5.3".parse().unwrap()),
+        ));
+        records.insert(Record::new(
+            fqdn("cdn.home.local."),
+            RData::Cname(fqdn("ads.example.com.")),
+        ));
+        record_store
+            .add_source_records(
+                &SourceId::new(&Uuid::new_v4(), SourceType::File, "local"),
+                records,
+            )
+            .await;
+
+        timeout(async {
+            while record_store
+                .blocklists
+                .blocked(&name("a.tracker.example.org."), None)
+                .is_none()
+                || record_store
+                    .blocklists
+                    .blocked(&name("ads.example.com."), None)
+                    .is_none()
+            {
+                sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+
+        let server_state = ServerState::new(record_store.receiver(), EmptyZones {})
+            .with_record_store(&record_store);
+
+        let lookup = |query_name: &str, query_type: RecordType| {
+            let server_state = &server_state;
+            let query_name = name(query_name);
+
+            async move {
+                let mut query_state = QueryState::new(Query::query(query_name, query_type), true);
+                server_state
+                    .locked()
+                    .await
+                    .perform_query(&mut query_state)
+                    .await;
+
+                let answers: Vec<String> = query_state
+                    .answers()
+                    .iter()
+                    .map(|record| record.data().unwrap().to_string())
+                    .collect();
+                (query_state.response_code, answers)
+            }
+        };
+
+        assert_eq!(
+            lookup("ads.example.com.", RecordType::A).await,
+            (ResponseCode::NoError, vec!["0.0.0.0".to_owned()])
+        );
+        assert_eq!(
+            lookup("ads.example.com.", RecordType::AAAA).await,
+            (ResponseCode::NoError, vec!["::".to_owned()])
+        );
+        assert_eq!(
+            lookup("ads.example.com.", RecordType::TXT).await,
+            (ResponseCode::NoError, vec![])
+        );
+        assert_eq!(
+            lookup("cdn.home.local.", RecordType::A).await,
+            (
+                ResponseCode::NoError,
+                vec!["ads.example.com.".to_owned(), "0.0.0.0".to_owned()]
+            )
+        );
+        assert_eq!(
+            lookup("a.tracker.example.org.", RecordType::A).await,
+            (ResponseCode::NXDomain, vec![])
+        );
+
+        // Local records are never blocked.
+        assert_eq!(
+            lookup("www.home.local.", RecordType::A).await,
+            (ResponseCode::NoError, vec!["10.10.5.3".to_owned()])
+        );
+
+        sources.shutdown().await;
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn response_policies() {
+        let (address, _) = udp_server("10.10.10.5", Duration::ZERO).await;
+        let upstream = Upstream::from(Address {
+            host: Host::from_str("127.0.0.1").unwrap(),
+            port: Some(address.port()),
+        });
+
+        let temp = TempDir::new().unwrap();
+
+        write_file(
+            &temp.path().join("rpz.zone"),
+            r#"
+$TTL 300
+@ SOA localhost. admin.localhost. 1 3600 600 86400 300
+blocked.example.com CNAME .
+*.nodata.example.com CNAME *.
+local.example.com A 10.10.5.10
+passthru.example.com CNAME rpz-passthru.
+32.5.10.10.10.rpz-ip CNAME .
+32.1.1.10.10.rpz-client-ip A 10.10.5.99
+"#,
+        )
+        .await;
+
+        let config_file = temp.path().join("config.yml");
+        write_file(
+            &config_file,
+            r#"
+sources:
+  rpz:
+    policy:
+      zone: rpz.local
+      file: rpz.zone
+"#,
+        )
+        .await;
+
+        let config = Config::from_file(&config_file).unwrap();
+
+        let record_store = RecordStore::new();
+        let mut sources = Sources::new(record_store.clone(), Client::new());
+        sources.install_sources(config, None).await;
+
+        let mut records = RecordSet::new();
+        records.insert(Record::new(
+            fqdn("www.home.local."),
+            RData::A("10.10.5.3".parse().unwrap()),
+        ));
+        records.insert(Record::new(
+            fqdn("alias.home.local."),
+            RData::Cname(fqdn("blocked.example.com.")),
+        ));
+        records.insert(Record::new(
+            fqdn("cdn.home.local."),
+            RData::Cname(fqdn("other.example.com.")),
+        ));
+        record_store
+            .add_source_records(
+                &SourceId::new(&Uuid::new_v4(), SourceType::File, "local"),
+                records,
+            )
+            .await;
+
+        timeout(async {
+            while record_store
+                .policies
+                .find(None, &[name("blocked.example.com.")], &[], None)
+                .is_none()
+            {
+                sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+
+        let server_state = ServerState::new(record_store.receiver(), ZoneWithUpstream { upstream })
+            .with_record_store(&record_store);
+
+        let lookup = |client: &str, query_name: &str| {
+            let server_state = &server_state;
+            let client = client.parse().unwrap();
+            let query_name = name(query_name);
+
+            async move {
+                let mut query_state =
+                    QueryState::new(Query::query(query_name, RecordType::A), true);
+                server_state
+                    .locked_for(&client)
+                    .await
+                    .perform_query(&mut query_state)
+                    .await;
+
+                let answers: Vec<String> = query_state
+                    .answers()
+                    .iter()
+                    .map(|record| record.data().unwrap().to_string())
+                    .collect();
+                (query_state.response_code, answers)
+            }
+        };
+
+        assert_eq!(
+            lookup("10.10.1.2", "blocked.example.com.").await,
+            (ResponseCode::NXDomain, vec![])
+        );
+        assert_eq!(
+            lookup("10.10.1.2", "www.nodata.example.com.").await,
+            (ResponseCode::NoError, vec![])
+        );
+        assert_eq!(
+            lookup("10.10.1.2", "local.example.com.").await,
+            (ResponseCode::NoError, vec!["10.10.5.10".to_owned()])
+        );
+        assert_eq!(
+            lookup("10.10.1.2", "passthru.example.com.").await,
+            (ResponseCode::NoError, vec!["10.10.10.5".to_owned()])
+        );
+
+        // Addresses from upstream are checked.
+        assert_eq!(
+            lookup("10.10.1.2", "other.example.com.").await,
+            (ResponseCode::NXDomain, vec![])
+        );
+
+        // As are the targets of CNAMEs.
+        assert_eq!(
+            lookup("10.10.1.2", "alias.home.local.").await,
+            (
+                ResponseCode::NXDomain,
+                vec!["blocked.example.com.".to_owned()]
+            )
+        );
+        assert_eq!(
+            lookup("10.10.1.2", "cdn.home.local.").await,
+            (
+                ResponseCode::NXDomain,
+                vec!["other.example.com.".to_owned()]
+            )
+        );
+
+        assert_eq!(
+            lookup("10.10.1.2", "www.home.local.").await,
+            (ResponseCode::NoError, vec!["10.10.5.3".to_owned()])
+        );
+        assert_eq!(
+            lookup("10.10.1.1", "www.home.local.").await,
+            (ResponseCode::NoError, vec!["10.10.5.99".to_owned()])
+        );
+
+        sources.shutdown().await;
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn wildcards() {
+        let mut records = RecordSet::new();
+        records.insert(Record::new(
+            fqdn("*.dev.home.local."),
+            RData::Cname(fqdn("proxy.home.local.")),
+        ));
+        records.insert(Record::new(
+            fqdn("proxy.home.local."),
+            RData::A("10.10.5.1".parse().unwrap()),
+        ));
+        records.insert(Record::new(
+            fqdn("www.dev.home.local."),
+            RData::A("10.10.5.2".parse().unwrap()),
+        ));
+        records.insert(Record::new(
+            fqdn("a.b.dev.home.local."),
+            RData::A("10.10.5.3".parse().unwrap()),
+        ));
+        records.insert(Record::new(
+            fqdn("*.test.home.local."),
+            RData::A("10.10.5.4".parse().unwrap()),
+        ));
+
+        let (_, receiver) = channel(records.clone());
+        let server_state = ServerState::new(receiver, EmptyZones {}).locked().await;
+
+        let lookup = |name: &str| {
+            let server_state = &server_state;
+            let query = Query::query(self::name(name), RecordType::A);
+
+            async move {
+                let mut query_state = QueryState::new(query, false);
+                server_state.perform_query(&mut query_state).await;
+
+                let mut answers: Vec<String> = query_state
+                    .answers()
+                    .iter()
+                    .map(|record| format!("{} {}", record.name(), record.data().unwrap()))
+                    .collect();
+                answers.sort();
+                answers
+            }
+        };
+
+        assert_eq!(
+            lookup("foo.dev.home.local.").await,
+            vec![
+                "foo.dev.home.local. proxy.home.local.",
+                "proxy.home.local. 10.10.5.1"
+            ]
+        );
+
+        // Wildcards match names at any depth.
+        assert_eq!(
+            lookup("deep.foo.dev.home.local.").await,
+            vec![
+                "deep.foo.dev.home.local. proxy.home.local.",
+                "proxy.home.local. 10.10.5.1"
+            ]
+        );
+
+        // Names that exist take precedence.
+        assert_eq!(
+            lookup("www.dev.home.local.").await,
+            vec!["www.dev.home.local. 10.10.5.2"]
+        );
+
+        // As do names that only exist as the parent of other names.
+        assert!(lookup("b.dev.home.local.").await.is_empty());
+        assert!(lookup("x.b.dev.home.local.").await.is_empty());
+
+        assert_eq!(
+            lookup("foo.test.home.local.").await,
+            vec!["foo.test.home.local. 10.10.5.4"]
+        );
+
+        // Wildcards are not used for reverse lookups.
+        assert!(records
+            .lookup(
+                &name("4.5.10.10.in-addr.arpa."),
+                DNSClass::IN,
+                RecordType::PTR
+            )
+            .next()
+            .is_none());
+    }
+
     #[tracing_test::traced_test]
     #[tokio::test]
     async fn aliases() {
@@ -644,4 +1474,116 @@ other   IN A     10.5.3.2
         assert_eq!(record.record_type(), RecordType::A);
         assert_eq!(*record.data().unwrap(), rdata_a("10.10.10.5"));
     }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn cached_upstream() {
+        let (address, received) = udp_server("10.10.10.5", Duration::ZERO).await;
+        let upstream = Upstream::from(Address {
+            host: Host::from_str("127.0.0.1").unwrap(),
+            port: Some(address.port()),
+        });
+
+        let (_, receiver) = channel(RecordSet::new());
+        let server_state = ServerState::new(receiver, ZoneWithUpstream { upstream });
+
+        for _ in 0..2 {
+            let mut query_state =
+                QueryState::new(Query::query(name("www.example.org."), RecordType::A), true);
+            server_state
+                .locked()
+                .await
+                .perform_query(&mut query_state)
+                .await;
+
+            assert_eq!(query_state.response_code, ResponseCode::NoError);
+            let answers = query_state.answers();
+            assert_eq!(answers.len(), 1);
+            assert_eq!(
+                *answers.first().unwrap().data().unwrap(),
+                rdata_a("10.10.10.5")
+            );
+        }
+
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+
+        // Changing the upstream drops the cached answer.
+        let (address, received) = udp_server("10.10.10.6", Duration::ZERO).await;
+        let upstream = Upstream::from(Address {
+            host: Host::from_str("127.0.0.1").unwrap(),
+            port: Some(address.port()),
+        });
+        server_state
+            .replace_zones(ZoneWithUpstream { upstream })
+            .await;
+
+        let mut query_state =
+            QueryState::new(Query::query(name("www.example.org."), RecordType::A), true);
+        server_state
+            .locked()
+            .await
+            .perform_query(&mut query_state)
+            .await;
+
+        let answers = query_state.answers();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(
+            *answers.first().unwrap().data().unwrap(),
+            rdata_a("10.10.10.6")
+        );
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn serve_stale() {
+        // Nothing is listening on this port once the socket is dropped.
+        let address = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let upstream = Upstream::from(Address {
+            host: Host::from_str("127.0.0.1").unwrap(),
+            port: Some(address.port()),
+        });
+
+        let (_, receiver) = channel(RecordSet::new());
+        let server_state = ServerState::new(receiver, ZoneWithUpstream { upstream });
+        server_state.cache.configure(10, Duration::from_secs(3600));
+
+        let mut message = Message::new();
+        message.add_answer(rr::Record::from_rdata(
+            name("www.example.org."),
+            1,
+            rdata_a("10.10.10.5"),
+        ));
+        server_state.cache.insert(
+            &name("www.example.org."),
+            DNSClass::IN,
+            RecordType::A,
+            &message,
+        );
+
+        sleep(Duration::from_millis(1100)).await;
+
+        for _ in 0..4 {
+            let mut query_state =
+                QueryState::new(Query::query(name("www.example.org."), RecordType::A), true);
+            server_state
+                .locked()
+                .await
+                .perform_query(&mut query_state)
+                .await;
+
+            assert_eq!(query_state.response_code, ResponseCode::NoError);
+            let answers = query_state.answers();
+            assert_eq!(answers.len(), 1);
+            let record = answers.first().unwrap();
+            assert_eq!(*record.data().unwrap(), rdata_a("10.10.10.5"));
+            assert_eq!(record.ttl(), 30);
+        }
+
+        assert!(logs_contain("serving stale answer"));
+    }
 }
@@ -0,0 +1,316 @@
+use std::{collections::HashSet, net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Error};
+use hickory_client::{
+    client::{AsyncClient, ClientHandle},
+    op::ResponseCode,
+    proto::rr::dnssec::tsig::TSigner,
+    rr::{self, DNSClass, RecordType},
+    udp::UdpClientStream,
+};
+use tokio::{
+    net::UdpSocket,
+    task::JoinHandle,
+    time::{timeout, Instant},
+};
+use tracing::instrument;
+
+use crate::{
+    config::ZoneConfigProvider,
+    dns::{Fqdn, ServerState},
+};
+
+const NOTIFY_PORT: u16 = 53;
+/// How long to wait for further changes before notifying secondaries.
+const COALESCE_DELAY: Duration = Duration::from_secs(1);
+/// The longest that notifications will be delayed by a stream of changes.
+const MAX_DELAY: Duration = Duration::from_secs(10);
+const NOTIFY_ATTEMPTS: usize = 3;
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[instrument(level = "debug", name = "notify", fields(%origin, %secondary), skip(soa, signer))]
+async fn send_notify(
+    origin: Fqdn,
+    soa: rr::Record,
+    secondary: SocketAddr,
+    signer: Option<Arc<TSigner>>,
+) {
+    let result: Result<ResponseCode, Error> = async {
+        let stream = UdpClientStream::<UdpSocket, TSigner>::with_timeout_and_signer(
+            secondary,
+            NOTIFY_TIMEOUT,
+            signer,
+        );
+        let (mut client, bg) = AsyncClient::connect(stream).await?;
+        tokio::spawn(bg);
+
+        let mut last_error = None;
+        for _ in 0..NOTIFY_ATTEMPTS {
+            match client
+                .notify(
+                    origin.name(),
+                    DNSClass::IN,
+                    RecordType::SOA,
+                    Some(soa.clone()),
+                )
+                .await
+            {
+                Ok(response) => return Ok(response.response_code()),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(last_error.unwrap().into())
+    }
+    .await;
+
+    match result {
+        Ok(ResponseCode::NoError) => tracing::debug!("Secondary acknowledged notify"),
+        Ok(response_code) => {
+            tracing::warn!(%response_code, "Secondary rejected notify")
+        }
+        Err(e) => tracing::warn!(error = %e, "Failed to notify secondary"),
+    }
+}
+
+/// Sends NOTIFY messages to the secondaries of any authoritative zone whose
+/// records change.
+pub(crate) struct Notifier {
+    handle: JoinHandle<()>,
+}
+
+impl Notifier {
+    pub(crate) fn new<Z>(server_state: ServerState<Z>) -> Self
+    where
+        Z: ZoneConfigProvider + Clone + Send + Sync + 'static,
+    {
+        Self {
+            handle: tokio::spawn(Self::run(server_state)),
+        }
+    }
+
+    async fn run<Z>(server_state: ServerState<Z>)
+    where
+        Z: ZoneConfigProvider + Clone + Send + Sync + 'static,
+    {
+        let mut receiver = server_state.receiver.clone();
+        let mut previous = receiver.borrow_and_update().clone();
+
+        loop {
+            if receiver.changed().await.is_err() {
+                return;
+            }
+
+            // Wait for a quiet period so that a burst of changes only causes a
+            // single notification.
+            let start = Instant::now();
+            while start.elapsed() < MAX_DELAY {
+                match timeout(COALESCE_DELAY, receiver.changed()).await {
+                    Ok(Ok(())) => continue,
+                    Ok(Err(_)) => return,
+                    Err(_) => break,
+                }
+            }
+
+            let current = receiver.borrow_and_update().clone();
+            let changed = previous.changed_names(&current);
+            previous = current;
+
+            let locked = server_state.locked().await;
+
+            let origins: HashSet<Fqdn> = changed
+                .into_iter()
+                .filter_map(|name| locked.zone_config(&Fqdn::from(name)).origin)
+                .collect();
+
+            for origin in origins {
+                let config = locked.zone_config(&origin);
+                if config.notify.is_empty() {
+                    continue;
+                }
+
+                let Some(soa) = config.soa() else {
+                    continue;
+                };
+
+                let signer = match config.notify_key {
+                    Some(ref name) => {
+                        let signer = locked
+                            .zones
+                            .tsig_key(name)
+                            .ok_or_else(|| anyhow!("Unknown TSIG key {name}"))
+                            .and_then(|key| key.signer(name));
+
+                        match signer {
+                            Ok(signer) => Some(Arc::new(signer)),
+                            Err(e) => {
+                                tracing::error!(%origin, error = %e, "Unable to sign notify");
+                                continue;
+                            }
+                        }
+                    }
+                    None => None,
+                };
+
+                tracing::info!(%origin, secondaries = config.notify.len(), "Notifying secondaries of zone change");
+
+                for secondary in &config.notify {
+                    tokio::spawn(send_notify(
+                        origin.clone(),
+                        soa.clone(),
+                        secondary.to_socket_address(NOTIFY_PORT),
+                        signer.clone(),
+                    ));
+                }
+            }
+        }
+    }
+
+    pub(crate) fn shutdown(&self) {
+        self.handle.abort();
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use hickory_client::{
+        op::{Message, MessageType, OpCode},
+        proto::serialize::binary::{BinDecodable, BinEncodable},
+    };
+    use tokio::{
+        net::UdpSocket,
+        sync::{mpsc, watch::channel},
+        time::sleep,
+    };
+
+    use crate::{
+        config::{ZoneConfig, ZoneConfigProvider},
+        dns::{notify::Notifier, Fqdn, RData, Record, RecordSet, ServerState, TsigKey},
+        test::{fqdn, name, timeout},
+        util::Address,
+    };
+
+    #[derive(Clone)]
+    struct NotifyZones {
+        secondary: SocketAddr,
+    }
+
+    impl ZoneConfigProvider for NotifyZones {
+        fn zone_config(&self, name: &Fqdn) -> ZoneConfig {
+            let mut config = ZoneConfig::default();
+
+            for origin in ["home.local.", "signed.local."] {
+                let origin = fqdn(origin);
+                if origin.zone_of(name) {
+                    config.origin = Some(origin);
+                    config.authoritative = true;
+                    config.notify = vec![Address {
+                        host: self.secondary.ip(),
+                        port: Some(self.secondary.port()),
+                    }];
+                }
+            }
+
+            if fqdn("signed.local.").zone_of(name) {
+                config.notify_key = Some(fqdn("notify.key."));
+            }
+
+            config
+        }
+
+        fn tsig_key(&self, name: &Fqdn) -> Option<TsigKey> {
+            if *name == fqdn("notify.key.") {
+                serde_yaml::from_str("secret: c2VjcmV0LWtleS1mb3ItdGVzdGluZw==").ok()
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Acknowledges NOTIFY messages and reports the zone they were for and
+    /// whether they were signed.
+    async fn secondary() -> (SocketAddr, mpsc::UnboundedReceiver<(String, bool)>) {
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = udp.local_addr().unwrap();
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let mut buffer = [0_u8; 512];
+            loop {
+                let (len, source) = udp.recv_from(&mut buffer).await.unwrap();
+                let request = Message::from_bytes(&buffer[..len]).unwrap();
+                assert_eq!(request.op_code(), OpCode::Notify);
+
+                let zone = request.queries().first().unwrap().name().to_string();
+                sender
+                    .send((zone, !request.signature().is_empty()))
+                    .unwrap();
+
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_op_code(OpCode::Notify)
+                    .add_queries(request.queries().to_vec());
+                udp.send_to(&response.to_bytes().unwrap(), source)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        (address, receiver)
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn notify() {
+        let (address, mut notifications) = secondary().await;
+
+        let (sender, receiver) = channel(RecordSet::new());
+        let server_state = ServerState::new(receiver, NotifyZones { secondary: address });
+        let _notifier = Notifier::new(server_state);
+
+        let mut records = RecordSet::new();
+        for i in 0..5 {
+            records.insert(Record::new(
+                fqdn(&format!("host{i}.home.local.")),
+                RData::A(format!("10.10.10.{i}").parse().unwrap()),
+            ));
+            sender.send_replace(records.clone());
+            sleep(Duration::from_millis(50)).await;
+        }
+
+        let (zone, signed) = timeout(notifications.recv()).await.unwrap();
+        assert_eq!(zone, name("home.local.").to_string());
+        assert!(!signed);
+
+        // Changes outside of the zone do not notify.
+        records.insert(Record::new(
+            fqdn("www.example.org."),
+            RData::A("10.10.10.10".parse().unwrap()),
+        ));
+        sender.send_replace(records.clone());
+
+        sleep(Duration::from_millis(1500)).await;
+        assert!(notifications.try_recv().is_err());
+
+        // Zones with a notify key sign their notifications.
+        records.insert(Record::new(
+            fqdn("www.signed.local."),
+            RData::A("10.10.10.11".parse().unwrap()),
+        ));
+        sender.send_replace(records.clone());
+
+        let (zone, signed) = timeout(notifications.recv()).await.unwrap();
+        assert_eq!(zone, name("signed.local.").to_string());
+        assert!(signed);
+    }
+}
@@ -0,0 +1,114 @@
+use std::{collections::HashSet, net::IpAddr};
+
+use hickory_server::proto::{
+    op::ResponseCode,
+    rr::{self, Name, RecordType},
+};
+
+use crate::{
+    config::ZoneConfigProvider,
+    dns::{query::QueryState, LockedServerState},
+    sources::rpz::Policy,
+};
+
+/// The names in the CNAME chain of the answers, starting with the queried
+/// name.
+fn answer_chain(query_state: &QueryState) -> Vec<Name> {
+    let mut chain = vec![query_state.query.name().clone()];
+
+    while let Some(target) = query_state
+        .answers()
+        .iter()
+        .find_map(|record| match record.data() {
+            Some(rr::RData::CNAME(target)) if chain.last() == Some(record.name()) => {
+                Some(target.0.clone())
+            }
+            _ => None,
+        })
+    {
+        if chain.contains(&target) {
+            break;
+        }
+        chain.push(target);
+    }
+
+    chain
+}
+
+impl<Z: ZoneConfigProvider> LockedServerState<Z> {
+    /// Rewrites the response as the first response policy zone with a
+    /// matching trigger says to. This happens once any upstream answers have
+    /// been added so that the CNAME targets and addresses that they lead to
+    /// are checked too.
+    pub(super) async fn apply_policies(&self, query_state: &mut QueryState) {
+        let chain = answer_chain(query_state);
+
+        let addresses: Vec<(usize, IpAddr)> = query_state
+            .answers()
+            .iter()
+            .filter_map(|record| {
+                let index = chain.iter().position(|name| name == record.name())?;
+
+                match record.data()? {
+                    rr::RData::A(a) => Some((index, IpAddr::V4(a.0))),
+                    rr::RData::AAAA(aaaa) => Some((index, IpAddr::V6(aaaa.0))),
+                    _ => None,
+                }
+            })
+            .collect();
+
+        let Some(found) =
+            self.policies
+                .find(self.client.as_ref(), &chain, &addresses, self.view.as_ref())
+        else {
+            return;
+        };
+
+        if found.policy == Policy::Passthru {
+            return;
+        }
+
+        let name = chain[found.index].clone();
+        tracing::debug!(%name, "Rewriting response for response policy");
+
+        // Everything from the matching name onwards is replaced.
+        let rewritten: HashSet<&Name> = chain[found.index..].iter().collect();
+        query_state.remove_records(|record| rewritten.contains(record.name()));
+        query_state.name_servers.clear();
+        query_state.soa = None;
+
+        match found.policy {
+            Policy::NxDomain => {
+                query_state.response_code = ResponseCode::NXDomain;
+                query_state.soa = found.soa;
+            }
+            Policy::NoData => {
+                query_state.response_code = ResponseCode::NoError;
+                query_state.soa = found.soa;
+            }
+            Policy::LocalData(records) => {
+                query_state.response_code = ResponseCode::NoError;
+
+                let query_type = query_state.query_type();
+                let records = records
+                    .into_iter()
+                    .filter(|record| {
+                        query_type == RecordType::ANY
+                            || record.record_type() == query_type
+                            || record.record_type() == RecordType::CNAME
+                    })
+                    .map(|mut record| {
+                        record.set_name(name.clone());
+                        record
+                    })
+                    .collect();
+
+                query_state.add_answers(records);
+
+                // A CNAME in the local data is followed like any other.
+                self.lookup_names(query_state).await;
+            }
+            Policy::Passthru => {}
+        }
+    }
+}
@@ -4,7 +4,7 @@ use std::{
 };
 
 use hickory_server::proto::{
-    op::{Header, Query, ResponseCode},
+    op::{Header, Message, Query, ResponseCode},
     rr::{self, DNSClass, Name, RData, RecordType},
 };
 
@@ -22,6 +22,10 @@ pub(super) struct QueryState {
     unknowns: HashSet<Name>,
 
     pub(super) recursion_available: bool,
+    /// Whether the client may have the queried name looked up upstream.
+    pub(super) recursion_allowed: bool,
+    /// Whether records needed to answer the query were left out.
+    pub(super) truncated: bool,
     pub(super) response_code: ResponseCode,
 
     /// A list of answers to respond with
@@ -45,6 +49,8 @@ impl QueryState {
             resolve_aliases: false,
 
             recursion_available: true,
+            recursion_allowed: true,
+            truncated: false,
             response_code: ResponseCode::NXDomain,
 
             answers: Vec::new(),
@@ -70,6 +76,8 @@ impl QueryState {
             resolve_aliases: true,
 
             recursion_available: true,
+            recursion_allowed: true,
+            truncated: false,
             response_code: ResponseCode::NXDomain,
 
             answers: self.answers.clone(),
@@ -146,10 +154,70 @@ impl QueryState {
         self.answers.extend(records);
     }
 
+    /// Adds signatures for the answers.
+    pub(super) fn add_signatures(&mut self, records: Vec<rr::Record>) {
+        self.answers.extend(records);
+    }
+
     pub(super) fn add_additionals(&mut self, records: Vec<rr::Record>) {
         self.additionals.extend(records);
     }
 
+    /// Moves the matching answers to the start of the additional section,
+    /// returning how many were moved.
+    pub(super) fn move_to_additionals<F>(&mut self, filter: F) -> usize
+    where
+        F: Fn(&rr::Record) -> bool,
+    {
+        let (moved, answers): (Vec<rr::Record>, Vec<rr::Record>) =
+            self.answers.drain(..).partition(filter);
+        let count = moved.len();
+        self.answers = answers;
+        self.additionals.splice(0..0, moved);
+        count
+    }
+
+    /// Removes the matching answers and additional records.
+    pub(super) fn remove_records<F>(&mut self, filter: F)
+    where
+        F: Fn(&rr::Record) -> bool,
+    {
+        self.answers.retain(|record| !filter(record));
+        self.additionals.retain(|record| !filter(record));
+    }
+
+    /// Removes the last additional record.
+    pub(super) fn pop_additional(&mut self) -> Option<rr::Record> {
+        self.additionals.pop()
+    }
+
+    /// Adds the records from an upstream response for the given name.
+    pub(super) fn add_response(&mut self, name: &Name, mut message: Message) {
+        // The upstream may know that the name exists even without answers.
+        if message.response_code() == ResponseCode::NoError {
+            self.response_code = ResponseCode::NoError;
+        }
+
+        self.add_answers(message.take_answers());
+        self.add_additionals(message.take_additionals());
+
+        if name == self.query.name() {
+            let mut name_servers: Vec<rr::Record> = Vec::new();
+            let mut soa: Option<rr::Record> = None;
+
+            for record in message.take_name_servers() {
+                if record.record_type() == RecordType::SOA {
+                    soa.replace(record);
+                } else {
+                    name_servers.push(record);
+                }
+            }
+
+            self.name_servers.extend(name_servers);
+            self.soa = soa;
+        }
+    }
+
     pub(super) fn next_unknown(&mut self) -> Option<Name> {
         let next = self.unknowns.iter().next()?.clone();
         self.unknowns.remove(&next);
@@ -159,7 +227,8 @@ impl QueryState {
     pub(super) fn header(&self, request_header: &Header) -> Header {
         let mut response_header = Header::response_from_request(request_header);
         response_header.set_authoritative(self.soa.is_some());
-        response_header.set_recursion_available(self.recursion_available);
+        response_header.set_recursion_available(self.recursion_available && self.recursion_allowed);
+        response_header.set_truncated(self.truncated);
         response_header.set_response_code(self.response_code);
         response_header
     }
@@ -0,0 +1,332 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+use hickory_server::proto::{op::ResponseCode, rr::Name};
+use serde::{Deserialize, Serialize};
+
+use crate::util::Network;
+
+/// Above this many tracked clients and names the least recently used are
+/// forgotten.
+const MAX_BUCKETS: usize = 10000;
+
+fn default_slip() -> u32 {
+    2
+}
+
+fn default_ipv4_prefix() -> u8 {
+    24
+}
+
+fn default_ipv6_prefix() -> u8 {
+    56
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub(crate) struct RateLimitConfig {
+    /// How many identical responses a client network may receive each second.
+    responses_per_second: u32,
+
+    /// Every this many limited responses one is sent truncated rather than
+    /// dropped so that real clients retry over TCP. 0 drops every response.
+    #[serde(default = "default_slip")]
+    slip: u32,
+
+    #[serde(default = "default_ipv4_prefix")]
+    ipv4_prefix: u8,
+
+    #[serde(default = "default_ipv6_prefix")]
+    ipv6_prefix: u8,
+}
+
+/// What to do with a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RateLimitAction {
+    Send,
+    /// Send an empty truncated response instead.
+    Slip,
+    Drop,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct RateLimitCounters {
+    pub(crate) dropped: u64,
+    pub(crate) slipped: u64,
+}
+
+/// What a response is counted against.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum RateLimitKey {
+    /// Answers for a name or negative answers for a zone.
+    Name(Name),
+    /// Other responses without answers are counted together by their response
+    /// code.
+    Error(ResponseCode),
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    limited: u32,
+    /// The position of the bucket in the least recently used order.
+    used: u64,
+}
+
+/// The tracked buckets along with the order that they were last used in so
+/// that the least recently used can be evicted in logarithmic time.
+#[derive(Debug, Default)]
+struct Buckets {
+    buckets: HashMap<(Network, RateLimitKey), Bucket>,
+    recent: BTreeMap<u64, (Network, RateLimitKey)>,
+    next_use: u64,
+}
+
+impl Buckets {
+    fn clear(&mut self) {
+        self.buckets.clear();
+        self.recent.clear();
+    }
+
+    /// Finds the bucket for the key, creating one with a full allowance if
+    /// necessary, and marks it as the most recently used.
+    fn touch(&mut self, key: (Network, RateLimitKey), rate: f64, now: Instant) -> &mut Bucket {
+        let used = self.next_use;
+        self.next_use += 1;
+
+        if let Some(bucket) = self.buckets.get(&key) {
+            self.recent.remove(&bucket.used);
+        } else if self.buckets.len() >= MAX_BUCKETS {
+            if let Some((_, oldest)) = self.recent.pop_first() {
+                self.buckets.remove(&oldest);
+            }
+        }
+
+        self.recent.insert(used, key.clone());
+
+        let bucket = self.buckets.entry(key).or_insert_with(|| Bucket {
+            tokens: rate,
+            updated: now,
+            limited: 0,
+            used,
+        });
+        bucket.used = used;
+        bucket
+    }
+}
+
+/// Response rate limiting (RRL) for UDP responses, which could otherwise be
+/// used to reflect traffic at a spoofed address. Responses are counted by the
+/// client's network and the name that they are for.
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    config: Mutex<Option<RateLimitConfig>>,
+    buckets: Mutex<Buckets>,
+    dropped: AtomicU64,
+    slipped: AtomicU64,
+}
+
+impl RateLimiter {
+    pub(crate) fn configure(&self, config: Option<RateLimitConfig>) {
+        let mut current = self.config.lock().unwrap();
+        if *current != config {
+            self.buckets.lock().unwrap().clear();
+            *current = config;
+        }
+    }
+
+    pub(crate) fn counters(&self) -> RateLimitCounters {
+        RateLimitCounters {
+            dropped: self.dropped.load(Ordering::Relaxed),
+            slipped: self.slipped.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn check(&self, client: &IpAddr, key: &RateLimitKey) -> RateLimitAction {
+        let Some(config) = self.config.lock().unwrap().clone() else {
+            return RateLimitAction::Send;
+        };
+
+        let network = match client.to_canonical() {
+            IpAddr::V4(_) => Network::containing(client, config.ipv4_prefix),
+            IpAddr::V6(_) => Network::containing(client, config.ipv6_prefix),
+        };
+
+        let rate = f64::from(config.responses_per_second);
+        let now = Instant::now();
+
+        let key = match key {
+            RateLimitKey::Name(name) => RateLimitKey::Name(name.to_lowercase()),
+            RateLimitKey::Error(response_code) => RateLimitKey::Error(*response_code),
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.touch((network, key), rate, now);
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.limited = 0;
+            return RateLimitAction::Send;
+        }
+
+        bucket.limited += 1;
+        if config.slip > 0 && bucket.limited.is_multiple_of(config.slip) {
+            self.slipped.fetch_add(1, Ordering::Relaxed);
+            RateLimitAction::Slip
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            RateLimitAction::Drop
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use hickory_server::proto::op::ResponseCode;
+
+    use crate::{
+        dns::rate_limit::{
+            RateLimitAction, RateLimitConfig, RateLimitCounters, RateLimitKey, RateLimiter,
+            MAX_BUCKETS,
+        },
+        test::name,
+    };
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn key(host: &str) -> RateLimitKey {
+        RateLimitKey::Name(name(host))
+    }
+
+    #[test]
+    fn rate_limit() {
+        let limiter = RateLimiter::default();
+
+        // Nothing is limited until configured.
+        for _ in 0..10 {
+            assert_eq!(
+                limiter.check(&ip("10.10.1.1"), &key("www.home.local.")),
+                RateLimitAction::Send
+            );
+        }
+
+        limiter.configure(Some(RateLimitConfig {
+            responses_per_second: 3,
+            slip: 2,
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+        }));
+
+        for _ in 0..3 {
+            assert_eq!(
+                limiter.check(&ip("10.10.1.1"), &key("www.home.local.")),
+                RateLimitAction::Send
+            );
+        }
+
+        // The same network and name share a limit.
+        assert_eq!(
+            limiter.check(&ip("10.10.1.2"), &key("WWW.home.local.")),
+            RateLimitAction::Drop
+        );
+        assert_eq!(
+            limiter.check(&ip("10.10.1.1"), &key("www.home.local.")),
+            RateLimitAction::Slip
+        );
+        assert_eq!(
+            limiter.check(&ip("10.10.1.1"), &key("www.home.local.")),
+            RateLimitAction::Drop
+        );
+
+        // Other networks and names are unaffected.
+        assert_eq!(
+            limiter.check(&ip("10.10.2.1"), &key("www.home.local.")),
+            RateLimitAction::Send
+        );
+        assert_eq!(
+            limiter.check(&ip("10.10.1.1"), &key("other.home.local.")),
+            RateLimitAction::Send
+        );
+
+        assert_eq!(
+            limiter.counters(),
+            RateLimitCounters {
+                dropped: 2,
+                slipped: 1,
+            }
+        );
+
+        limiter.configure(Some(RateLimitConfig {
+            responses_per_second: 1,
+            slip: 0,
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+        }));
+
+        assert_eq!(
+            limiter.check(&ip("fd00::1"), &key("www.home.local.")),
+            RateLimitAction::Send
+        );
+        for _ in 0..3 {
+            assert_eq!(
+                limiter.check(&ip("fd00::ff:1"), &key("www.home.local.")),
+                RateLimitAction::Drop
+            );
+        }
+    }
+
+    #[test]
+    fn eviction() {
+        let limiter = RateLimiter::default();
+        limiter.configure(Some(RateLimitConfig {
+            responses_per_second: 1,
+            slip: 0,
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+        }));
+
+        let client = ip("10.10.1.1");
+        let error = RateLimitKey::Error(ResponseCode::Refused);
+
+        assert_eq!(limiter.check(&client, &error), RateLimitAction::Send);
+        assert_eq!(
+            limiter.check(&client, &key("first.home.local.")),
+            RateLimitAction::Send
+        );
+
+        // Keep the error bucket recently used while filling past the cap.
+        for i in 0..MAX_BUCKETS {
+            if i == MAX_BUCKETS / 2 {
+                assert_eq!(limiter.check(&client, &error), RateLimitAction::Drop);
+            }
+
+            limiter.check(&client, &key(&format!("host{i}.home.local.")));
+        }
+
+        assert_eq!(limiter.buckets.lock().unwrap().buckets.len(), MAX_BUCKETS);
+        assert_eq!(limiter.buckets.lock().unwrap().recent.len(), MAX_BUCKETS);
+
+        // The least recently used bucket was forgotten and starts again with a
+        // full allowance.
+        assert_eq!(
+            limiter.check(&client, &key("first.home.local.")),
+            RateLimitAction::Send
+        );
+        assert_eq!(limiter.check(&client, &error), RateLimitAction::Drop);
+    }
+}
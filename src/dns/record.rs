@@ -8,14 +8,60 @@ use std::{
     str::FromStr,
 };
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 use hickory_server::proto::{
     error::ProtoError,
-    rr::{self, rdata, DNSClass, IntoName, Name, RecordType},
+    rr::{
+        self,
+        rdata::{self, caa},
+        DNSClass, IntoName, Name, RecordType,
+    },
 };
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
-use crate::config::ZoneConfig;
+use crate::{config::ZoneConfig, sources::SourceType};
+
+/// The most bytes that a single TXT string may hold.
+const MAX_TXT_STRING: usize = 255;
+
+/// Splits text into strings short enough for a TXT record without breaking up
+/// any characters.
+fn split_txt(text: &str) -> Vec<String> {
+    let mut strings = Vec::new();
+    let mut current = String::new();
+
+    for ch in text.chars() {
+        if current.len() + ch.len_utf8() > MAX_TXT_STRING {
+            strings.push(std::mem::take(&mut current));
+        }
+        current.push(ch);
+    }
+
+    if !current.is_empty() || strings.is_empty() {
+        strings.push(current);
+    }
+
+    strings
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TxtOneOrMany {
+    List(Vec<String>),
+    Text(String),
+}
+
+fn deserialize_txt<'de, D>(de: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let strings = match TxtOneOrMany::deserialize(de)? {
+        TxtOneOrMany::List(strings) => strings,
+        TxtOneOrMany::Text(text) => vec![text],
+    };
+
+    Ok(strings.iter().flat_map(|text| split_txt(text)).collect())
+}
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
 #[serde(tag = "type", content = "value", rename_all = "UPPERCASE")]
@@ -25,6 +71,27 @@ pub(crate) enum RData {
     Cname(Fqdn),
     Aname(Fqdn),
     Ptr(Fqdn),
+    /// The strings of the record. A single string may be given instead of a
+    /// list and strings longer than 255 bytes are split into several.
+    Txt(#[serde(deserialize_with = "deserialize_txt")] Vec<String>),
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: Fqdn,
+    },
+    Mx {
+        preference: u16,
+        exchange: Fqdn,
+    },
+    Ns(Fqdn),
+    /// Only the `issue`, `issuewild` and `iodef` properties are supported.
+    Caa {
+        #[serde(default)]
+        critical: bool,
+        tag: String,
+        value: String,
+    },
 }
 
 impl RData {
@@ -38,10 +105,61 @@ impl RData {
             RData::A(_) => record_type == RecordType::A,
             RData::Aaaa(_) => record_type == RecordType::AAAA,
             RData::Ptr(_) => record_type == RecordType::PTR,
+            RData::Txt(_) => record_type == RecordType::TXT,
+            RData::Srv { .. } => record_type == RecordType::SRV,
+            RData::Mx { .. } => record_type == RecordType::MX,
+            RData::Ns(_) => record_type == RecordType::NS,
+            RData::Caa { .. } => record_type == RecordType::CAA,
+        }
+    }
+
+    pub(crate) fn record_type(&self) -> RecordType {
+        match self {
+            RData::A(_) => RecordType::A,
+            RData::Aaaa(_) => RecordType::AAAA,
+            RData::Cname(_) => RecordType::CNAME,
+            RData::Aname(_) => RecordType::ANAME,
+            RData::Ptr(_) => RecordType::PTR,
+            RData::Txt(_) => RecordType::TXT,
+            RData::Srv { .. } => RecordType::SRV,
+            RData::Mx { .. } => RecordType::MX,
+            RData::Ns(_) => RecordType::NS,
+            RData::Caa { .. } => RecordType::CAA,
         }
     }
 }
 
+fn caa_rdata(critical: bool, tag: &str, value: &str) -> Result<rdata::CAA, Error> {
+    match tag {
+        "issue" => {
+            let (name, options) = caa::read_issuer(value.as_bytes())?;
+            Ok(rdata::CAA::new_issue(critical, name, options))
+        }
+        "issuewild" => {
+            let (name, options) = caa::read_issuer(value.as_bytes())?;
+            Ok(rdata::CAA::new_issuewild(critical, name, options))
+        }
+        "iodef" => Ok(rdata::CAA::new_iodef(
+            critical,
+            caa::read_iodef(value.as_bytes())?,
+        )),
+        _ => Err(anyhow!("Unsupported CAA property {tag}")),
+    }
+}
+
+fn caa_value(value: &caa::Value) -> String {
+    match value {
+        caa::Value::Issuer(name, options) => {
+            let mut parts: Vec<String> = Vec::new();
+            parts.push(name.as_ref().map(|n| n.to_string()).unwrap_or_default());
+            parts.extend(options.iter().map(|option| option.to_string()));
+            parts.join("; ")
+        }
+        caa::Value::Url(url) => url.to_string(),
+        caa::Value::Unknown(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
 impl TryInto<rr::RData> for RData {
     type Error = Error;
 
@@ -52,6 +170,66 @@ impl TryInto<rr::RData> for RData {
             RData::Cname(name) => Ok(rr::RData::CNAME(rdata::CNAME(name.into()))),
             RData::Ptr(name) => Ok(rr::RData::PTR(rdata::PTR(name.into()))),
             RData::Aname(name) => Ok(rr::RData::ANAME(rdata::ANAME(name.into()))),
+            RData::Txt(strings) => Ok(rr::RData::TXT(rdata::TXT::new(
+                strings.iter().flat_map(|text| split_txt(text)).collect(),
+            ))),
+            RData::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => Ok(rr::RData::SRV(rdata::SRV::new(
+                priority,
+                weight,
+                port,
+                target.into(),
+            ))),
+            RData::Mx {
+                preference,
+                exchange,
+            } => Ok(rr::RData::MX(rdata::MX::new(preference, exchange.into()))),
+            RData::Ns(name) => Ok(rr::RData::NS(rdata::NS(name.into()))),
+            RData::Caa {
+                critical,
+                tag,
+                value,
+            } => Ok(rr::RData::CAA(caa_rdata(critical, &tag, &value)?)),
+        }
+    }
+}
+
+impl TryFrom<&rr::RData> for RData {
+    type Error = Error;
+
+    fn try_from(rdata: &rr::RData) -> Result<Self, Self::Error> {
+        match rdata {
+            rr::RData::A(ip) => Ok(RData::A(ip.0)),
+            rr::RData::AAAA(ip) => Ok(RData::Aaaa(ip.0)),
+            rr::RData::CNAME(name) => Ok(RData::Cname(name.0.clone().into())),
+            rr::RData::PTR(name) => Ok(RData::Ptr(name.0.clone().into())),
+            rr::RData::ANAME(name) => Ok(RData::Aname(name.0.clone().into())),
+            rr::RData::TXT(txt) => Ok(RData::Txt(
+                txt.iter()
+                    .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+                    .collect(),
+            )),
+            rr::RData::SRV(srv) => Ok(RData::Srv {
+                priority: srv.priority(),
+                weight: srv.weight(),
+                port: srv.port(),
+                target: srv.target().clone().into(),
+            }),
+            rr::RData::MX(mx) => Ok(RData::Mx {
+                preference: mx.preference(),
+                exchange: mx.exchange().clone().into(),
+            }),
+            rr::RData::NS(name) => Ok(RData::Ns(name.0.clone().into())),
+            rr::RData::CAA(caa) if !caa.tag().is_unknown() => Ok(RData::Caa {
+                critical: caa.issuer_critical(),
+                tag: caa.tag().as_str().to_owned(),
+                value: caa_value(caa.value()),
+            }),
+            _ => Err(anyhow!("Unsupported record type {}", rdata.record_type())),
         }
     }
 }
@@ -223,27 +401,77 @@ impl Record {
     }
 }
 
+/// How to choose the names to answer a reverse lookup with when several names
+/// share an address.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum PtrNames {
+    /// Answer with every name.
+    #[default]
+    All,
+    /// Answer with the shortest name.
+    Shortest,
+    /// Answer with the names from the first of these types of source that
+    /// provided any, or every name if none did.
+    Prefer(Vec<SourceType>),
+}
+
+/// A name that shares its address with other names.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct ConflictName {
+    pub(crate) name: Fqdn,
+    pub(crate) sources: Vec<SourceType>,
+}
+
+/// An address that more than one name resolves to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct PtrConflict {
+    pub(crate) address: IpAddr,
+    pub(crate) names: Vec<ConflictName>,
+}
+
+fn ptr_target(record: &Record) -> Option<&Fqdn> {
+    match record.rdata() {
+        RData::Ptr(target) => Some(target),
+        _ => None,
+    }
+}
+
+/// Adds a name and all of its ancestors to a set of names.
+fn add_names(names: &mut HashSet<Name>, mut name: Name) {
+    while !name.is_root() {
+        let parent = name.base_name();
+        names.insert(name);
+        name = parent;
+    }
+}
+
 #[derive(Default, PartialEq, Eq, Clone, Deserialize, Serialize)]
 #[serde(from = "Vec<Record>")]
 #[serde(into = "Vec<Record>")]
 pub(crate) struct RecordSet {
     records: HashMap<Fqdn, HashSet<Record>>,
-    reverse: HashMap<IpAddr, Record>,
+    reverse: HashMap<IpAddr, Vec<Record>>,
     names: HashSet<Name>,
+    /// The types of source that provided the records for each name.
+    sources: HashMap<Fqdn, HashSet<SourceType>>,
 }
 
 impl fmt::Debug for RecordSet {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let records: Vec<&Record> = self.records().collect();
-        let reverse: HashMap<&IpAddr, String> = self
+        let reverse: HashMap<&IpAddr, Vec<String>> = self
             .reverse
             .iter()
-            .map(|(ip, record)| {
-                if let RData::Ptr(p) = &record.rdata {
-                    (ip, p.to_string())
-                } else {
-                    unreachable!()
-                }
+            .map(|(ip, records)| {
+                (
+                    ip,
+                    records
+                        .iter()
+                        .filter_map(ptr_target)
+                        .map(|target| target.to_string())
+                        .collect(),
+                )
             })
             .collect();
 
@@ -271,13 +499,7 @@ impl RecordSet {
     pub(crate) fn contains_reverse<I: Into<IpAddr>>(&self, ip: I, name: &Fqdn) -> bool {
         self.reverse
             .get(&ip.into())
-            .map(|r| {
-                if let RData::Ptr(p) = &r.rdata {
-                    p == name
-                } else {
-                    false
-                }
-            })
+            .map(|records| records.iter().any(|r| ptr_target(r) == Some(name)))
             .unwrap_or_default()
     }
 
@@ -290,36 +512,151 @@ impl RecordSet {
         self.records.values().flatten()
     }
 
+    /// Lists the names whose records differ between the two sets, including
+    /// reverse lookup names.
+    pub(crate) fn changed_names(&self, other: &RecordSet) -> HashSet<Name> {
+        let mut changed = HashSet::new();
+
+        for (name, records) in &self.records {
+            if other.records.get(name) != Some(records) {
+                changed.insert(name.name());
+            }
+        }
+
+        for (name, records) in &other.records {
+            if self.records.get(name) != Some(records) {
+                changed.insert(name.name());
+            }
+        }
+
+        for (ip, records) in &self.reverse {
+            if other.reverse.get(ip) != Some(records) {
+                changed.insert(Name::from(*ip));
+            }
+        }
+
+        for (ip, records) in &other.reverse {
+            if self.reverse.get(ip) != Some(records) {
+                changed.insert(Name::from(*ip));
+            }
+        }
+
+        changed
+    }
+
+    /// The PTR records generated for the addresses in this set.
+    pub(crate) fn reverse_records<'a>(
+        &'a self,
+        ptr_names: &'a PtrNames,
+    ) -> impl Iterator<Item = Record> + 'a {
+        self.reverse
+            .values()
+            .flat_map(|records| self.choose_ptrs(records.clone(), ptr_names))
+    }
+
+    /// The types of source that provided a PTR record or its target.
+    fn ptr_sources(&self, record: &Record) -> HashSet<SourceType> {
+        once(record.name())
+            .chain(ptr_target(record))
+            .filter_map(|name| self.sources.get(name))
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    /// Chooses which of the PTR records for an address to answer with.
+    pub(crate) fn choose_ptrs(&self, records: Vec<Record>, ptr_names: &PtrNames) -> Vec<Record> {
+        let mut unique: Vec<Record> = Vec::new();
+        for record in records {
+            if !unique.iter().any(|r| r.rdata == record.rdata) {
+                unique.push(record);
+            }
+        }
+
+        match ptr_names {
+            PtrNames::All => unique,
+            PtrNames::Shortest => unique
+                .into_iter()
+                .min_by_key(|record| {
+                    let target = ptr_target(record).map(|target| target.to_string());
+                    (target.as_ref().map(|t| t.len()), target)
+                })
+                .into_iter()
+                .collect(),
+            PtrNames::Prefer(source_types) => {
+                for source_type in source_types {
+                    let preferred: Vec<Record> = unique
+                        .iter()
+                        .filter(|record| self.ptr_sources(record).contains(source_type))
+                        .cloned()
+                        .collect();
+
+                    if !preferred.is_empty() {
+                        return preferred;
+                    }
+                }
+
+                unique
+            }
+        }
+    }
+
+    /// Lists the addresses that more than one name resolves to.
+    pub(crate) fn ptr_conflicts(&self) -> Vec<PtrConflict> {
+        let mut conflicts: Vec<PtrConflict> = self
+            .reverse
+            .iter()
+            .filter(|(_, records)| records.len() > 1)
+            .map(|(ip, records)| PtrConflict {
+                address: *ip,
+                names: records
+                    .iter()
+                    .filter_map(|record| {
+                        let mut sources: Vec<SourceType> =
+                            self.ptr_sources(record).into_iter().collect();
+                        sources.sort_by_key(|source| source.to_string());
+
+                        Some(ConflictName {
+                            name: ptr_target(record)?.clone(),
+                            sources,
+                        })
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        conflicts.sort_by_key(|conflict| conflict.address);
+        conflicts
+    }
+
     fn apply_records<T>(&mut self, fqdn: &Fqdn, records: T)
     where
         T: Iterator<Item = Record>,
     {
-        let mut name = fqdn.name();
-        self.names.insert(name.clone());
-        while name.num_labels() > 1 {
-            name = name.trim_to(name.num_labels() as usize - 1);
-            self.names.insert(fqdn.name());
-        }
+        add_names(&mut self.names, fqdn.name());
 
         let inner = self.records.entry(fqdn.clone()).or_default();
         for record in records {
             assert_eq!(record.name(), fqdn);
 
             if !inner.contains(&record) {
-                match record.rdata() {
-                    RData::A(ip) => {
-                        let mut ptr =
-                            Record::new(Name::from(*ip).into(), RData::Ptr(record.name().clone()));
-                        ptr.ttl = record.ttl;
-                        self.reverse.insert(IpAddr::from(*ip), ptr);
-                    }
-                    RData::Aaaa(ip) => {
-                        let mut ptr =
-                            Record::new(Name::from(*ip).into(), RData::Ptr(record.name().clone()));
-                        ptr.ttl = record.ttl;
-                        self.reverse.insert(IpAddr::from(*ip), ptr);
+                let ip = match record.rdata() {
+                    RData::A(ip) => Some(IpAddr::from(*ip)),
+                    RData::Aaaa(ip) => Some(IpAddr::from(*ip)),
+                    _ => None,
+                };
+
+                // A wildcard is not a name that an address should resolve to.
+                if let Some(ip) = ip.filter(|_| !fqdn.is_wildcard()) {
+                    let mut ptr = Record::new(Name::from(ip).into(), RData::Ptr(fqdn.clone()));
+                    ptr.ttl = record.ttl;
+                    add_names(&mut self.names, ptr.name().name());
+
+                    let ptrs = self.reverse.entry(ip).or_default();
+                    if !ptrs.iter().any(|r| r.rdata == ptr.rdata) {
+                        ptrs.push(ptr);
+                        ptrs.sort_by(|a, b| ptr_target(a).cmp(&ptr_target(b)));
                     }
-                    _ => {}
                 }
 
                 inner.insert(record);
@@ -328,11 +665,27 @@ impl RecordSet {
     }
 
     pub(crate) fn append(&mut self, records: RecordSet) {
+        for (name, source_types) in records.sources {
+            self.sources.entry(name).or_default().extend(source_types);
+        }
+
         for (name, records) in records.records {
             self.apply_records(&name, records.into_iter());
         }
     }
 
+    /// Adds the records provided by a type of source.
+    pub(crate) fn append_source(&mut self, records: RecordSet, source_type: SourceType) {
+        for name in records.records.keys() {
+            self.sources
+                .entry(name.clone())
+                .or_default()
+                .insert(source_type);
+        }
+
+        self.append(records);
+    }
+
     pub(crate) fn insert(&mut self, record: Record) {
         self.apply_records(&record.name().clone(), once(record));
     }
@@ -360,6 +713,88 @@ impl RecordSet {
         true
     }
 
+    /// The types of the records that exist for a name.
+    pub(crate) fn record_types(&self, name: &Name) -> Vec<RecordType> {
+        let mut types = Vec::new();
+
+        for record in self.name_records(name) {
+            let record_types = match record.rdata() {
+                RData::Aname(_) => vec![RecordType::A, RecordType::AAAA],
+                rdata => vec![rdata.record_type()],
+            };
+
+            for record_type in record_types {
+                if !types.contains(&record_type) {
+                    types.push(record_type);
+                }
+            }
+        }
+
+        if self
+            .lookup(name, DNSClass::IN, RecordType::PTR)
+            .next()
+            .is_some()
+            && !types.contains(&RecordType::PTR)
+        {
+            types.push(RecordType::PTR);
+        }
+
+        types
+    }
+
+    /// Whether a name exists, either with records of its own or as an
+    /// ancestor of a name with records.
+    pub(crate) fn name_exists(&self, name: &Name) -> bool {
+        self.names.contains(name)
+    }
+
+    /// The wildcard name that would apply to a name that does not exist, the
+    /// wildcard child of its closest existing ancestor (RFC 4592).
+    fn wildcard(&self, name: &Name) -> Option<Fqdn> {
+        let mut encloser = name.base_name();
+        while !self.names.contains(&encloser) {
+            if encloser.is_root() {
+                return None;
+            }
+            encloser = encloser.base_name();
+        }
+
+        Fqdn::from(encloser).child("*").ok()
+    }
+
+    /// The records for a name. Names that don't exist get copies of the
+    /// records of any matching wildcard.
+    fn name_records(&self, name: &Name) -> Box<dyn Iterator<Item = Record> + '_> {
+        let fqdn = Fqdn::from(name.clone());
+
+        if let Some(records) = self.records.get(&fqdn) {
+            return Box::new(records.iter().cloned());
+        }
+
+        if self.names.contains(name) {
+            return Box::new(empty());
+        }
+
+        match self
+            .wildcard(name)
+            .and_then(|wildcard| self.records.get(&wildcard))
+        {
+            Some(records) => Box::new(records.iter().filter_map(move |record| {
+                // A wildcard CNAME would point the name at itself.
+                if record.rdata == RData::Cname(fqdn.clone()) {
+                    return None;
+                }
+
+                Some(Record {
+                    name: fqdn.clone(),
+                    ttl: record.ttl,
+                    rdata: record.rdata.clone(),
+                })
+            })),
+            None => Box::new(empty()),
+        }
+    }
+
     pub(crate) fn lookup(
         &self,
         name: &Name,
@@ -375,18 +810,18 @@ impl RecordSet {
                 name.parse_arpa_name()
                     .ok()
                     .and_then(|net| self.reverse.get(&net.addr()))
+                    .into_iter()
+                    .flatten()
                     .cloned()
-                    .into_iter(),
+                    .chain(
+                        self.name_records(name)
+                            .filter(|record| record.rdata().matches(RecordType::PTR)),
+                    ),
+            ),
+            _ => Box::new(
+                self.name_records(name)
+                    .filter(move |record| record.rdata().matches(query_type)),
             ),
-            _ => match self.records.get(&name.clone().into()) {
-                Some(records) => Box::new(
-                    records
-                        .iter()
-                        .filter(move |record| record.rdata().matches(query_type))
-                        .cloned(),
-                ),
-                None => Box::new(empty()),
-            },
         }
     }
 }
@@ -447,7 +882,15 @@ impl From<RecordSet> for Vec<Record> {
 
 #[cfg(test)]
 mod tests {
-    use crate::dns::Fqdn;
+    use std::net::IpAddr;
+
+    use hickory_server::proto::rr::{self, DNSClass, RecordType};
+
+    use crate::{
+        dns::{record::PtrNames, Fqdn, RData, Record, RecordSet},
+        sources::SourceType,
+        test,
+    };
 
     #[tracing_test::traced_test]
     #[test]
@@ -457,4 +900,153 @@ mod tests {
             Fqdn::try_from("test.example.com").unwrap()
         );
     }
+
+    #[tracing_test::traced_test]
+    #[test]
+    fn rdata() {
+        let long_text = "a".repeat(300);
+
+        for rdata in [
+            RData::Txt(vec!["v=spf1 mx -all".to_string()]),
+            RData::Txt(vec!["a".repeat(255), "a".repeat(45)]),
+            RData::Txt(vec![
+                "v=DKIM1; k=rsa; ".to_string(),
+                "p=MIGfMA0".to_string(),
+            ]),
+            RData::Srv {
+                priority: 10,
+                weight: 5,
+                port: 8080,
+                target: test::fqdn("www.home.local"),
+            },
+            RData::Mx {
+                preference: 10,
+                exchange: test::fqdn("mail.home.local"),
+            },
+            RData::Ns(test::fqdn("ns.home.local")),
+            RData::Caa {
+                critical: false,
+                tag: "issue".to_string(),
+                value: "letsencrypt.org; validationmethods=dns-01".to_string(),
+            },
+            RData::Caa {
+                critical: true,
+                tag: "iodef".to_string(),
+                value: "mailto:security@home.local".to_string(),
+            },
+        ] {
+            let raw: rr::RData = rdata.clone().try_into().unwrap();
+            assert_eq!(raw.record_type(), rdata.record_type());
+            assert_eq!(RData::try_from(&raw).unwrap(), rdata);
+
+            let yaml = serde_yaml::to_string(&rdata).unwrap();
+            assert_eq!(serde_yaml::from_str::<RData>(&yaml).unwrap(), rdata);
+        }
+
+        let raw: rr::RData = RData::Txt(vec![long_text]).try_into().unwrap();
+        let rr::RData::TXT(txt) = raw else {
+            panic!("Expected a TXT record");
+        };
+        assert_eq!(txt.txt_data().len(), 2);
+
+        // Long text is only split between characters. Here a two byte
+        // character starts at byte 254 so covers byte 255.
+        let text = format!("{}é{}", "a".repeat(254), "b".repeat(20));
+        let rdata: RData = serde_yaml::from_str(&format!("type: TXT\nvalue: {text}\n")).unwrap();
+        let RData::Txt(ref strings) = rdata else {
+            panic!("Expected a TXT record");
+        };
+        assert_eq!(strings.len(), 2);
+        assert_eq!(strings[0], "a".repeat(254));
+        assert_eq!(strings.concat(), text);
+
+        let raw: rr::RData = rdata.clone().try_into().unwrap();
+        assert_eq!(RData::try_from(&raw).unwrap(), rdata);
+
+        let rdata: RData = serde_yaml::from_str("type: TXT\nvalue: [one, two]\n").unwrap();
+        assert_eq!(
+            rdata,
+            RData::Txt(vec!["one".to_string(), "two".to_string()])
+        );
+
+        let caa = RData::Caa {
+            critical: false,
+            tag: "unknown".to_string(),
+            value: "nothing".to_string(),
+        };
+        assert!(TryInto::<rr::RData>::try_into(caa).is_err());
+    }
+
+    #[tracing_test::traced_test]
+    #[test]
+    fn ptr_names() {
+        let mut records = RecordSet::new();
+
+        let mut docker = RecordSet::new();
+        docker.insert(Record::new(
+            test::fqdn("www.home.local"),
+            RData::A("10.10.4.5".parse().unwrap()),
+        ));
+        records.append_source(docker, SourceType::Docker);
+
+        let mut dhcp = RecordSet::new();
+        dhcp.insert(Record::new(
+            test::fqdn("laptop.home.local"),
+            RData::A("10.10.4.5".parse().unwrap()),
+        ));
+        dhcp.insert(Record::new(
+            test::fqdn("www.home.local"),
+            RData::A("10.10.4.5".parse().unwrap()),
+        ));
+        records.append_source(dhcp, SourceType::Dhcp);
+
+        let names = |ptr_names: PtrNames| -> Vec<String> {
+            let found = records
+                .lookup(
+                    &test::name("5.4.10.10.in-addr.arpa."),
+                    DNSClass::IN,
+                    RecordType::PTR,
+                )
+                .collect();
+
+            records
+                .choose_ptrs(found, &ptr_names)
+                .iter()
+                .filter_map(|record| match record.rdata() {
+                    RData::Ptr(target) => Some(target.to_string()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        assert_eq!(
+            names(PtrNames::All),
+            vec!["laptop.home.local.", "www.home.local."]
+        );
+        assert_eq!(names(PtrNames::Shortest), vec!["www.home.local."]);
+        assert_eq!(
+            names(PtrNames::Prefer(vec![SourceType::Docker])),
+            vec!["www.home.local."]
+        );
+        assert_eq!(
+            names(PtrNames::Prefer(vec![
+                SourceType::Traefik,
+                SourceType::Dhcp
+            ])),
+            vec!["laptop.home.local.", "www.home.local."]
+        );
+        assert_eq!(
+            names(PtrNames::Prefer(vec![SourceType::File])),
+            vec!["laptop.home.local.", "www.home.local."]
+        );
+
+        let conflicts = records.ptr_conflicts();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].address, "10.10.4.5".parse::<IpAddr>().unwrap());
+        assert_eq!(conflicts[0].names.len(), 2);
+        assert_eq!(
+            conflicts[0].names[1].sources,
+            vec![SourceType::Dhcp, SourceType::Docker]
+        );
+    }
 }
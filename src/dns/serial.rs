@@ -0,0 +1,248 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Error;
+use tokio::task::JoinHandle;
+
+use crate::dns::{Fqdn, RecordSet};
+
+const SERIALS_FILE: &str = "serials.yaml";
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as u32)
+        .unwrap_or_default()
+}
+
+/// Writes the serials to a temporary file which then replaces the real file so
+/// that the file is never left partially written.
+fn write_serials(path: &Path, serials: &BTreeMap<Fqdn, u32>) -> Result<(), Error> {
+    let data = serde_yaml::to_string(serials)?;
+    let temp_path = path.with_extension("yaml.tmp");
+    fs::write(&temp_path, data)?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+fn persist(path: Option<PathBuf>, serials: &BTreeMap<Fqdn, u32>) {
+    let Some(path) = path else {
+        return;
+    };
+
+    if let Err(e) = write_serials(&path, serials) {
+        tracing::warn!(path = %path.display(), error = %e, "Failed to store zone serials");
+    }
+}
+
+/// A serial that is later than both the previous serial and any serial handed
+/// out at an earlier time, so serials keep increasing even if the persisted
+/// state is lost.
+fn next_serial(previous: u32) -> u32 {
+    previous.wrapping_add(1).max(now())
+}
+
+#[derive(Debug, Default)]
+struct SerialState {
+    origins: Vec<Fqdn>,
+    serials: BTreeMap<Fqdn, u32>,
+    path: Option<PathBuf>,
+}
+
+impl SerialState {
+    fn load(&mut self, path: &Path) {
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => {
+                tracing::warn!(path = %path.display(), error = %e, "Failed to read zone serials");
+                return;
+            }
+        };
+
+        match serde_yaml::from_str::<BTreeMap<Fqdn, u32>>(&data) {
+            Ok(serials) => {
+                for (origin, serial) in serials {
+                    let current = self.serials.entry(origin).or_default();
+                    *current = (*current).max(serial);
+                }
+            }
+            Err(e) => {
+                tracing::warn!(path = %path.display(), error = %e, "Failed to parse zone serials");
+            }
+        }
+    }
+}
+
+/// Tracks the SOA serial for each configured zone, increasing it whenever the
+/// records in the zone change.
+#[derive(Debug, Default)]
+pub(crate) struct ZoneSerials {
+    state: Arc<Mutex<SerialState>>,
+    /// Held while writing the serials so that writes never overlap.
+    writer: Arc<Mutex<()>>,
+    /// The most recently started write.
+    pending: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl ZoneSerials {
+    /// Sets the zones to track and the directory to persist serials in.
+    pub(crate) fn configure(&self, origins: Vec<Fqdn>, data_dir: Option<&Path>) {
+        let mut state = self.state.lock().unwrap();
+
+        let path = data_dir.map(|dir| dir.join(SERIALS_FILE));
+        if path != state.path {
+            if let Some(ref path) = path {
+                state.load(path);
+            }
+            state.path = path;
+        }
+
+        for origin in &origins {
+            if !state.serials.contains_key(origin) {
+                state.serials.insert(origin.clone(), now());
+            }
+        }
+
+        state.origins = origins;
+        persist(state.path.clone(), &state.serials);
+    }
+
+    pub(crate) fn serial(&self, origin: &Fqdn) -> u32 {
+        self.state
+            .lock()
+            .unwrap()
+            .serials
+            .get(origin)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Increases the serial of any zone containing records that differ
+    /// between the two sets.
+    pub(crate) fn update(&self, old: &RecordSet, new: &RecordSet) {
+        let changed = old.changed_names(new);
+        if changed.is_empty() {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let mut updated = false;
+
+        let origins = state.origins.clone();
+        for origin in origins {
+            if changed.iter().any(|name| origin.zone_of(name)) {
+                let serial = state.serials.entry(origin.clone()).or_default();
+                *serial = next_serial(*serial);
+
+                tracing::debug!(%origin, serial = *serial, "Zone records changed");
+                updated = true;
+            }
+        }
+
+        if updated {
+            drop(state);
+            self.persist_in_background();
+        }
+    }
+
+    /// Writes the serials without blocking the caller, which may be handling
+    /// changes to the records.
+    fn persist_in_background(&self) {
+        let state = self.state.clone();
+        let writer = self.writer.clone();
+
+        let task = tokio::task::spawn_blocking(move || {
+            let _writing = writer.lock().unwrap();
+
+            // Reading the serials once writes are serialized means that the
+            // last write always stores the latest serials.
+            let (path, serials) = {
+                let state = state.lock().unwrap();
+                (state.path.clone(), state.serials.clone())
+            };
+
+            persist(path, &serials);
+        });
+
+        self.pending.lock().unwrap().replace(task);
+    }
+
+    /// Waits for the most recently started write to complete.
+    #[cfg(test)]
+    async fn flush(&self) {
+        let task = self.pending.lock().unwrap().take();
+        if let Some(task) = task {
+            task.await.unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use crate::{
+        dns::{serial::ZoneSerials, RData, Record, RecordSet},
+        test::fqdn,
+    };
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn serials() {
+        let temp = TempDir::new().unwrap();
+
+        let serials = ZoneSerials::default();
+        serials.configure(
+            vec![fqdn("home.local."), fqdn("10.in-addr.arpa.")],
+            Some(temp.path()),
+        );
+
+        let home = serials.serial(&fqdn("home.local."));
+        let reverse = serials.serial(&fqdn("10.in-addr.arpa."));
+        assert!(home > 0);
+        assert_eq!(serials.serial(&fqdn("other.local.")), 0);
+
+        let old = RecordSet::new();
+        let mut new = RecordSet::new();
+        new.insert(Record::new(
+            fqdn("www.other.local."),
+            RData::Cname(fqdn("www.home.local.")),
+        ));
+
+        serials.update(&old, &new);
+        assert_eq!(serials.serial(&fqdn("home.local.")), home);
+        assert_eq!(serials.serial(&fqdn("10.in-addr.arpa.")), reverse);
+
+        let old = new.clone();
+        new.insert(Record::new(
+            fqdn("www.home.local."),
+            RData::A("10.5.3.2".parse().unwrap()),
+        ));
+
+        serials.update(&old, &new);
+        assert!(serials.serial(&fqdn("home.local.")) > home);
+        assert!(serials.serial(&fqdn("10.in-addr.arpa.")) > reverse);
+        let home = serials.serial(&fqdn("home.local."));
+        let reverse = serials.serial(&fqdn("10.in-addr.arpa."));
+
+        serials.update(&new, &new);
+        assert_eq!(serials.serial(&fqdn("home.local.")), home);
+
+        serials.update(&new, &old);
+        assert!(serials.serial(&fqdn("home.local.")) > home);
+        assert!(serials.serial(&fqdn("10.in-addr.arpa.")) > reverse);
+        let home = serials.serial(&fqdn("home.local."));
+        serials.flush().await;
+
+        // Serials are restored from the data directory.
+        let restored = ZoneSerials::default();
+        restored.configure(vec![fqdn("home.local.")], Some(temp.path()));
+        assert_eq!(restored.serial(&fqdn("home.local.")), home);
+    }
+}
@@ -0,0 +1,452 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{bail, Error};
+use serde::Deserialize;
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    task::JoinHandle,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{
+    config::Zones,
+    dns::{
+        cache::DEFAULT_CACHE_SIZE,
+        handler::Handler,
+        listener::{serve_tcp, serve_udp},
+        rate_limit::RateLimitConfig,
+        tls::{TlsCertificates, TlsConfig},
+        ServerState,
+    },
+    util::Address,
+};
+
+const DEFAULT_PORT: u16 = 53;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Protocol {
+    Udp,
+    Tcp,
+    #[serde(skip_deserializing)]
+    Tls,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Udp => f.pad("udp"),
+            Protocol::Tcp => f.pad("tcp"),
+            Protocol::Tls => f.pad("tls"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum ListenConfig {
+    Address(Address),
+    Full {
+        address: Address,
+        #[serde(default)]
+        protocols: Option<HashSet<Protocol>>,
+    },
+}
+
+impl ListenConfig {
+    fn listeners(&self, default_port: u16) -> Vec<Listener> {
+        let (address, protocols) = match self {
+            ListenConfig::Address(address) => (address, None),
+            ListenConfig::Full { address, protocols } => (address, protocols.as_ref()),
+        };
+
+        let address = address.to_socket_address(default_port);
+
+        match protocols {
+            Some(protocols) => protocols
+                .iter()
+                .map(|protocol| Listener {
+                    protocol: *protocol,
+                    address,
+                })
+                .collect(),
+            None => vec![
+                Listener {
+                    protocol: Protocol::Udp,
+                    address,
+                },
+                Listener {
+                    protocol: Protocol::Tcp,
+                    address,
+                },
+            ],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Default, Deserialize)]
+pub(crate) struct ServerConfig {
+    #[serde(default)]
+    port: Option<u16>,
+
+    #[serde(default)]
+    listen: Vec<ListenConfig>,
+
+    #[serde(default)]
+    tls: Option<TlsConfig>,
+
+    #[serde(default)]
+    cache_size: Option<usize>,
+
+    #[serde(default)]
+    serve_stale_ms: Option<u64>,
+
+    #[serde(default)]
+    rate_limit: Option<RateLimitConfig>,
+}
+
+impl ServerConfig {
+    fn listeners(&self) -> HashSet<Listener> {
+        let port = self.port.unwrap_or(DEFAULT_PORT);
+
+        let mut listeners: HashSet<Listener> = if self.listen.is_empty() {
+            ListenConfig::Address(Address {
+                host: Ipv4Addr::UNSPECIFIED.into(),
+                port: None,
+            })
+            .listeners(port)
+            .into_iter()
+            .collect()
+        } else {
+            self.listen
+                .iter()
+                .flat_map(|listen| listen.listeners(port))
+                .collect()
+        };
+
+        if let Some(ref tls) = self.tls {
+            listeners.extend(tls.addresses().into_iter().map(|address| Listener {
+                protocol: Protocol::Tls,
+                address,
+            }));
+        }
+
+        listeners
+    }
+}
+
+/// A single socket that the DNS server listens on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Listener {
+    protocol: Protocol,
+    address: SocketAddr,
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(&format!("{}://{}", self.protocol, self.address))
+    }
+}
+
+impl Listener {
+    async fn bind(
+        &self,
+        handler: Arc<Handler>,
+        certificates: &TlsCertificates,
+    ) -> Result<JoinHandle<()>, Error> {
+        let task = match self.protocol {
+            Protocol::Udp => {
+                let socket = UdpSocket::bind(self.address).await?;
+                tokio::spawn(serve_udp(socket, handler))
+            }
+            Protocol::Tcp => {
+                let listener = TcpListener::bind(self.address).await?;
+                tokio::spawn(serve_tcp(listener, None, handler))
+            }
+            Protocol::Tls => {
+                let listener = TcpListener::bind(self.address).await?;
+                let acceptor = TlsAcceptor::from(certificates.server_config());
+                tokio::spawn(serve_tcp(listener, Some(acceptor), handler))
+            }
+        };
+
+        Ok(task)
+    }
+}
+
+pub(crate) struct DnsServer {
+    server_state: ServerState<Zones>,
+    servers: HashMap<Listener, JoinHandle<()>>,
+    certificates: TlsCertificates,
+}
+
+impl DnsServer {
+    pub(crate) async fn new(
+        server_config: &ServerConfig,
+        server_state: ServerState<Zones>,
+    ) -> Result<Self, Error> {
+        let mut server = Self {
+            server_state,
+            servers: HashMap::new(),
+            certificates: TlsCertificates::new(),
+        };
+
+        if let Err(e) = server.restart(server_config).await {
+            server.shutdown().await;
+            return Err(e);
+        }
+
+        Ok(server)
+    }
+
+    pub(crate) async fn shutdown(&mut self) {
+        tracing::debug!("Shutting down DNS service");
+
+        for (_, server) in self.servers.drain() {
+            server.abort();
+            let _ = server.await;
+        }
+    }
+
+    /// Brings the set of listening sockets in line with the configuration,
+    /// leaving any that are unchanged alone.
+    pub(crate) async fn restart(&mut self, server_config: &ServerConfig) -> Result<(), Error> {
+        tracing::debug!("Restarting DNS service");
+
+        self.server_state.cache.configure(
+            server_config.cache_size.unwrap_or(DEFAULT_CACHE_SIZE),
+            Duration::from_millis(server_config.serve_stale_ms.unwrap_or_default()),
+        );
+        self.server_state
+            .rate_limiter
+            .configure(server_config.rate_limit.clone());
+
+        let listeners = server_config.listeners();
+
+        let removed: Vec<Listener> = self
+            .servers
+            .keys()
+            .filter(|listener| !listeners.contains(listener))
+            .copied()
+            .collect();
+
+        for listener in removed {
+            if let Some(server) = self.servers.remove(&listener) {
+                tracing::info!("Server no longer listening on {}", listener);
+
+                server.abort();
+                let _ = server.await;
+            }
+        }
+
+        let mut failures = Vec::new();
+
+        let tls_available = match self
+            .certificates
+            .configure(server_config.tls.as_ref())
+            .await
+        {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::error!(error = %e, "Unable to load TLS certificate");
+                failures.push(format!("TLS certificate ({e})"));
+                false
+            }
+        };
+
+        for listener in listeners {
+            if self.servers.contains_key(&listener) {
+                continue;
+            }
+
+            if listener.protocol == Protocol::Tls && !tls_available {
+                continue;
+            }
+
+            let handler = Arc::new(Handler {
+                server_state: self.server_state.clone(),
+            });
+
+            match listener.bind(handler, &self.certificates).await {
+                Ok(server) => {
+                    tracing::info!("Server listening on {}", listener);
+                    self.servers.insert(listener, server);
+                }
+                Err(e) => {
+                    tracing::error!(%listener, error = %e, "Unable to bind DNS listener");
+                    failures.push(format!("{listener} ({e})"));
+                }
+            }
+        }
+
+        if !failures.is_empty() {
+            bail!("Failed to listen on {}", failures.join(", "));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, path::PathBuf};
+
+    use figment::{
+        providers::{Format, Yaml},
+        Figment,
+    };
+    use tokio::{
+        net::{TcpListener, UdpSocket},
+        sync::watch::channel,
+    };
+
+    use crate::{
+        config::Zones,
+        dns::{
+            server::{DnsServer, Listener, Protocol, ServerConfig},
+            RecordSet, ServerState,
+        },
+    };
+
+    fn parse_config(yaml: &str) -> ServerConfig {
+        Figment::from(Yaml::string(yaml)).extract().unwrap()
+    }
+
+    fn listener(protocol: Protocol, address: &str) -> Listener {
+        Listener {
+            protocol,
+            address: address.parse().unwrap(),
+        }
+    }
+
+    #[tracing_test::traced_test]
+    #[test]
+    fn parse_listeners() {
+        let config = parse_config("{}");
+        let listeners = config.listeners();
+        assert_eq!(listeners.len(), 2);
+        assert!(listeners.contains(&listener(Protocol::Udp, "0.0.0.0:53")));
+        assert!(listeners.contains(&listener(Protocol::Tcp, "0.0.0.0:53")));
+
+        let config = parse_config(
+            r#"
+port: 5353
+listen:
+  - 10.10.4.5
+  - "[::1]:53"
+  - address: "::"
+    protocols: [udp]
+tls:
+  certificate: cert.pem
+  private_key: key.pem
+"#,
+        );
+        let listeners = config.listeners();
+        assert_eq!(listeners.len(), 6);
+        assert!(listeners.contains(&listener(Protocol::Tls, "0.0.0.0:853")));
+        assert!(listeners.contains(&listener(Protocol::Udp, "10.10.4.5:5353")));
+        assert!(listeners.contains(&listener(Protocol::Tcp, "10.10.4.5:5353")));
+        assert!(listeners.contains(&listener(Protocol::Udp, "[::1]:53")));
+        assert!(listeners.contains(&listener(Protocol::Tcp, "[::1]:53")));
+        assert!(listeners.contains(&listener(Protocol::Udp, "[::]:5353")));
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn bind_failure() {
+        let (_, receiver) = channel(RecordSet::new());
+        let server_state = ServerState::new(receiver, Zones::default());
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address: SocketAddr = socket.local_addr().unwrap();
+
+        let config = parse_config(&format!(
+            r#"
+listen:
+  - address: "{address}"
+    protocols: [udp]
+"#
+        ));
+
+        assert!(DnsServer::new(&config, server_state.clone()).await.is_err());
+
+        drop(socket);
+
+        let mut server = DnsServer::new(&config, server_state).await.unwrap();
+        assert_eq!(server.servers.len(), 1);
+
+        let config = parse_config(&format!(
+            r#"
+listen:
+  - address: "{address}"
+    protocols: [udp, tcp]
+"#
+        ));
+
+        server.restart(&config).await.unwrap();
+        assert_eq!(server.servers.len(), 2);
+
+        server.shutdown().await;
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn tls_listener() {
+        let (_, receiver) = channel(RecordSet::new());
+        let server_state = ServerState::new(receiver, Zones::default());
+
+        let test_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("test_resources")
+            .join("tls");
+
+        let address = {
+            let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            socket.local_addr().unwrap()
+        };
+
+        let config = parse_config(&format!(
+            r#"
+listen:
+  - address: "127.0.0.1:0"
+    protocols: [udp]
+tls:
+  certificate: {}/cert.pem
+  private_key: {}/key.pem
+  listen:
+    - "{address}"
+"#,
+            test_dir.display(),
+            test_dir.display(),
+        ));
+
+        let mut server = DnsServer::new(&config, server_state.clone()).await.unwrap();
+        assert!(server
+            .servers
+            .contains_key(&listener(Protocol::Tls, &address.to_string())));
+
+        server.shutdown().await;
+
+        let config = parse_config(&format!(
+            r#"
+listen:
+  - address: "127.0.0.1:0"
+    protocols: [udp]
+tls:
+  certificate: {}/missing.pem
+  private_key: {}/key.pem
+  listen:
+    - "{address}"
+"#,
+            test_dir.display(),
+            test_dir.display(),
+        ));
+
+        assert!(DnsServer::new(&config, server_state).await.is_err());
+    }
+}
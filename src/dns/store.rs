@@ -1,7 +1,7 @@
 use std::{
     collections::{HashMap, HashSet},
     ops::Deref,
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 
 use tokio::sync::{
@@ -10,14 +10,24 @@ use tokio::sync::{
 };
 
 use crate::{
-    dns::RecordSet,
-    sources::{IntoSourceRecordSet, SourceId, SourceRecords},
+    config::View,
+    dns::{serial::ZoneSerials, PtrConflict, RecordSet},
+    sources::{
+        blocklist::Blocklists, rpz::ResponsePolicies, update::UpdateZones, IntoSourceRecordSet,
+        SourceId, SourceRecords, SourceSelector,
+    },
 };
 
 #[derive(Clone)]
 pub(crate) struct RecordStore {
     pub(crate) source_records: Arc<RwLock<HashMap<SourceId, Vec<SourceRecords>>>>,
     pub(crate) sender: Sender<RecordSet>,
+    pub(crate) serials: Arc<ZoneSerials>,
+    pub(crate) updates: Arc<UpdateZones>,
+    pub(crate) blocklists: Arc<Blocklists>,
+    pub(crate) policies: Arc<ResponsePolicies>,
+    /// The records visible to views, by the sources that they select.
+    views: Arc<Mutex<HashMap<Vec<SourceSelector>, Arc<RecordSet>>>>,
 }
 
 impl RecordStore {
@@ -27,6 +37,11 @@ impl RecordStore {
         Self {
             source_records: Default::default(),
             sender,
+            serials: Default::default(),
+            updates: Default::default(),
+            blocklists: Default::default(),
+            policies: Default::default(),
+            views: Default::default(),
         }
     }
 
@@ -54,16 +69,71 @@ impl RecordStore {
         joined.into_values()
     }
 
+    fn build_record_set<'a, I>(source_records: I) -> RecordSet
+    where
+        I: Iterator<Item = &'a SourceRecords>,
+    {
+        let mut records = RecordSet::new();
+        for sr in source_records {
+            records.append_source(sr.records.clone(), sr.source_id.source_type);
+        }
+        records
+    }
+
     fn update_record_set<G>(&self, source_records: &G)
     where
         G: Deref<Target = HashMap<SourceId, Vec<SourceRecords>>>,
     {
-        let records = Self::dedupe_sources(source_records)
-            .map(|sr| &sr.records)
-            .cloned()
-            .collect();
+        let records = Self::build_record_set(Self::dedupe_sources(source_records));
+        self.views.lock().unwrap().clear();
+
+        let old_conflicts = self.sender.borrow().ptr_conflicts();
+        for conflict in records.ptr_conflicts() {
+            if !old_conflicts.contains(&conflict) {
+                let names: Vec<String> = conflict
+                    .names
+                    .iter()
+                    .map(|name| name.name.to_string())
+                    .collect();
+
+                tracing::warn!(
+                    address = %conflict.address,
+                    names = names.join(", "),
+                    "Multiple names share an address"
+                );
+            }
+        }
+
+        // Serials are only increased once the new records are visible so that
+        // a secondary never sees the new serial alongside the old records.
+        let old = self.sender.send_replace(records);
+        self.serials.update(&old, &self.sender.borrow());
+    }
+
+    /// The records from the sources that a view selects, or `None` if the
+    /// view uses the records from every source. These are built when first
+    /// needed after the records change.
+    pub(crate) async fn view_records(&self, view: &View) -> Option<Arc<RecordSet>> {
+        let sources = view.sources.as_ref()?;
+
+        let source_records = self.source_records.read().await;
+        self.views
+            .lock()
+            .unwrap()
+            .entry(sources.clone())
+            .or_insert_with(|| {
+                Arc::new(Self::build_record_set(
+                    Self::dedupe_sources(&source_records)
+                        .filter(|sr| view.includes_source(&sr.source_id)),
+                ))
+            })
+            .clone()
+            .into()
+    }
 
-        self.sender.send_replace(records);
+    /// Lists the addresses that more than one name resolves to.
+    pub(crate) fn ptr_conflicts(&self) -> Vec<PtrConflict> {
+        self.sender.borrow().ptr_conflicts()
     }
 
     pub(crate) async fn resolve_source_records(&self) -> Vec<SourceRecords> {
@@ -93,6 +163,8 @@ impl RecordStore {
     pub(crate) async fn prune_sources(&self, keep: &HashSet<SourceId>) {
         let mut source_records = self.source_records.write().await;
         source_records.retain(|source_id, _| keep.contains(source_id));
+        self.blocklists.prune_sources(keep);
+        self.policies.prune_sources(keep);
         self.update_record_set(&source_records);
     }
 }
@@ -0,0 +1,224 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{anyhow, Error};
+use figment::value::magic::RelativePathBuf;
+use hickory_server::proto::rustls::tls_server::{read_cert, read_key};
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+};
+use serde::Deserialize;
+
+use crate::{
+    util::Address,
+    watcher::{watch, FileEvent, WatchListener, Watcher},
+};
+
+const DEFAULT_TLS_PORT: u16 = 853;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub(crate) struct TlsConfig {
+    certificate: RelativePathBuf,
+    private_key: RelativePathBuf,
+
+    #[serde(default)]
+    listen: Vec<Address>,
+}
+
+impl TlsConfig {
+    pub(super) fn addresses(&self) -> Vec<SocketAddr> {
+        if self.listen.is_empty() {
+            vec![SocketAddr::new(
+                Ipv4Addr::UNSPECIFIED.into(),
+                DEFAULT_TLS_PORT,
+            )]
+        } else {
+            self.listen
+                .iter()
+                .map(|address| address.to_socket_address(DEFAULT_TLS_PORT))
+                .collect()
+        }
+    }
+}
+
+fn load_certificate(certificate: &Path, private_key: &Path) -> Result<Arc<CertifiedKey>, Error> {
+    let certificates = read_cert(certificate)?;
+    let key = read_key(private_key)?;
+    let key = sign::any_supported_type(&key)
+        .map_err(|e| anyhow!("Unsupported private key in {}: {e}", private_key.display()))?;
+
+    Ok(Arc::new(CertifiedKey::new(certificates, key)))
+}
+
+/// Hands out whichever certificate was most recently loaded so that the
+/// certificate can be replaced without rebinding listeners.
+#[derive(Default)]
+struct CertificateResolver {
+    key: RwLock<Option<Arc<CertifiedKey>>>,
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.key.read().unwrap().clone()
+    }
+}
+
+impl CertificateResolver {
+    fn replace(&self, key: Arc<CertifiedKey>) {
+        self.key.write().unwrap().replace(key);
+    }
+}
+
+struct CertificateWatcher {
+    certificate: PathBuf,
+    private_key: PathBuf,
+    resolver: Arc<CertificateResolver>,
+}
+
+impl WatchListener for CertificateWatcher {
+    async fn event(&mut self, _: FileEvent) {
+        match load_certificate(&self.certificate, &self.private_key) {
+            Ok(key) => {
+                tracing::info!(certificate = %self.certificate.display(), "Reloaded TLS certificate");
+                self.resolver.replace(key);
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to reload TLS certificate, continuing to use the previous one");
+            }
+        }
+    }
+}
+
+pub(super) struct TlsCertificates {
+    config: Option<TlsConfig>,
+    resolver: Arc<CertificateResolver>,
+    watchers: Vec<Watcher>,
+}
+
+impl TlsCertificates {
+    pub(super) fn new() -> Self {
+        Self {
+            config: None,
+            resolver: Default::default(),
+            watchers: Vec::new(),
+        }
+    }
+
+    pub(super) fn server_config(&self) -> Arc<rustls::ServerConfig> {
+        let mut config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self.resolver.clone());
+        config.alpn_protocols = vec![b"dot".to_vec()];
+
+        Arc::new(config)
+    }
+
+    /// Loads the configured certificate and starts watching it for changes.
+    pub(super) async fn configure(&mut self, config: Option<&TlsConfig>) -> Result<(), Error> {
+        if self.config.as_ref() == config {
+            return Ok(());
+        }
+
+        self.watchers.clear();
+        self.config = config.cloned();
+
+        let Some(config) = config else {
+            return Ok(());
+        };
+
+        let certificate = config.certificate.relative();
+        let private_key = config.private_key.relative();
+
+        let key = load_certificate(&certificate, &private_key).inspect_err(|_| {
+            self.config = None;
+        })?;
+        self.resolver.replace(key);
+
+        for path in [&certificate, &private_key] {
+            let watcher = watch(
+                path,
+                CertificateWatcher {
+                    certificate: certificate.clone(),
+                    private_key: private_key.clone(),
+                    resolver: self.resolver.clone(),
+                },
+            )
+            .await?;
+
+            self.watchers.push(watcher);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc, time::Duration};
+
+    use tempfile::TempDir;
+    use tokio::{fs, time::sleep};
+
+    use crate::{
+        dns::tls::{TlsCertificates, TlsConfig},
+        test::timeout,
+    };
+
+    #[tracing_test::traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reload_certificate() {
+        let test_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("test_resources")
+            .join("tls");
+        let temp = TempDir::new().unwrap();
+
+        let certificate = temp.path().join("cert.pem");
+        let private_key = temp.path().join("key.pem");
+
+        let config = TlsConfig {
+            certificate: certificate.as_path().into(),
+            private_key: private_key.as_path().into(),
+            listen: Vec::new(),
+        };
+
+        let mut certificates = TlsCertificates::new();
+        assert!(certificates.configure(Some(&config)).await.is_err());
+        assert!(certificates.resolver.key.read().unwrap().is_none());
+
+        fs::copy(test_dir.join("cert.pem"), &certificate)
+            .await
+            .unwrap();
+        fs::copy(test_dir.join("key.pem"), &private_key)
+            .await
+            .unwrap();
+
+        certificates.configure(Some(&config)).await.unwrap();
+        let initial = certificates.resolver.key.read().unwrap().clone().unwrap();
+
+        // Any change to either file should trigger a reload.
+        let key_data = fs::read_to_string(&private_key).await.unwrap();
+        fs::write(&private_key, format!("\n{key_data}"))
+            .await
+            .unwrap();
+
+        timeout(async {
+            loop {
+                let current = certificates.resolver.key.read().unwrap().clone().unwrap();
+                if !Arc::ptr_eq(&current, &initial) {
+                    break;
+                }
+
+                sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+
+        certificates.configure(None).await.unwrap();
+        assert!(certificates.watchers.is_empty());
+    }
+}
@@ -0,0 +1,248 @@
+use std::net::IpAddr;
+
+use hickory_server::proto::{
+    op::ResponseCode,
+    rr::{self, DNSClass, Name, RecordType},
+};
+use tracing::instrument;
+
+use crate::{
+    config::ZoneConfigProvider,
+    dns::{Fqdn, LockedServerState, RData},
+};
+
+impl<Z: ZoneConfigProvider> LockedServerState<Z> {
+    /// Builds the records for a transfer of an authoritative zone, starting and
+    /// ending with the zone's SOA record. Incremental transfers are answered
+    /// with the full zone unless the client is already up to date. Transfers
+    /// are allowed from the zone's allowed networks or when signed with one of
+    /// the zone's transfer keys.
+    #[instrument(level = "debug", fields(%zone, %source), skip(self))]
+    pub(super) fn zone_transfer(
+        &self,
+        zone: &Name,
+        source: IpAddr,
+        key: Option<&Fqdn>,
+        client_serial: Option<u32>,
+    ) -> Result<Vec<rr::Record>, ResponseCode> {
+        let fqdn = Fqdn::from(zone.clone());
+        let config = self.zone_config(&fqdn);
+
+        if config.origin.as_ref() != Some(&fqdn) {
+            tracing::debug!("Transfer requested for a name that is not a zone");
+            return Err(ResponseCode::NotAuth);
+        }
+
+        let Some(soa) = config.soa() else {
+            tracing::debug!("Transfer requested for a zone that is not authoritative");
+            return Err(ResponseCode::NotAuth);
+        };
+
+        let allowed_source = config
+            .allow_transfer
+            .iter()
+            .any(|network| network.contains(&source));
+        let allowed_key = key.is_some_and(|key| config.transfer_keys.contains(key));
+
+        if !allowed_source && !allowed_key {
+            tracing::warn!("Refused zone transfer");
+            return Err(ResponseCode::Refused);
+        }
+
+        let serial = match soa.data() {
+            Some(rr::RData::SOA(soa)) => soa.serial(),
+            _ => 0,
+        };
+
+        if client_serial == Some(serial) {
+            tracing::debug!(serial, "Client is already up to date");
+            return Ok(vec![soa]);
+        }
+
+        let mut records = vec![soa.clone()];
+
+        let reverse_records = self.records.reverse_records(&config.ptr_names);
+        for record in self.records.records().cloned().chain(reverse_records) {
+            // Records in a sub-zone belong to that zone's transfer.
+            if self.zone_config(record.name()).origin.as_ref() != Some(&fqdn) {
+                continue;
+            }
+
+            match record.rdata() {
+                RData::Aname(target) => {
+                    // Secondaries won't understand ANAME so send the addresses
+                    // that it currently resolves to locally.
+                    let ttl = record.ttl.unwrap_or(config.ttl);
+
+                    for record_type in [RecordType::A, RecordType::AAAA] {
+                        records.extend(
+                            self.records
+                                .lookup(&target.name(), DNSClass::IN, record_type)
+                                .filter_map(|target| target.raw(&config))
+                                .filter_map(|target| target.into_data())
+                                .map(|data| {
+                                    rr::Record::from_rdata(record.name().name(), ttl, data)
+                                }),
+                        );
+                    }
+                }
+                _ => records.extend(record.raw(&config)),
+            }
+        }
+
+        records.push(soa);
+
+        tracing::info!(records = records.len() - 2, "Serving zone transfer");
+
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use hickory_server::proto::{
+        op::ResponseCode,
+        rr::{self, RecordType},
+    };
+    use tokio::sync::watch::channel;
+
+    use crate::{
+        config::{ZoneConfig, ZoneConfigProvider},
+        dns::{Fqdn, RData, Record, RecordSet, ServerState},
+        test::{fqdn, name, rdata_a},
+    };
+
+    #[derive(Clone)]
+    struct TransferZones {}
+
+    impl ZoneConfigProvider for TransferZones {
+        fn zone_config(&self, name: &Fqdn) -> ZoneConfig {
+            let mut config = ZoneConfig::default();
+
+            for origin in ["home.local.", "sub.home.local.", "10.in-addr.arpa."] {
+                let origin = fqdn(origin);
+                if origin.zone_of(name) {
+                    config.origin = Some(origin);
+                    config.authoritative = true;
+                    config.allow_transfer = vec!["10.10.0.0/16".parse().unwrap()];
+                    config.transfer_keys = vec![fqdn("transfer.key.")];
+                }
+            }
+
+            config
+        }
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn sorted(records: &[rr::Record]) -> Vec<(String, RecordType)> {
+        let mut records: Vec<(String, RecordType)> = records
+            .iter()
+            .map(|record| (record.name().to_string(), record.record_type()))
+            .collect();
+        records.sort();
+        records
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn transfer() {
+        let mut records = RecordSet::new();
+        records.insert(Record::new(
+            fqdn("www.home.local."),
+            RData::A("10.10.5.3".parse().unwrap()),
+        ));
+        records.insert(Record::new(
+            fqdn("alias.home.local."),
+            RData::Aname(fqdn("www.home.local.")),
+        ));
+        records.insert(Record::new(
+            fqdn("other.sub.home.local."),
+            RData::A("10.10.5.4".parse().unwrap()),
+        ));
+        records.insert(Record::new(
+            fqdn("www.example.org."),
+            RData::A("10.10.5.5".parse().unwrap()),
+        ));
+
+        let (_, receiver) = channel(records);
+        let server_state = ServerState::new(receiver, TransferZones {}).locked().await;
+
+        let transfer = server_state
+            .zone_transfer(&name("home.local."), ip("10.10.1.1"), None, None)
+            .unwrap();
+
+        assert_eq!(transfer.first().unwrap().record_type(), RecordType::SOA);
+        assert_eq!(transfer.last().unwrap().record_type(), RecordType::SOA);
+        assert_eq!(
+            sorted(&transfer[1..transfer.len() - 1]),
+            vec![
+                ("alias.home.local.".to_owned(), RecordType::A),
+                ("www.home.local.".to_owned(), RecordType::A),
+            ]
+        );
+
+        let alias = transfer
+            .iter()
+            .find(|record| *record.name() == name("alias.home.local."))
+            .unwrap();
+        assert_eq!(*alias.data().unwrap(), rdata_a("10.10.5.3"));
+
+        let transfer = server_state
+            .zone_transfer(&name("10.in-addr.arpa."), ip("10.10.1.1"), None, None)
+            .unwrap();
+        assert_eq!(
+            sorted(&transfer[1..transfer.len() - 1]),
+            vec![
+                ("3.5.10.10.in-addr.arpa.".to_owned(), RecordType::PTR),
+                ("4.5.10.10.in-addr.arpa.".to_owned(), RecordType::PTR),
+                ("5.5.10.10.in-addr.arpa.".to_owned(), RecordType::PTR),
+            ]
+        );
+
+        // An up to date client only gets the SOA.
+        let transfer = server_state
+            .zone_transfer(&name("home.local."), ip("10.10.1.1"), None, Some(0))
+            .unwrap();
+        assert_eq!(transfer.len(), 1);
+        assert_eq!(transfer.first().unwrap().record_type(), RecordType::SOA);
+
+        assert_eq!(
+            server_state.zone_transfer(&name("home.local."), ip("10.11.1.1"), None, None),
+            Err(ResponseCode::Refused)
+        );
+
+        // Signed requests are allowed from anywhere.
+        let transfer = server_state
+            .zone_transfer(
+                &name("home.local."),
+                ip("10.11.1.1"),
+                Some(&fqdn("transfer.key.")),
+                None,
+            )
+            .unwrap();
+        assert_eq!(transfer.len(), 4);
+
+        assert_eq!(
+            server_state.zone_transfer(
+                &name("home.local."),
+                ip("10.11.1.1"),
+                Some(&fqdn("other.key.")),
+                None
+            ),
+            Err(ResponseCode::Refused)
+        );
+        assert_eq!(
+            server_state.zone_transfer(&name("www.home.local."), ip("10.10.1.1"), None, None),
+            Err(ResponseCode::NotAuth)
+        );
+        assert_eq!(
+            server_state.zone_transfer(&name("example.org."), ip("10.10.1.1"), None, None),
+            Err(ResponseCode::NotAuth)
+        );
+    }
+}
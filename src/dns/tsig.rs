@@ -0,0 +1,574 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Error;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hickory_server::{
+    proto::{
+        error::ProtoResult,
+        op::{
+            message::{emit_message_parts, EmitAndCount},
+            Header, Query, ResponseCode,
+        },
+        rr::{
+            self,
+            dnssec::{
+                rdata::{
+                    tsig::{self, make_tsig_record, message_tbs, signed_bitmessage_to_buf, TSIG},
+                    DNSSECRData,
+                },
+                tsig::TSigner,
+            },
+            RecordType,
+        },
+        serialize::binary::{BinEncodable, BinEncoder},
+    },
+    server::Request,
+};
+use serde::Deserialize;
+
+use crate::dns::Fqdn;
+
+/// How far the signing time may differ from our clock, as recommended by
+/// RFC 8945.
+const FUDGE: u16 = 300;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum TsigAlgorithm {
+    #[default]
+    HmacSha256,
+    HmacSha512,
+}
+
+impl From<TsigAlgorithm> for tsig::TsigAlgorithm {
+    fn from(algorithm: TsigAlgorithm) -> Self {
+        match algorithm {
+            TsigAlgorithm::HmacSha256 => tsig::TsigAlgorithm::HmacSha256,
+            TsigAlgorithm::HmacSha512 => tsig::TsigAlgorithm::HmacSha512,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TsigKeyConfig {
+    #[serde(default)]
+    algorithm: TsigAlgorithm,
+    secret: String,
+}
+
+/// A shared secret used to authenticate messages.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "TsigKeyConfig")]
+pub(crate) struct TsigKey {
+    algorithm: TsigAlgorithm,
+    secret: Vec<u8>,
+}
+
+impl TryFrom<TsigKeyConfig> for TsigKey {
+    type Error = base64::DecodeError;
+
+    fn try_from(config: TsigKeyConfig) -> Result<Self, Self::Error> {
+        Ok(Self {
+            algorithm: config.algorithm,
+            secret: STANDARD.decode(config.secret.trim())?,
+        })
+    }
+}
+
+impl TsigKey {
+    /// Creates a signer for requests that we send using this key.
+    pub(crate) fn signer(&self, name: &Fqdn) -> Result<TSigner, Error> {
+        Ok(TSigner::new(
+            self.secret.clone(),
+            self.algorithm.into(),
+            name.name(),
+            FUDGE,
+        )?)
+    }
+}
+
+impl fmt::Debug for TsigKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TsigKey")
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Emits a query the same way as hickory-server which copies the query from
+/// the request and only records the full name for name compression.
+struct ResponseQuery<'a>(&'a Query);
+
+impl EmitAndCount for ResponseQuery<'_> {
+    fn emit(&mut self, encoder: &mut BinEncoder<'_>) -> ProtoResult<usize> {
+        let offset = encoder.offset();
+        encoder.emit_vec(&self.0.to_bytes()?)?;
+        encoder.store_label_pointer(offset, offset + self.0.name().len());
+        Ok(1)
+    }
+}
+
+/// A response as it will be sent, without its TSIG record.
+struct UnsignedResponse<'a> {
+    header: &'a Header,
+    query: &'a Query,
+    answers: &'a [rr::Record],
+    name_servers: &'a [rr::Record],
+    additionals: &'a [rr::Record],
+}
+
+impl BinEncodable for UnsignedResponse<'_> {
+    fn emit(&self, encoder: &mut BinEncoder<'_>) -> ProtoResult<()> {
+        // Compression pointers are offsets from the start of the message so
+        // it must be encoded separately to the previous MAC that precedes it.
+        let mut buffer = Vec::new();
+        emit_message_parts(
+            self.header,
+            &mut ResponseQuery(self.query),
+            &mut self.answers.iter(),
+            &mut self.name_servers.iter(),
+            &mut self.additionals.iter(),
+            None,
+            &[],
+            &mut BinEncoder::new(&mut buffer),
+        )?;
+
+        encoder.emit_vec(&buffer)
+    }
+}
+
+/// A request that carried a valid TSIG signature.
+pub(super) struct SignedRequest {
+    pub(super) key_name: Fqdn,
+    key: TsigKey,
+    mac: Vec<u8>,
+}
+
+impl SignedRequest {
+    /// Starts signing the messages of the response to this request. The
+    /// response must be sent without EDNS.
+    pub(super) fn response_signer(&self) -> ResponseSigner<'_> {
+        ResponseSigner {
+            request: self,
+            previous_mac: None,
+        }
+    }
+
+    /// The encoded size of the TSIG record that will be added to the
+    /// response.
+    pub(super) fn signature_len(&self) -> usize {
+        let signature = TSIG::new(
+            self.key.algorithm.into(),
+            0,
+            FUDGE,
+            vec![0; self.mac.len()],
+            0,
+            0,
+            Vec::new(),
+        );
+
+        make_tsig_record(self.key_name.name(), signature)
+            .to_bytes()
+            .map(|bytes| bytes.len())
+            .unwrap_or_default()
+    }
+}
+
+/// Builds the TSIG records for the messages of a response. When a response
+/// spans several messages, as zone transfers over TCP do, each signature
+/// covers the previous one and only the timers of the TSIG record (RFC 8945
+/// section 5.3.1).
+pub(super) struct ResponseSigner<'a> {
+    request: &'a SignedRequest,
+    previous_mac: Option<Vec<u8>>,
+}
+
+impl ResponseSigner<'_> {
+    /// Builds the TSIG record to send as the last additional record of the
+    /// next message with the given sections.
+    pub(super) fn sign(
+        &mut self,
+        header: &Header,
+        query: &Query,
+        answers: &[rr::Record],
+        name_servers: &[rr::Record],
+        additionals: &[rr::Record],
+    ) -> Result<rr::Record, Error> {
+        let response = UnsignedResponse {
+            header,
+            query,
+            answers,
+            name_servers,
+            additionals,
+        };
+
+        let algorithm: tsig::TsigAlgorithm = self.request.key.algorithm.into();
+        let pre_tsig = TSIG::new(
+            algorithm.clone(),
+            now(),
+            FUDGE,
+            Vec::new(),
+            header.id(),
+            0,
+            Vec::new(),
+        );
+
+        let key_name = self.request.key_name.name();
+        let tbs = match self.previous_mac {
+            None => message_tbs(Some(&self.request.mac), &response, &pre_tsig, &key_name)?,
+            Some(ref previous_mac) => {
+                let mut tbs = Vec::new();
+                let mut encoder = BinEncoder::new(&mut tbs);
+                encoder.emit_u16(previous_mac.len() as u16)?;
+                encoder.emit_vec(previous_mac)?;
+                response.emit(&mut encoder)?;
+                encoder.emit_u16((pre_tsig.time() >> 32) as u16)?;
+                encoder.emit_u32(pre_tsig.time() as u32)?;
+                encoder.emit_u16(pre_tsig.fudge())?;
+                tbs
+            }
+        };
+        let mac = algorithm.mac_data(&self.request.key.secret, &tbs)?;
+        self.previous_mac = Some(mac.clone());
+
+        Ok(make_tsig_record(key_name, pre_tsig.set_mac(mac)))
+    }
+}
+
+/// Why a signed request was rejected.
+pub(super) struct RejectedRequest {
+    pub(super) response_code: ResponseCode,
+    /// An unsigned TSIG record telling the client what was wrong with the
+    /// signature (RFC 8945 section 5.2).
+    pub(super) signature: Option<Box<rr::Record>>,
+}
+
+impl RejectedRequest {
+    fn malformed() -> Self {
+        Self {
+            response_code: ResponseCode::FormErr,
+            signature: None,
+        }
+    }
+
+    fn with_error(key_name: &Fqdn, signature: &TSIG, oid: u16, error: ResponseCode) -> Self {
+        let time = now();
+
+        // A BADTIME response tells the client our time.
+        let other = if error == ResponseCode::BADTIME {
+            time.to_be_bytes()[2..].to_vec()
+        } else {
+            Vec::new()
+        };
+
+        let tsig = TSIG::new(
+            signature.algorithm().clone(),
+            time,
+            FUDGE,
+            Vec::new(),
+            oid,
+            error.into(),
+            other,
+        );
+
+        Self {
+            response_code: ResponseCode::NotAuth,
+            signature: Some(Box::new(make_tsig_record(key_name.name(), tsig))),
+        }
+    }
+}
+
+/// Checks the TSIG signature on a request if there is one against the message
+/// as it was received. Requests that are signed with an unknown key, have an
+/// invalid signature or were signed too long ago are rejected.
+pub(super) fn verify_request(
+    keys: &HashMap<Fqdn, TsigKey>,
+    request: &Request,
+    message: &[u8],
+) -> Result<Option<SignedRequest>, RejectedRequest> {
+    let Some(record) = request
+        .sig0()
+        .iter()
+        .find(|record| record.record_type() == RecordType::TSIG)
+    else {
+        return Ok(None);
+    };
+
+    let Some(rr::RData::DNSSEC(DNSSECRData::TSIG(signature))) = record.data() else {
+        return Err(RejectedRequest::malformed());
+    };
+
+    let key_name = Fqdn::from(record.name().clone());
+    let rejected = |error| RejectedRequest::with_error(&key_name, signature, request.id(), error);
+
+    let Some(key) = keys.get(&key_name) else {
+        tracing::warn!(key = %key_name, "Request signed with an unknown key");
+        return Err(rejected(ResponseCode::BADKEY));
+    };
+
+    let algorithm: tsig::TsigAlgorithm = key.algorithm.into();
+    if signature.algorithm() != &algorithm {
+        tracing::warn!(key = %key_name, "Request signed with the wrong algorithm");
+        return Err(rejected(ResponseCode::BADKEY));
+    }
+
+    // The signature covers the message as it was before the TSIG record was
+    // added.
+    let (tbs, _) =
+        signed_bitmessage_to_buf(None, message, true).map_err(|_| RejectedRequest::malformed())?;
+
+    if algorithm
+        .verify_mac(&key.secret, &tbs, signature.mac())
+        .is_err()
+    {
+        tracing::warn!(key = %key_name, "Request has an invalid signature");
+        return Err(rejected(ResponseCode::BADSIG));
+    }
+
+    if now().abs_diff(signature.time()) > signature.fudge().into() {
+        tracing::warn!(key = %key_name, "Request signature has expired");
+        return Err(rejected(ResponseCode::BADTIME));
+    }
+
+    Ok(Some(SignedRequest {
+        key_name,
+        key: key.clone(),
+        mac: signature.mac().to_vec(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::Arc,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use hickory_client::{
+        client::{AsyncClient, ClientHandle, Signer},
+        op::ResponseCode,
+        op::{Message, Query},
+        proto::{
+            iocompat::AsyncIoTokioAsStd,
+            rr::dnssec::{
+                rdata::{
+                    tsig::{TsigAlgorithm, TSIG},
+                    DNSSECRData,
+                },
+                tsig::TSigner,
+            },
+        },
+        rr::{self, DNSClass, RecordType},
+        tcp::TcpClientStream,
+    };
+    use tempfile::TempDir;
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        sync::watch::channel,
+    };
+
+    use crate::{
+        config::Config,
+        dns::{doh, DnsServer, RData, Record, RecordSet, ServerState},
+        test::{fqdn, name, write_file},
+    };
+
+    const SECRET: &[u8] = b"a secret used for testing signed requests";
+
+    fn signer(key: &str) -> TSigner {
+        TSigner::new(SECRET.to_vec(), TsigAlgorithm::HmacSha512, name(key), 300).unwrap()
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn signed_requests() {
+        let temp = TempDir::new().unwrap();
+
+        let address: SocketAddr = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+
+        let config_file = temp.path().join("config.yml");
+        write_file(
+            &config_file,
+            &format!(
+                r#"
+server:
+  listen:
+    - "{address}"
+
+tsig_keys:
+  transfer.key.:
+    algorithm: hmac-sha512
+    secret: {secret}
+
+zones:
+  home.local:
+    transfer_keys: [transfer.key]
+"#,
+                secret = STANDARD.encode(SECRET)
+            ),
+        )
+        .await;
+
+        let config = Config::from_file(&config_file).unwrap();
+
+        let mut records = RecordSet::new();
+        records.insert(Record::new(
+            fqdn("www.home.local."),
+            RData::A("10.10.5.3".parse().unwrap()),
+        ));
+        records.insert(Record::new(
+            fqdn("alias.home.local."),
+            RData::Cname(fqdn("www.home.local.")),
+        ));
+
+        let (_sender, receiver) = channel(records);
+        let server_state = ServerState::new(receiver, config.zones.clone());
+        let mut dns_server = DnsServer::new(&config.server, server_state).await.unwrap();
+
+        // The client verifies the signature on the response.
+        let (stream, sender) = TcpClientStream::<AsyncIoTokioAsStd<TcpStream>>::new(address);
+        let (mut client, bg) = AsyncClient::new(
+            stream,
+            sender,
+            Some(Arc::new(Signer::from(signer("transfer.key.")))),
+        )
+        .await
+        .unwrap();
+        tokio::spawn(bg);
+
+        let response = client
+            .query(name("home.local."), DNSClass::IN, RecordType::AXFR)
+            .await
+            .unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(response.answers().len(), 4);
+
+        // Transfers are refused without the key.
+        let (stream, sender) = TcpClientStream::<AsyncIoTokioAsStd<TcpStream>>::new(address);
+        let (mut client, bg) = AsyncClient::new(stream, sender, None).await.unwrap();
+        tokio::spawn(bg);
+
+        let response = client
+            .query(name("home.local."), DNSClass::IN, RecordType::AXFR)
+            .await
+            .unwrap();
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+
+        dns_server.shutdown().await;
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn rejected_requests() {
+        let temp = TempDir::new().unwrap();
+
+        let config_file = temp.path().join("config.yml");
+        write_file(
+            &config_file,
+            &format!(
+                r#"
+tsig_keys:
+  transfer.key.:
+    algorithm: hmac-sha512
+    secret: {secret}
+"#,
+                secret = STANDARD.encode(SECRET)
+            ),
+        )
+        .await;
+
+        let config = Config::from_file(&config_file).unwrap();
+        let (_sender, receiver) = channel(RecordSet::new());
+        let server_state = ServerState::new(receiver, config.zones.clone());
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32;
+        let wrong_secret = TSigner::new(
+            b"not the secret".to_vec(),
+            TsigAlgorithm::HmacSha512,
+            name("transfer.key."),
+            300,
+        )
+        .unwrap();
+
+        for (signer, time, error) in [
+            (signer("unknown.key."), now, ResponseCode::BADKEY),
+            (wrong_secret, now, ResponseCode::BADSIG),
+            (signer("transfer.key."), now - 3600, ResponseCode::BADTIME),
+        ] {
+            let mut message = Message::new();
+            message.set_id(1234);
+            message.add_query(Query::query(name("www.home.local."), RecordType::A));
+            message.finalize(&signer, time).unwrap();
+
+            let source = SocketAddr::new("10.10.1.1".parse().unwrap(), 5353);
+            let response = doh::handle_message(&server_state, &message.to_vec().unwrap(), source)
+                .await
+                .unwrap();
+            let response = Message::from_vec(&response.message).unwrap();
+            assert_eq!(response.response_code(), ResponseCode::NotAuth);
+
+            // The TSIG record says what was wrong without being signed.
+            let record = response.signature().first().unwrap();
+            assert_eq!(record.record_type(), RecordType::TSIG);
+            let Some(rr::RData::DNSSEC(DNSSECRData::TSIG(tsig))) = record.data() else {
+                panic!("Expected a TSIG record");
+            };
+            assert!(tsig.mac().is_empty());
+
+            let other = if error == ResponseCode::BADTIME {
+                tsig.time().to_be_bytes()[2..].to_vec()
+            } else {
+                Vec::new()
+            };
+            assert_eq!(
+                *tsig,
+                TSIG::new(
+                    TsigAlgorithm::HmacSha512,
+                    tsig.time(),
+                    300,
+                    Vec::new(),
+                    1234,
+                    error.into(),
+                    other,
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let temp = TempDir::new().unwrap();
+
+        let config_file = temp.path().join("config.yml");
+        std::fs::write(
+            &config_file,
+            r#"
+zones:
+  home.local:
+    update_keys: [missing.key]
+"#,
+        )
+        .unwrap();
+
+        assert!(Config::from_file(&config_file).is_err());
+    }
+}
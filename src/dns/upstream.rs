@@ -1,47 +1,453 @@
-use std::{fmt, net::SocketAddr};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::Duration,
+};
 
+use anyhow::{anyhow, bail};
+use futures::{stream::FuturesUnordered, StreamExt};
 use hickory_client::{
     client::{AsyncClient, ClientHandle},
-    op::DnsResponse,
-    rr::{self, DNSClass, Name, RecordType},
+    error::{ClientError, ClientErrorKind},
+    op::{DnsResponse, Message, ResponseCode},
+    proto::{
+        error::ProtoErrorKind, h2::HttpsClientStreamBuilder, iocompat::AsyncIoTokioAsStd,
+        rustls::tls_client_connect, tcp::TcpClientStream,
+    },
+    rr::{DNSClass, Name, RecordType},
     udp::UdpClientStream,
 };
+use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
 use serde::Deserialize;
-use tokio::net::UdpSocket;
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    time::{self, Instant},
+};
 use tracing::{instrument, Span};
 
-use crate::{dns::query::QueryState, util::Address, Error};
+use crate::{util::Address, Error};
+
+const DEFAULT_TIMEOUT_MS: u64 = 2000;
+/// Consecutive failures before an upstream is considered unavailable.
+const FAILURE_THRESHOLD: u32 = 3;
+const UNAVAILABLE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The protocol used to talk to an upstream server. The encrypted transports
+/// carry the name to verify the server's certificate against.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Transport {
+    Udp,
+    Tcp,
+    Tls(String),
+    Https(String),
+}
+
+impl Transport {
+    fn default_port(&self) -> u16 {
+        match self {
+            Transport::Udp | Transport::Tcp => 53,
+            Transport::Tls(_) => 853,
+            Transport::Https(_) => 443,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct UpstreamConfig {
+    pub(crate) transport: Transport,
+    address: Address,
+}
+
+impl UpstreamConfig {
+    #[cfg(test)]
+    pub(crate) fn address(&self, default_port: u16) -> String {
+        self.address.address(default_port)
+    }
+
+    fn socket_address(&self) -> SocketAddr {
+        self.address
+            .to_socket_address(self.transport.default_port())
+    }
+}
+
+impl fmt::Display for UpstreamConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.transport {
+            Transport::Udp => f.pad(&self.address.to_string()),
+            Transport::Tcp => f.pad(&format!("tcp://{}", self.address)),
+            Transport::Tls(server_name) => {
+                f.pad(&format!("tls://{}#{}", self.address, server_name))
+            }
+            Transport::Https(server_name) => {
+                f.pad(&format!("https://{}#{}", self.address, server_name))
+            }
+        }
+    }
+}
+
+impl From<Address> for UpstreamConfig {
+    fn from(address: Address) -> Self {
+        Self {
+            transport: Transport::Udp,
+            address,
+        }
+    }
+}
+
+impl FromStr for UpstreamConfig {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (scheme, remainder) = match value.split_once("://") {
+            Some((scheme, remainder)) => (scheme, remainder),
+            None => ("udp", value),
+        };
+
+        let (remainder, server_name) = match remainder.split_once('#') {
+            Some((remainder, server_name)) => (remainder, Some(server_name)),
+            None => (remainder, None),
+        };
+
+        let host = match remainder.split_once('/') {
+            Some((host, "" | "dns-query")) if scheme == "https" => host,
+            Some(_) => bail!("Unexpected path in upstream '{value}'"),
+            None => remainder,
+        };
+
+        let address = Address::try_from(host.to_owned())?;
+        let server_name = server_name
+            .map(|name| name.to_owned())
+            .unwrap_or_else(|| address.host.to_string());
+
+        let transport = match scheme {
+            "udp" => Transport::Udp,
+            "tcp" => Transport::Tcp,
+            "tls" => Transport::Tls(server_name),
+            "https" => Transport::Https(server_name),
+            _ => bail!("Unknown upstream protocol '{scheme}'"),
+        };
 
-pub(crate) type UpstreamConfig = Address;
+        Ok(Self { transport, address })
+    }
+}
+
+impl TryFrom<String> for UpstreamConfig {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
 
-async fn connect_client(address: SocketAddr) -> Result<AsyncClient, Error> {
-    let stream = UdpClientStream::<UdpSocket>::new(address);
+fn tls_client_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
 
-    let client = AsyncClient::connect(stream);
-    let (client, bg) = client.await?;
-    tokio::spawn(bg);
+    CONFIG
+        .get_or_init(|| {
+            let mut roots = RootCertStore::empty();
+            roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    anchor.subject,
+                    anchor.spki,
+                    anchor.name_constraints,
+                )
+            }));
+
+            Arc::new(
+                ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth(),
+            )
+        })
+        .clone()
+}
+
+async fn connect_client(transport: &Transport, address: SocketAddr) -> Result<AsyncClient, Error> {
+    type Tcp = AsyncIoTokioAsStd<TcpStream>;
+
+    let client = match transport {
+        Transport::Udp => {
+            let stream = UdpClientStream::<UdpSocket>::new(address);
+            let (client, bg) = AsyncClient::connect(stream).await?;
+            tokio::spawn(bg);
+            client
+        }
+        Transport::Tcp => {
+            let (stream, sender) = TcpClientStream::<Tcp>::new(address);
+            let (client, bg) = AsyncClient::new(stream, sender, None).await?;
+            tokio::spawn(bg);
+            client
+        }
+        Transport::Tls(server_name) => {
+            let (stream, sender) =
+                tls_client_connect::<Tcp>(address, server_name.clone(), tls_client_config());
+            let (client, bg) = AsyncClient::new(stream, sender, None).await?;
+            tokio::spawn(bg);
+            client
+        }
+        Transport::Https(server_name) => {
+            let stream = HttpsClientStreamBuilder::with_client_config(tls_client_config())
+                .build::<Tcp>(address, server_name.clone());
+            let (client, bg) = AsyncClient::connect(stream).await?;
+            tokio::spawn(bg);
+            client
+        }
+    };
 
     Ok(client)
 }
 
-#[derive(Clone, PartialEq, Eq, Deserialize)]
-#[serde(from = "UpstreamConfig")]
+/// Whether a failed query means that the client's connection can no longer be
+/// used, rather than only the query failing, such as by timing out on a
+/// connection that other queries are still using.
+fn is_connection_error(error: &ClientError) -> bool {
+    match error.kind() {
+        ClientErrorKind::Timeout => false,
+        ClientErrorKind::Proto(e) => !matches!(e.kind(), ProtoErrorKind::Timeout),
+        _ => true,
+    }
+}
+
+/// A pooled client along with an identifier for the connection that it uses.
+#[derive(Clone)]
+struct PooledClient {
+    id: u64,
+    client: AsyncClient,
+}
+
+/// Connected clients for a single upstream, one per transport. Each client
+/// multiplexes concurrent queries so they are shared across queries.
+#[derive(Default)]
+struct ClientPool {
+    clients: Mutex<HashMap<Transport, PooledClient>>,
+    next_id: AtomicU64,
+}
+
+impl ClientPool {
+    async fn client(
+        &self,
+        transport: &Transport,
+        address: SocketAddr,
+    ) -> Result<(PooledClient, bool), Error> {
+        if let Some(pooled) = self.clients.lock().unwrap().get(transport) {
+            return Ok((pooled.clone(), true));
+        }
+
+        let pooled = PooledClient {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            client: connect_client(transport, address).await?,
+        };
+        self.clients
+            .lock()
+            .unwrap()
+            .insert(transport.clone(), pooled.clone());
+
+        Ok((pooled, false))
+    }
+
+    /// Drops the client if it is still the pooled client for the transport.
+    /// Another query may already have replaced it with a new connection.
+    fn discard(&self, transport: &Transport, id: u64) {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.get(transport).is_some_and(|pooled| pooled.id == id) {
+            clients.remove(transport);
+        }
+    }
+
+    async fn query(
+        &self,
+        transport: &Transport,
+        address: SocketAddr,
+        name: &Name,
+        query_class: DNSClass,
+        query_type: RecordType,
+    ) -> Result<DnsResponse, Error> {
+        let (mut pooled, reused) = self.client(transport, address).await?;
+
+        match pooled
+            .client
+            .query(name.clone(), query_class, query_type)
+            .await
+        {
+            Ok(response) => Ok(response),
+            Err(e) if !is_connection_error(&e) => Err(e.into()),
+            Err(e) => {
+                self.discard(transport, pooled.id);
+
+                if !reused {
+                    return Err(e.into());
+                }
+
+                // The server may have closed an idle connection, try once more
+                // with a fresh one.
+                tracing::debug!(error = %e, "Pooled upstream connection failed, reconnecting");
+                let (mut pooled, _) = self.client(transport, address).await?;
+
+                match pooled
+                    .client
+                    .query(name.clone(), query_class, query_type)
+                    .await
+                {
+                    Ok(response) => Ok(response),
+                    Err(e) => {
+                        if is_connection_error(&e) {
+                            self.discard(transport, pooled.id);
+                        }
+                        Err(e.into())
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// How a zone uses its list of upstream servers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum UpstreamMode {
+    /// Try each upstream in turn until one answers.
+    #[default]
+    Failover,
+    /// Query every upstream at once and use the first answer.
+    Race,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UpstreamItem {
+    Address(UpstreamConfig),
+    Full {
+        address: UpstreamConfig,
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+    },
+}
+
+#[derive(Default)]
+struct HealthState {
+    failures: u32,
+    unavailable_until: Option<Instant>,
+}
+
+/// Tracks recent failures of an upstream so that one that is not responding
+/// can be skipped for a while.
+#[derive(Default)]
+struct Health {
+    state: Mutex<HealthState>,
+}
+
+impl Health {
+    fn is_available(&self) -> bool {
+        match self.state.lock().unwrap().unavailable_until {
+            Some(until) => Instant::now() >= until,
+            None => true,
+        }
+    }
+
+    fn success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.unavailable_until.is_some() {
+            tracing::info!("Upstream DNS server is available again");
+        }
+
+        *state = HealthState::default();
+    }
+
+    fn failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures += 1;
+
+        if state.failures >= FAILURE_THRESHOLD {
+            if state.unavailable_until.is_none() {
+                tracing::warn!(
+                    failures = state.failures,
+                    "Upstream DNS server is not responding, skipping it for now"
+                );
+            }
+
+            state.unavailable_until = Some(Instant::now() + UNAVAILABLE_INTERVAL);
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(from = "UpstreamItem")]
 pub(crate) struct Upstream {
     pub(crate) config: UpstreamConfig,
+    timeout: Duration,
+    health: Arc<Health>,
+    clients: Arc<ClientPool>,
 }
 
+impl PartialEq for Upstream {
+    fn eq(&self, other: &Self) -> bool {
+        self.config == other.config && self.timeout == other.timeout
+    }
+}
+
+impl Eq for Upstream {}
+
 impl fmt::Debug for Upstream {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.pad(&format!("{}", self.config))
     }
 }
 
+impl From<UpstreamItem> for Upstream {
+    fn from(item: UpstreamItem) -> Upstream {
+        match item {
+            UpstreamItem::Address(config) => config.into(),
+            UpstreamItem::Full {
+                address,
+                timeout_ms,
+            } => Upstream {
+                timeout: Duration::from_millis(timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS)),
+                ..address.into()
+            },
+        }
+    }
+}
+
 impl From<UpstreamConfig> for Upstream {
     fn from(config: UpstreamConfig) -> Upstream {
-        Upstream { config }
+        Upstream {
+            config,
+            timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
+            health: Default::default(),
+            clients: Default::default(),
+        }
     }
 }
 
+impl From<Address> for Upstream {
+    fn from(address: Address) -> Upstream {
+        UpstreamConfig::from(address).into()
+    }
+}
+
+/// Whether a response is good enough to stop asking other upstreams.
+pub(super) fn is_answer(response: &Message) -> bool {
+    !matches!(
+        response.response_code(),
+        ResponseCode::ServFail | ResponseCode::Refused
+    )
+}
+
+/// Whether every upstream has recently stopped responding.
+pub(super) fn all_unavailable(upstreams: &VecDeque<Upstream>) -> bool {
+    upstreams
+        .iter()
+        .all(|upstream| !upstream.health.is_available())
+}
+
 impl Upstream {
     #[instrument(level = "trace", name = "upstream_resolve", fields(
         upstream = %self.config,
@@ -56,75 +462,475 @@ impl Upstream {
         query_class: DNSClass,
         query_type: RecordType,
     ) -> Option<DnsResponse> {
-        let address = self.config.to_socket_address(53);
+        let address = self.config.socket_address();
 
-        let mut client = match connect_client(address).await {
-            Ok(c) => c,
-            Err(e) => {
-                tracing::error!(error = %e);
-                return None;
-            }
-        };
+        let result = time::timeout(self.timeout, async {
+            let response = self
+                .clients
+                .query(
+                    &self.config.transport,
+                    address,
+                    name,
+                    query_class,
+                    query_type,
+                )
+                .await?;
 
-        let result = client.query(name.clone(), query_class, query_type).await;
+            if self.config.transport == Transport::Udp && response.truncated() {
+                tracing::debug!("Truncated response from upstream, retrying over TCP");
+                self.clients
+                    .query(&Transport::Tcp, address, name, query_class, query_type)
+                    .await
+            } else {
+                Ok(response)
+            }
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Timed out after {}ms", self.timeout.as_millis())));
 
         match result {
             Ok(response) => {
                 let span = Span::current();
-                span.record("lookup.response_code", response.response_code().to_string());
+                span.record("response_code", response.response_code().to_string());
+
+                if is_answer(&response) {
+                    self.health.success();
+                } else {
+                    self.health.failure();
+                }
+
                 Some(response)
             }
             Err(e) => {
                 tracing::warn!(error = %e, "Upstream DNS server returned error");
+                self.health.failure();
                 None
             }
         }
     }
+}
 
-    pub(super) async fn resolve(&self, name: &Name, query_state: &mut QueryState) {
-        if let Some(response) = self
-            .lookup(name, query_state.query_class(), query_state.query_type())
-            .await
-        {
-            let mut message = response.into_message();
+/// Resolves a name using a zone's upstreams. Upstreams that have recently
+/// stopped responding are only used when no others are available.
+pub(super) async fn resolve(
+    upstreams: &VecDeque<Upstream>,
+    mode: UpstreamMode,
+    name: &Name,
+    query_class: DNSClass,
+    query_type: RecordType,
+) -> Option<Message> {
+    let (mut candidates, unavailable): (Vec<&Upstream>, Vec<&Upstream>) = upstreams
+        .iter()
+        .partition(|upstream| upstream.health.is_available());
 
-            query_state.add_answers(message.take_answers());
-            query_state.add_additionals(message.take_additionals());
+    let mut fallback = None;
 
-            if name == query_state.query.name() {
-                let mut name_servers: Vec<rr::Record> = Vec::new();
-                let mut soa: Option<rr::Record> = None;
+    let response = match mode {
+        UpstreamMode::Failover => {
+            candidates.extend(unavailable);
 
-                for record in message.take_name_servers() {
-                    if record.record_type() == rr::RecordType::SOA {
-                        soa.replace(record);
-                    } else {
-                        name_servers.push(record);
+            let mut answer = None;
+            for upstream in candidates {
+                match upstream.lookup(name, query_class, query_type).await {
+                    Some(response) if is_answer(&response) => {
+                        answer = Some(response);
+                        break;
                     }
+                    Some(response) => fallback = Some(response),
+                    None => {}
                 }
+            }
 
-                query_state.name_servers.extend(name_servers);
-                query_state.soa = soa;
+            answer
+        }
+        UpstreamMode::Race => {
+            if candidates.is_empty() {
+                candidates = unavailable;
             }
+
+            let mut lookups: FuturesUnordered<_> = candidates
+                .into_iter()
+                .map(|upstream| upstream.lookup(name, query_class, query_type))
+                .collect();
+
+            let mut answer = None;
+            while let Some(result) = lookups.next().await {
+                match result {
+                    Some(response) if is_answer(&response) => {
+                        answer = Some(response);
+                        break;
+                    }
+                    Some(response) => fallback = Some(response),
+                    None => {}
+                }
+            }
+
+            answer
         }
-    }
+    };
+
+    response
+        .or(fallback)
+        .map(|response| response.into_message())
 }
 
 #[cfg(test)]
 mod tests {
-    use std::str::FromStr;
+    use std::{
+        collections::VecDeque,
+        net::SocketAddr,
+        str::FromStr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
 
     use hickory_client::{
+        client::ClientHandle,
+        error::ClientErrorKind,
         op::{Query, ResponseCode},
-        rr::{DNSClass, RecordType},
+        proto::error::{ProtoError, ProtoErrorKind},
+        rr::{DNSClass, Name, RData, RecordType},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, UdpSocket},
+        time::Instant,
     };
 
     use crate::{
-        dns::{query::QueryState, Upstream},
-        test::{coredns_container, name, rdata_a, rdata_cname},
+        dns::{
+            query::QueryState,
+            upstream::{
+                connect_client, is_connection_error, resolve, ClientPool, Transport,
+                UpstreamConfig, UpstreamMode, FAILURE_THRESHOLD,
+            },
+            Upstream,
+        },
+        test::{coredns_container, fake_response, name, rdata_a, rdata_cname, udp_server},
         util::{Address, Host},
     };
 
+    #[tracing_test::traced_test]
+    #[test]
+    fn parse_config() {
+        let config = UpstreamConfig::from_str("10.10.10.1").unwrap();
+        assert_eq!(config.transport, Transport::Udp);
+        assert_eq!(config.socket_address(), "10.10.10.1:53".parse().unwrap());
+        assert_eq!(config.to_string(), "10.10.10.1");
+
+        let config = UpstreamConfig::from_str("tcp://10.10.10.1:5353").unwrap();
+        assert_eq!(config.transport, Transport::Tcp);
+        assert_eq!(config.socket_address(), "10.10.10.1:5353".parse().unwrap());
+
+        let config = UpstreamConfig::from_str("tls://1.1.1.1#cloudflare-dns.com").unwrap();
+        assert_eq!(
+            config.transport,
+            Transport::Tls("cloudflare-dns.com".to_owned())
+        );
+        assert_eq!(config.socket_address(), "1.1.1.1:853".parse().unwrap());
+        assert_eq!(config.to_string(), "tls://1.1.1.1#cloudflare-dns.com");
+
+        let config = UpstreamConfig::from_str("tls://[2606:4700::1111]:8853").unwrap();
+        assert_eq!(
+            config.transport,
+            Transport::Tls("2606:4700::1111".to_owned())
+        );
+        assert_eq!(
+            config.socket_address(),
+            "[2606:4700::1111]:8853".parse().unwrap()
+        );
+
+        let config =
+            UpstreamConfig::from_str("https://1.1.1.1/dns-query#cloudflare-dns.com").unwrap();
+        assert_eq!(
+            config.transport,
+            Transport::Https("cloudflare-dns.com".to_owned())
+        );
+        assert_eq!(config.socket_address(), "1.1.1.1:443".parse().unwrap());
+
+        assert!(UpstreamConfig::from_str("quic://1.1.1.1").is_err());
+        assert!(UpstreamConfig::from_str("https://1.1.1.1/resolve").is_err());
+        assert!(UpstreamConfig::from_str("tcp://1.1.1.1/dns-query").is_err());
+    }
+
+    /// Serves truncated answers over UDP and full answers over TCP on the same
+    /// port. Also returns the number of TCP connections accepted.
+    async fn truncating_server() -> (SocketAddr, Arc<AtomicUsize>) {
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = tcp.local_addr().unwrap();
+        let udp = UdpSocket::bind(address).await.unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        tokio::spawn(async move {
+            let mut buffer = [0_u8; 512];
+            loop {
+                let (len, source) = udp.recv_from(&mut buffer).await.unwrap();
+                let response = fake_response(&buffer[..len], None);
+                udp.send_to(&response, source).await.unwrap();
+            }
+        });
+
+        let counter = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = tcp.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                tokio::spawn(async move {
+                    while let Ok(len) = stream.read_u16().await {
+                        let mut buffer = vec![0_u8; len as usize];
+                        stream.read_exact(&mut buffer).await.unwrap();
+
+                        let response = fake_response(&buffer, Some("10.10.10.5"));
+                        let mut framed = (response.len() as u16).to_be_bytes().to_vec();
+                        framed.extend(response);
+                        stream.write_all(&framed).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        (address, connections)
+    }
+
+    /// Receives queries over UDP but never answers them.
+    async fn silent_server() -> (SocketAddr, Arc<AtomicUsize>) {
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = udp.local_addr().unwrap();
+        let received = Arc::new(AtomicUsize::new(0));
+
+        let counter = received.clone();
+        tokio::spawn(async move {
+            let mut buffer = [0_u8; 512];
+            while udp.recv_from(&mut buffer).await.is_ok() {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        (address, received)
+    }
+
+    async fn resolve_query(
+        upstreams: &VecDeque<Upstream>,
+        mode: UpstreamMode,
+        name: &Name,
+        query_state: &mut QueryState,
+    ) {
+        if let Some(message) = resolve(
+            upstreams,
+            mode,
+            name,
+            query_state.query_class(),
+            query_state.query_type(),
+        )
+        .await
+        {
+            query_state.add_response(name, message);
+        }
+    }
+
+    fn upstream(address: SocketAddr, timeout_ms: u64) -> Upstream {
+        Upstream {
+            timeout: Duration::from_millis(timeout_ms),
+            ..Upstream::from(Address {
+                host: Host::from_str("127.0.0.1").unwrap(),
+                port: Some(address.port()),
+            })
+        }
+    }
+
+    fn answer(query_state: &QueryState) -> RData {
+        let answers = query_state.answers();
+        assert_eq!(answers.len(), 1);
+        answers.first().unwrap().data().unwrap().clone()
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn failover() {
+        let (dead, received) = silent_server().await;
+        let (live, _) = udp_server("10.10.10.6", Duration::ZERO).await;
+
+        let upstreams = VecDeque::from([upstream(dead, 100), upstream(live, 2000)]);
+
+        for _ in 0..FAILURE_THRESHOLD {
+            let mut query_state =
+                QueryState::new(Query::query(name("www.example.org."), RecordType::A), true);
+            resolve_query(
+                &upstreams,
+                UpstreamMode::Failover,
+                &name("www.example.org."),
+                &mut query_state,
+            )
+            .await;
+
+            assert_eq!(query_state.response_code, ResponseCode::NoError);
+            assert_eq!(answer(&query_state), rdata_a("10.10.10.6"));
+        }
+
+        assert_eq!(received.load(Ordering::SeqCst), FAILURE_THRESHOLD as usize);
+        assert!(!upstreams[0].health.is_available());
+        assert!(upstreams[1].health.is_available());
+
+        // The unresponsive upstream should now be skipped entirely.
+        let mut query_state =
+            QueryState::new(Query::query(name("www.example.org."), RecordType::A), true);
+        resolve_query(
+            &upstreams,
+            UpstreamMode::Failover,
+            &name("www.example.org."),
+            &mut query_state,
+        )
+        .await;
+
+        assert_eq!(answer(&query_state), rdata_a("10.10.10.6"));
+        assert_eq!(received.load(Ordering::SeqCst), FAILURE_THRESHOLD as usize);
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn race() {
+        let (slow, _) = udp_server("10.10.10.7", Duration::from_millis(1000)).await;
+        let (fast, _) = udp_server("10.10.10.8", Duration::ZERO).await;
+
+        let upstreams = VecDeque::from([upstream(slow, 5000), upstream(fast, 5000)]);
+
+        let mut query_state =
+            QueryState::new(Query::query(name("www.example.org."), RecordType::A), true);
+        resolve_query(
+            &upstreams,
+            UpstreamMode::Race,
+            &name("www.example.org."),
+            &mut query_state,
+        )
+        .await;
+
+        assert_eq!(query_state.response_code, ResponseCode::NoError);
+        assert_eq!(answer(&query_state), rdata_a("10.10.10.8"));
+
+        let mut query_state =
+            QueryState::new(Query::query(name("www.example.org."), RecordType::A), true);
+        resolve_query(
+            &upstreams,
+            UpstreamMode::Failover,
+            &name("www.example.org."),
+            &mut query_state,
+        )
+        .await;
+
+        assert_eq!(answer(&query_state), rdata_a("10.10.10.7"));
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn pooled_connections() {
+        let (address, connections) = truncating_server().await;
+        let name = name("www.example.org.");
+
+        let pool = ClientPool::default();
+        for _ in 0..10 {
+            pool.query(&Transport::Tcp, address, &name, DNSClass::IN, RecordType::A)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        let id = pool.clients.lock().unwrap()[&Transport::Tcp].id;
+
+        // Only the client that failed is dropped.
+        pool.discard(&Transport::Tcp, id + 1);
+        assert!(pool.clients.lock().unwrap().contains_key(&Transport::Tcp));
+        pool.discard(&Transport::Tcp, id);
+        assert!(pool.clients.lock().unwrap().is_empty());
+
+        pool.query(&Transport::Tcp, address, &name, DNSClass::IN, RecordType::A)
+            .await
+            .unwrap();
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+        // Queries that time out leave the connection in place for others.
+        assert!(!is_connection_error(&ClientErrorKind::Timeout.into()));
+        assert!(!is_connection_error(
+            &ProtoError::from(ProtoErrorKind::Timeout).into()
+        ));
+        assert!(is_connection_error(
+            &ProtoError::from("stream closed").into()
+        ));
+    }
+
+    /// Compares the throughput of connecting for every query with reusing
+    /// pooled connections. The timings depend on the machine so this only
+    /// runs when asked for.
+    #[tracing_test::traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "compares timings, run with --ignored"]
+    async fn pooled_throughput() {
+        const QUERIES: u32 = 200;
+
+        let (address, _) = truncating_server().await;
+        let name = name("www.example.org.");
+
+        let start = Instant::now();
+        for _ in 0..QUERIES {
+            let mut client = connect_client(&Transport::Tcp, address).await.unwrap();
+            client
+                .query(name.clone(), DNSClass::IN, RecordType::A)
+                .await
+                .unwrap();
+        }
+        let fresh = start.elapsed();
+
+        let pool = ClientPool::default();
+        let start = Instant::now();
+        for _ in 0..QUERIES {
+            pool.query(&Transport::Tcp, address, &name, DNSClass::IN, RecordType::A)
+                .await
+                .unwrap();
+        }
+        let pooled = start.elapsed();
+
+        tracing::info!(
+            fresh_qps = (QUERIES as f64 / fresh.as_secs_f64()).round(),
+            pooled_qps = (QUERIES as f64 / pooled.as_secs_f64()).round(),
+            "Upstream query throughput"
+        );
+
+        assert!(pooled < fresh);
+        assert_eq!(pool.clients.lock().unwrap().len(), 1);
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn truncated_retry() {
+        let (address, _) = truncating_server().await;
+
+        let upstreams = VecDeque::from([Upstream::from(Address {
+            host: Host::from_str("127.0.0.1").unwrap(),
+            port: Some(address.port()),
+        })]);
+
+        let mut query_state =
+            QueryState::new(Query::query(name("www.example.org."), RecordType::A), false);
+        resolve_query(
+            &upstreams,
+            UpstreamMode::Failover,
+            &name("www.example.org."),
+            &mut query_state,
+        )
+        .await;
+
+        assert_eq!(query_state.response_code, ResponseCode::NoError);
+        let answers = query_state.answers();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(
+            *answers.first().unwrap().data().unwrap(),
+            rdata_a("10.10.10.5")
+        );
+    }
+
     #[tracing_test::traced_test]
     #[tokio::test]
     async fn test_upstream() {
@@ -143,18 +949,22 @@ data    IN CNAME www
         )
         .await;
 
-        let upstream = Upstream::from(Address {
+        let upstreams = VecDeque::from([Upstream::from(Address {
             host: Host::from_str("127.0.0.1").unwrap(),
             port: Some(coredns.get_udp_port(53).await),
-        });
+        })]);
 
         let mut query_state = QueryState::new(
             Query::query(name("unknown.example.org."), RecordType::A),
             false,
         );
-        upstream
-            .resolve(&name("unknown.example.org."), &mut query_state)
-            .await;
+        resolve_query(
+            &upstreams,
+            UpstreamMode::Failover,
+            &name("unknown.example.org."),
+            &mut query_state,
+        )
+        .await;
 
         assert_eq!(query_state.response_code, ResponseCode::NXDomain);
         assert!(query_state.answers().is_empty());
@@ -162,9 +972,13 @@ data    IN CNAME www
 
         let mut query_state =
             QueryState::new(Query::query(name("www.example.org."), RecordType::A), false);
-        upstream
-            .resolve(&name("www.example.org."), &mut query_state)
-            .await;
+        resolve_query(
+            &upstreams,
+            UpstreamMode::Failover,
+            &name("www.example.org."),
+            &mut query_state,
+        )
+        .await;
 
         assert_eq!(query_state.response_code, ResponseCode::NoError);
         assert!(query_state.additionals().is_empty());
@@ -182,9 +996,13 @@ data    IN CNAME www
             Query::query(name("data.example.org."), RecordType::A),
             false,
         );
-        upstream
-            .resolve(&name("data.example.org."), &mut query_state)
-            .await;
+        resolve_query(
+            &upstreams,
+            UpstreamMode::Failover,
+            &name("data.example.org."),
+            &mut query_state,
+        )
+        .await;
 
         assert_eq!(query_state.response_code, ResponseCode::NoError);
         assert!(query_state.additionals().is_empty());
@@ -16,6 +16,7 @@ use std::{
     sync::Arc,
 };
 
+use anyhow::bail;
 pub use anyhow::Error;
 use reqwest::Client;
 use tokio::sync::Mutex;
@@ -24,14 +25,35 @@ use uuid::Uuid;
 
 use crate::{
     api::ApiServer,
-    config::{Config, Zones},
-    dns::{store::RecordStore, DnsServer, ServerState},
+    config::{Config, ZoneConfigProvider, Zones},
+    dns::{notify::Notifier, store::RecordStore, DnsServer, Fqdn, ServerState},
     sources::Sources,
     watcher::{watch, WatchListener, Watcher},
 };
 
 pub(crate) type ServerId = Uuid;
 
+/// Generates the DS records that the parent of a DNSSEC signed zone should
+/// publish.
+pub fn ds_records(config_file: &Path, zone: &str) -> Result<Vec<String>, Error> {
+    let zones = Config::zones_from_file(config_file)?;
+    let origin = Fqdn::try_from(zone)?;
+    let zone_config = zones.zone_config(&origin);
+
+    let Some(ref dnssec) = zone_config.dnssec else {
+        bail!("Zone {origin} is not signed");
+    };
+    if zone_config.origin.as_ref() != Some(&origin) {
+        bail!("{origin} is not a configured zone");
+    }
+
+    Ok(dnssec
+        .ds_records(&origin, zone_config.ttl)?
+        .iter()
+        .map(|record| record.to_string())
+        .collect())
+}
+
 struct LockedOption<T> {
     inner: Arc<Mutex<Option<T>>>,
 }
@@ -70,6 +92,7 @@ pub struct Server {
     server_state: ServerState<Zones>,
     record_store: RecordStore,
     dns_server: Arc<Mutex<DnsServer>>,
+    notifier: Arc<Notifier>,
     config_watcher: LockedOption<Watcher>,
     api_server: LockedOption<ApiServer>,
 }
@@ -96,7 +119,11 @@ impl Server {
         let config = Config::from_file(config_path)?;
 
         let record_store = RecordStore::new();
-        let server_state = ServerState::new(record_store.receiver(), config.zones.clone());
+        record_store
+            .serials
+            .configure(config.zones.origins(), config.data_dir.as_deref());
+        let server_state = ServerState::new(record_store.receiver(), config.zones.clone())
+            .with_record_store(&record_store);
 
         let http_client = Client::builder()
             .dns_resolver(Arc::new(server_state.clone()))
@@ -111,18 +138,22 @@ impl Server {
             record_store: record_store.clone(),
             sources: Arc::new(Mutex::new(sources)),
             dns_server: Arc::new(Mutex::new(
-                DnsServer::new(&config.server, server_state.clone()).await,
+                DnsServer::new(&config.server, server_state.clone()).await?,
             )),
+            notifier: Arc::new(Notifier::new(server_state.clone())),
             server_state,
             config_watcher: Default::default(),
             api_server: Default::default(),
         };
 
-        if let Some(api_server) = config
-            .api
-            .as_ref()
-            .and_then(|api_config| ApiServer::new(api_config, server_id, record_store))
-        {
+        if let Some(api_server) = config.api.as_ref().and_then(|api_config| {
+            ApiServer::new(
+                api_config,
+                server_id,
+                record_store,
+                server.server_state.clone(),
+            )
+        }) {
             server.api_server.replace(api_server).await;
         }
 
@@ -166,6 +197,8 @@ impl Server {
             dns_server.shutdown().await;
         }
 
+        self.notifier.shutdown();
+
         {
             let mut sources = self.sources.lock().await;
             sources.shutdown().await;
@@ -183,6 +216,9 @@ impl Server {
             let mut old_config = new_config.clone();
             mem::swap(config.deref_mut(), &mut old_config);
             self.server_state.replace_zones(config.zones.clone()).await;
+            self.record_store
+                .serials
+                .configure(config.zones.origins(), config.data_dir.as_deref());
 
             (restart_server, restart_api_server, old_config)
         };
@@ -196,7 +232,9 @@ impl Server {
 
         if restart_server {
             let mut dns_server = self.dns_server.lock().await;
-            dns_server.restart(&new_config.server).await;
+            if let Err(e) = dns_server.restart(&new_config.server).await {
+                tracing::error!(error = %e, "Failed to restart DNS server");
+            }
         }
 
         if restart_api_server {
@@ -205,7 +243,12 @@ impl Server {
             }
 
             if let Some(api_server) = new_config.api.as_ref().and_then(|api_config| {
-                ApiServer::new(api_config, self.server_id, self.record_store.clone())
+                ApiServer::new(
+                    api_config,
+                    self.server_id,
+                    self.record_store.clone(),
+                    self.server_state.clone(),
+                )
             }) {
                 self.api_server.replace(api_server).await;
             }
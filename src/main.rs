@@ -22,6 +22,10 @@ use tracing_subscriber::{
 #[clap(author, version)]
 struct CliArgs {
     config: Option<String>,
+
+    /// Print the DS records for a DNSSEC signed zone and exit.
+    #[clap(long, value_name = "ZONE")]
+    ds: Option<String>,
 }
 
 fn config_file(arg: Option<&str>) -> PathBuf {
@@ -41,6 +45,15 @@ async fn wait_for_termination() {
 async fn run() -> Result<(), Error> {
     let args = CliArgs::parse();
     let config_path = config_file(args.config.as_deref());
+
+    if let Some(zone) = args.ds {
+        for record in localns::ds_records(&config_path, &zone)? {
+            println!("{record}");
+        }
+
+        return Ok(());
+    }
+
     let server = Server::new(&config_path).await?;
 
     wait_for_termination().await;
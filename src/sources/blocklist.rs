@@ -0,0 +1,568 @@
+use std::{collections::HashSet, net::IpAddr, sync::RwLock, time::Duration};
+
+use anyhow::anyhow;
+use figment::value::magic::RelativePathBuf;
+use hickory_server::proto::rr::Name;
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use tokio::{fs, time::sleep};
+use tracing::instrument;
+
+use crate::{
+    config::{deserialize_url, View},
+    run_loop::{LoopResult, RunLoop},
+    sources::{RecordStore, SourceConfig, SourceHandle, SourceId, SourceType},
+    Error,
+};
+
+/// Lists are refreshed daily by default.
+const REFRESH_INTERVAL_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// How soon to try again after failing to load a list.
+const RETRY_INTERVAL_MS: u64 = 60 * 1000;
+
+/// Names that hosts files commonly map but that should never be blocked.
+const HOSTS_IGNORED: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts",
+    "0.0.0.0",
+];
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ListSource {
+    Url(#[serde(deserialize_with = "deserialize_url")] Url),
+    File(RelativePathBuf),
+}
+
+/// How to answer queries for blocked names.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) enum BlockResponse {
+    NxDomain,
+    /// Answers address queries with `0.0.0.0` or `::`.
+    #[default]
+    Null,
+    /// Answers address queries of the same family with this address.
+    Sink(IpAddr),
+}
+
+impl TryFrom<String> for BlockResponse {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "nxdomain" => Ok(BlockResponse::NxDomain),
+            "null" => Ok(BlockResponse::Null),
+            _ => value
+                .parse()
+                .map(BlockResponse::Sink)
+                .map_err(|_| anyhow!("Expected nxdomain, null or an address but got '{value}'")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub(crate) struct BlocklistConfig {
+    lists: Vec<ListSource>,
+
+    #[serde(default)]
+    allow: Vec<ListSource>,
+
+    #[serde(default)]
+    response: BlockResponse,
+
+    #[serde(default)]
+    interval_ms: Option<u64>,
+}
+
+/// A set of names, each either matching exactly or matching every name below
+/// it. Names are stored in lowercase without the trailing dot so that lookups
+/// are a hash lookup for each label of the name.
+#[derive(Debug, Default)]
+struct DomainSet {
+    exact: HashSet<Box<str>>,
+    subdomains: HashSet<Box<str>>,
+}
+
+impl DomainSet {
+    fn len(&self) -> usize {
+        self.exact.len() + self.subdomains.len()
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        if self.exact.contains(name) {
+            return true;
+        }
+
+        let mut remaining = name;
+        while let Some((_, parent)) = remaining.split_once('.') {
+            if self.subdomains.contains(parent) {
+                return true;
+            }
+            remaining = parent;
+        }
+
+        false
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Rule<'a> {
+    name: &'a str,
+    /// Whether this is an AdBlock exception (`@@||name^`).
+    exception: bool,
+    /// Whether the name itself matches.
+    exact: bool,
+    /// Whether names below the name match.
+    subdomains: bool,
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && !name.ends_with('.')
+        && name
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '-' | '_' | '.'))
+}
+
+/// Parses a line from a hosts file, a list of domains or an AdBlock filter
+/// list. Lines that don't apply to DNS are ignored.
+fn parse_line(line: &str) -> Vec<Rule<'_>> {
+    let line = line.trim();
+
+    // Comments, section headers and cosmetic filters.
+    if line.is_empty()
+        || line.starts_with(['#', '!', '['])
+        || line.contains("##")
+        || line.contains("#@#")
+        || line.contains("#?#")
+    {
+        return Vec::new();
+    }
+
+    let (line, exception) = match line.strip_prefix("@@") {
+        Some(line) => (line, true),
+        None => (line, false),
+    };
+
+    if let Some(filter) = line.strip_prefix("||") {
+        // Filters with options or paths are only meaningful to browsers.
+        let Some(name) = filter.strip_suffix('^').or(filter.strip_suffix("^|")) else {
+            return Vec::new();
+        };
+
+        if !valid_name(name) {
+            return Vec::new();
+        }
+
+        return vec![Rule {
+            name,
+            exception,
+            exact: true,
+            subdomains: true,
+        }];
+    }
+
+    if exception {
+        return Vec::new();
+    }
+
+    let line = line.split('#').next().unwrap_or_default();
+    let mut tokens = line.split_whitespace();
+    let Some(first) = tokens.next() else {
+        return Vec::new();
+    };
+
+    if first.parse::<IpAddr>().is_ok() {
+        return tokens
+            .filter(|name| valid_name(name) && !HOSTS_IGNORED.contains(name))
+            .map(|name| Rule {
+                name,
+                exception: false,
+                exact: true,
+                subdomains: false,
+            })
+            .collect();
+    }
+
+    if tokens.next().is_some() {
+        return Vec::new();
+    }
+
+    let (name, exact, subdomains) = match first.strip_prefix("*.") {
+        Some(name) => (name, false, true),
+        None => (first, true, false),
+    };
+
+    if !valid_name(name) {
+        return Vec::new();
+    }
+
+    vec![Rule {
+        name,
+        exception: false,
+        exact,
+        subdomains,
+    }]
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Blocklist {
+    response: BlockResponse,
+    blocked: DomainSet,
+    allowed: DomainSet,
+}
+
+impl Blocklist {
+    /// Adds the rules from a list. Everything in an allow-list allows names.
+    fn add_list(&mut self, list: &str, allow_list: bool) {
+        for rule in list.lines().flat_map(parse_line) {
+            let set = if allow_list || rule.exception {
+                &mut self.allowed
+            } else {
+                &mut self.blocked
+            };
+
+            let name: Box<str> = rule.name.to_ascii_lowercase().into();
+            if rule.exact {
+                set.exact.insert(name.clone());
+            }
+            if rule.subdomains {
+                set.subdomains.insert(name);
+            }
+        }
+    }
+}
+
+/// The blocklists loaded by each blocklist source, ordered by the source's
+/// name. A name is blocked when any list blocks it and no list allows it.
+#[derive(Debug, Default)]
+pub(crate) struct Blocklists {
+    lists: RwLock<Vec<(SourceId, Blocklist)>>,
+}
+
+impl Blocklists {
+    fn replace(&self, source_id: &SourceId, blocklist: Blocklist) {
+        let mut lists = self.lists.write().unwrap();
+        lists.retain(|(id, _)| id != source_id);
+        lists.push((source_id.clone(), blocklist));
+        lists.sort_by(|(a, _), (b, _)| a.source_name.cmp(&b.source_name));
+    }
+
+    pub(crate) fn prune_sources(&self, keep: &HashSet<SourceId>) {
+        self.lists
+            .write()
+            .unwrap()
+            .retain(|(source_id, _)| keep.contains(source_id));
+    }
+
+    /// Finds how to answer for a blocked name, using only the lists that a
+    /// view includes. When several lists block the name the response of the
+    /// first by source name is used.
+    pub(crate) fn blocked(&self, name: &Name, view: Option<&View>) -> Option<BlockResponse> {
+        let lists = self.lists.read().unwrap();
+        if lists.is_empty() {
+            return None;
+        }
+
+        let name = name.to_ascii().to_ascii_lowercase();
+        let name = name.trim_end_matches('.');
+
+        let mut response = None;
+        for (source_id, list) in lists.iter() {
+            if view.is_some_and(|view| !view.includes_source(source_id)) {
+                continue;
+            }
+
+            if list.allowed.contains(name) {
+                return None;
+            }
+
+            if response.is_none() && list.blocked.contains(name) {
+                response = Some(list.response);
+            }
+        }
+
+        response
+    }
+}
+
+async fn fetch_list(client: &Client, list: &ListSource) -> Result<String, Error> {
+    match list {
+        ListSource::Url(url) => Ok(client
+            .get(url.clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?),
+        ListSource::File(path) => Ok(fs::read_to_string(path.relative()).await?),
+    }
+}
+
+#[instrument(level = "debug", name = "blocklist_load", fields(%source_id), skip(client, config), err)]
+async fn load_blocklist(
+    source_id: &SourceId,
+    client: &Client,
+    config: &BlocklistConfig,
+) -> Result<Blocklist, Error> {
+    let mut blocklist = Blocklist {
+        response: config.response,
+        ..Default::default()
+    };
+
+    for list in &config.lists {
+        blocklist.add_list(&fetch_list(client, list).await?, false);
+    }
+
+    for list in &config.allow {
+        blocklist.add_list(&fetch_list(client, list).await?, true);
+    }
+
+    tracing::info!(
+        blocked = blocklist.blocked.len(),
+        allowed = blocklist.allowed.len(),
+        "Loaded blocklist"
+    );
+
+    Ok(blocklist)
+}
+
+async fn blocklist_loop(
+    record_store: RecordStore,
+    client: Client,
+    source_id: SourceId,
+    config: BlocklistConfig,
+) -> LoopResult {
+    // If a list can't be loaded the previous version remains in use.
+    match load_blocklist(&source_id, &client, &config).await {
+        Ok(blocklist) => record_store.blocklists.replace(&source_id, blocklist),
+        Err(_) => return LoopResult::Backoff,
+    }
+
+    sleep(Duration::from_millis(
+        config.interval_ms.unwrap_or(REFRESH_INTERVAL_MS),
+    ))
+    .await;
+
+    LoopResult::Sleep
+}
+
+impl SourceConfig for BlocklistConfig {
+    fn source_type() -> SourceType {
+        SourceType::Blocklist
+    }
+
+    async fn spawn(
+        self,
+        source_id: SourceId,
+        record_store: &RecordStore,
+        client: &Client,
+    ) -> Result<SourceHandle, Error> {
+        let handle = {
+            let backoff = RunLoop::new(RETRY_INTERVAL_MS);
+            let config = self.clone();
+            let client = client.clone();
+
+            tokio::spawn(backoff.run(
+                record_store.clone(),
+                source_id,
+                move |record_store, source_id| {
+                    blocklist_loop(record_store, client.clone(), source_id, config.clone())
+                },
+            ))
+        };
+
+        Ok(handle.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use uuid::Uuid;
+
+    use crate::{
+        sources::{
+            blocklist::{parse_line, BlockResponse, Blocklist, Blocklists, Rule},
+            SourceId, SourceType,
+        },
+        test::name,
+    };
+
+    fn rule(name: &str, exception: bool, exact: bool, subdomains: bool) -> Rule<'_> {
+        Rule {
+            name,
+            exception,
+            exact,
+            subdomains,
+        }
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_line("# A comment"), vec![]);
+        assert_eq!(parse_line("! Title: AdBlock list"), vec![]);
+        assert_eq!(parse_line("[Adblock Plus 2.0]"), vec![]);
+        assert_eq!(parse_line("example.com##.advert"), vec![]);
+        assert_eq!(parse_line(""), vec![]);
+
+        assert_eq!(
+            parse_line("ads.example.com"),
+            vec![rule("ads.example.com", false, true, false)]
+        );
+        assert_eq!(
+            parse_line("  ads.example.com # Inline comment"),
+            vec![rule("ads.example.com", false, true, false)]
+        );
+        assert_eq!(
+            parse_line("*.ads.example.com"),
+            vec![rule("ads.example.com", false, false, true)]
+        );
+
+        assert_eq!(
+            parse_line("0.0.0.0 ads.example.com tracker.example.com"),
+            vec![
+                rule("ads.example.com", false, true, false),
+                rule("tracker.example.com", false, true, false)
+            ]
+        );
+        assert_eq!(parse_line("127.0.0.1\tlocalhost"), vec![]);
+        assert_eq!(parse_line("::1 localhost ip6-localhost"), vec![]);
+        assert_eq!(parse_line("0.0.0.0 0.0.0.0"), vec![]);
+
+        assert_eq!(
+            parse_line("||ads.example.com^"),
+            vec![rule("ads.example.com", false, true, true)]
+        );
+        assert_eq!(
+            parse_line("@@||cdn.example.com^"),
+            vec![rule("cdn.example.com", true, true, true)]
+        );
+        assert_eq!(parse_line("||ads.example.com^$third-party"), vec![]);
+        assert_eq!(parse_line("||example.com/ads/*"), vec![]);
+        assert_eq!(parse_line("/banner/*/img^"), vec![]);
+        assert_eq!(parse_line("two names.example.com"), vec![]);
+    }
+
+    #[test]
+    fn blocked() {
+        let server_id = Uuid::new_v4();
+        let ads = SourceId::new(&server_id, SourceType::Blocklist, "ads");
+        let trackers = SourceId::new(&server_id, SourceType::Blocklist, "trackers");
+
+        let blocklists = Blocklists::default();
+        assert_eq!(blocklists.blocked(&name("ads.example.com."), None), None);
+
+        let mut blocklist = Blocklist::default();
+        blocklist.add_list(
+            r#"
+0.0.0.0 ads.example.com
+||Tracker.Example.org^
+@@||safe.tracker.example.org^
+*.wild.example.net
+"#,
+            false,
+        );
+        blocklist.add_list("ok.tracker.example.org\n", true);
+        blocklists.replace(&ads, blocklist);
+
+        let mut blocklist = Blocklist {
+            response: BlockResponse::NxDomain,
+            ..Default::default()
+        };
+        blocklist.add_list("||telemetry.example.com^\n||allowed.example.com^", false);
+        blocklist.add_list("allowed.example.com", true);
+        blocklists.replace(&trackers, blocklist);
+
+        let blocked = |n: &str| blocklists.blocked(&name(n), None);
+
+        assert_eq!(blocked("ads.example.com."), Some(BlockResponse::Null));
+        assert_eq!(blocked("ADS.example.com."), Some(BlockResponse::Null));
+        assert_eq!(blocked("www.ads.example.com."), None);
+        assert_eq!(blocked("example.com."), None);
+
+        assert_eq!(blocked("tracker.example.org."), Some(BlockResponse::Null));
+        assert_eq!(
+            blocked("a.b.tracker.example.org."),
+            Some(BlockResponse::Null)
+        );
+        assert_eq!(blocked("safe.tracker.example.org."), None);
+        assert_eq!(blocked("www.safe.tracker.example.org."), None);
+        assert_eq!(blocked("ok.tracker.example.org."), None);
+        assert_eq!(
+            blocked("www.ok.tracker.example.org."),
+            Some(BlockResponse::Null)
+        );
+
+        assert_eq!(blocked("wild.example.net."), None);
+        assert_eq!(blocked("www.wild.example.net."), Some(BlockResponse::Null));
+
+        assert_eq!(
+            blocked("telemetry.example.com."),
+            Some(BlockResponse::NxDomain)
+        );
+        assert_eq!(blocked("allowed.example.com."), None);
+        assert_eq!(
+            blocked("www.allowed.example.com."),
+            Some(BlockResponse::NxDomain)
+        );
+
+        // An allow-list in one source overrides blocks in another.
+        let mut blocklist = Blocklist::default();
+        blocklist.add_list("telemetry.example.com", true);
+        blocklists.replace(&ads, blocklist);
+        assert_eq!(blocked("telemetry.example.com."), None);
+        assert_eq!(blocked("ads.example.com."), None);
+
+        blocklists.prune_sources(&HashSet::from([ads]));
+        assert_eq!(blocked("www.allowed.example.com."), None);
+    }
+
+    #[test]
+    fn order() {
+        let server_id = Uuid::new_v4();
+        let blocklists = Blocklists::default();
+
+        let list = |response: BlockResponse| {
+            let mut blocklist = Blocklist {
+                response,
+                ..Default::default()
+            };
+            blocklist.add_list("ads.example.com", false);
+            blocklist
+        };
+
+        // The first list by source name decides the response, whatever the
+        // order that the lists were loaded in.
+        blocklists.replace(
+            &SourceId::new(&server_id, SourceType::Blocklist, "b"),
+            list(BlockResponse::NxDomain),
+        );
+        blocklists.replace(
+            &SourceId::new(&server_id, SourceType::Blocklist, "a"),
+            list(BlockResponse::Null),
+        );
+        blocklists.replace(
+            &SourceId::new(&server_id, SourceType::Blocklist, "c"),
+            list(BlockResponse::NxDomain),
+        );
+
+        assert_eq!(
+            blocklists.blocked(&name("ads.example.com."), None),
+            Some(BlockResponse::Null)
+        );
+    }
+}
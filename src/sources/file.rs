@@ -179,6 +179,24 @@ www.home.local:
   - 10.14.23.123
   - 1af2:cac:8e12:5b00::2
 other.home.local: www.home.local
+home.local:
+  - type: MX
+    value:
+      preference: 10
+      exchange: mail.home.local
+  - type: TXT
+    value: v=spf1 mx -all
+  - type: CAA
+    value:
+      tag: issue
+      value: letsencrypt.org
+_http._tcp.home.local:
+  type: SRV
+  value:
+    priority: 0
+    weight: 5
+    port: 80
+    target: www.home.local
 "#,
         )
         .await;
@@ -202,7 +220,7 @@ other.home.local: www.home.local
             .wait_for_records(|records| records.has_name(&name("www.home.local.")))
             .await;
 
-        assert_eq!(records.len(), 3);
+        assert_eq!(records.len(), 7);
 
         assert!(records.contains(
             &fqdn("www.home.local"),
@@ -219,6 +237,38 @@ other.home.local: www.home.local
             &RData::Aname(fqdn("www.home.local"))
         ));
 
+        assert!(records.contains(
+            &fqdn("home.local"),
+            &RData::Mx {
+                preference: 10,
+                exchange: fqdn("mail.home.local")
+            }
+        ));
+
+        assert!(records.contains(
+            &fqdn("home.local"),
+            &RData::Txt(vec!["v=spf1 mx -all".to_string()])
+        ));
+
+        assert!(records.contains(
+            &fqdn("home.local"),
+            &RData::Caa {
+                critical: false,
+                tag: "issue".to_string(),
+                value: "letsencrypt.org".to_string()
+            }
+        ));
+
+        assert!(records.contains(
+            &fqdn("_http._tcp.home.local"),
+            &RData::Srv {
+                priority: 0,
+                weight: 5,
+                port: 80,
+                target: fqdn("www.home.local")
+            }
+        ));
+
         write_file(
             &zone_file,
             r#"
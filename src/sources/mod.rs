@@ -4,6 +4,7 @@ use std::{
     mem::forget,
 };
 
+use anyhow::anyhow;
 use chrono::{DateTime, Utc};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
@@ -19,11 +20,14 @@ use crate::{
     Error, ServerId,
 };
 
+pub(crate) mod blocklist;
 pub(crate) mod dhcp;
 pub(crate) mod docker;
 pub(crate) mod file;
 pub(crate) mod remote;
+pub(crate) mod rpz;
 pub(crate) mod traefik;
+pub(crate) mod update;
 
 trait SourceConfig: PartialEq {
     fn source_type() -> SourceType;
@@ -80,6 +84,9 @@ pub(crate) enum SourceType {
     Docker,
     Remote,
     Traefik,
+    Update,
+    Blocklist,
+    Rpz,
 }
 
 derive_display_from_serialize!(SourceType);
@@ -112,6 +119,42 @@ impl fmt::Display for SourceId {
     }
 }
 
+/// Selects the sources of a type, or a single named source written as
+/// `type:name`.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct SourceSelector {
+    source_type: SourceType,
+    source_name: Option<String>,
+}
+
+impl SourceSelector {
+    pub(crate) fn matches(&self, source_id: &SourceId) -> bool {
+        self.source_type == source_id.source_type
+            && self
+                .source_name
+                .as_ref()
+                .is_none_or(|name| *name == source_id.source_name)
+    }
+}
+
+impl TryFrom<String> for SourceSelector {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (source_type, source_name) = match value.split_once(':') {
+            Some((source_type, source_name)) => (source_type, Some(source_name.to_owned())),
+            None => (value.as_str(), None),
+        };
+
+        Ok(Self {
+            source_type: serde_plain::from_str(source_type)
+                .map_err(|_| anyhow!("Unknown source type '{source_type}'"))?,
+            source_name,
+        })
+    }
+}
+
 pub(crate) trait IntoSourceRecordSet {
     fn into_source_record_set(self, source_id: &SourceId) -> Vec<SourceRecords>;
 }
@@ -162,6 +205,15 @@ pub(crate) struct SourcesConfig {
 
     #[serde(default)]
     pub remote: HashMap<String, remote::RemoteConfig>,
+
+    #[serde(default)]
+    pub(crate) update: HashMap<String, update::UpdateConfig>,
+
+    #[serde(default)]
+    pub(crate) blocklist: HashMap<String, blocklist::BlocklistConfig>,
+
+    #[serde(default)]
+    pub(crate) rpz: HashMap<String, rpz::RpzConfig>,
 }
 
 pub(crate) struct Sources {
@@ -247,6 +299,12 @@ impl Sources {
                 .await;
             self.list_sources(&config.sources.remote, &mut seen_sources)
                 .await;
+            self.list_sources(&config.sources.update, &mut seen_sources)
+                .await;
+            self.list_sources(&config.sources.blocklist, &mut seen_sources)
+                .await;
+            self.list_sources(&config.sources.rpz, &mut seen_sources)
+                .await;
 
             let all = self.sources.keys().cloned().collect::<HashSet<SourceId>>();
             for old in all.difference(&seen_sources) {
@@ -268,6 +326,19 @@ impl Sources {
         self.spawn_sources(config.sources.file, old_config.map(|c| &c.sources.file))
             .await;
 
+        // Dynamic updates are assumed to not need any additional resolution.
+        self.spawn_sources(config.sources.update, old_config.map(|c| &c.sources.update))
+            .await;
+
+        // Blocklists and response policy zones don't provide records.
+        self.spawn_sources(
+            config.sources.blocklist,
+            old_config.map(|c| &c.sources.blocklist),
+        )
+        .await;
+        self.spawn_sources(config.sources.rpz, old_config.map(|c| &c.sources.rpz))
+            .await;
+
         // Docker hostname may depend on DHCP records above.
         self.spawn_sources(config.sources.docker, old_config.map(|c| &c.sources.docker))
             .await;
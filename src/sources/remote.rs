@@ -193,7 +193,8 @@ mod tests {
 
     use crate::{
         api::{ApiConfig, ApiServer},
-        dns::{Fqdn, RData, Record, RecordSet},
+        config::Zones,
+        dns::{Fqdn, RData, Record, RecordSet, ServerState},
         sources::{remote::RemoteConfig, RecordStore, SourceConfig, SourceId, SourceType},
         test::{
             assert_single_response, fqdn, name, rdata_a, wait_for_missing_response,
@@ -249,10 +250,25 @@ mod tests {
                 ),
                 (
                     &remote_source_2,
-                    &[(
-                        fqdn("www.test.local"),
-                        RData::A("10.4.2.4".parse().unwrap()),
-                    )],
+                    &[
+                        (
+                            fqdn("www.test.local"),
+                            RData::A("10.4.2.4".parse().unwrap()),
+                        ),
+                        (
+                            fqdn("_http._tcp.test.local"),
+                            RData::Srv {
+                                priority: 10,
+                                weight: 5,
+                                port: 8080,
+                                target: fqdn("www.test.local"),
+                            },
+                        ),
+                        (
+                            fqdn("test.local"),
+                            RData::Txt(vec!["v=spf1 -all".to_string()]),
+                        ),
+                    ],
                 ),
             ],
         )
@@ -262,7 +278,13 @@ mod tests {
             address: SocketAddr::new(Ipv4Addr::from_str("0.0.0.0").unwrap().into(), 0),
         };
 
-        let api = ApiServer::new(&api_config, local_server, record_store.clone()).unwrap();
+        let api = ApiServer::new(
+            &api_config,
+            local_server,
+            record_store.clone(),
+            ServerState::new(record_store.receiver(), Zones::default()),
+        )
+        .unwrap();
 
         let record_store = RecordStore::new();
 
@@ -286,7 +308,7 @@ mod tests {
             .wait_for_records(|records| records.has_name(&name("www.test.local.")))
             .await;
 
-        assert_eq!(records.len(), 2);
+        assert_eq!(records.len(), 4);
 
         assert!(records.contains(
             &fqdn("www.test.local"),
@@ -298,6 +320,21 @@ mod tests {
             &RData::A("10.4.2.4".parse().unwrap())
         ));
 
+        assert!(records.contains(
+            &fqdn("_http._tcp.test.local"),
+            &RData::Srv {
+                priority: 10,
+                weight: 5,
+                port: 8080,
+                target: fqdn("www.test.local"),
+            }
+        ));
+
+        assert!(records.contains(
+            &fqdn("test.local"),
+            &RData::Txt(vec!["v=spf1 -all".to_string()])
+        ));
+
         build_records(
             &record_store,
             [(
@@ -448,7 +485,13 @@ sources:
             address: SocketAddr::new(Ipv4Addr::from_str("0.0.0.0").unwrap().into(), 8032),
         };
 
-        let api = ApiServer::new(&api_config, ServerId::new_v4(), record_store.clone()).unwrap();
+        let api = ApiServer::new(
+            &api_config,
+            ServerId::new_v4(),
+            record_store.clone(),
+            ServerState::new(record_store.receiver(), Zones::default()),
+        )
+        .unwrap();
 
         wait_for_response(
             localns_address,
@@ -493,7 +536,13 @@ sources:
         )
         .await;
 
-        let api = ApiServer::new(&api_config, ServerId::new_v4(), record_store.clone()).unwrap();
+        let api = ApiServer::new(
+            &api_config,
+            ServerId::new_v4(),
+            record_store.clone(),
+            ServerState::new(record_store.receiver(), Zones::default()),
+        )
+        .unwrap();
 
         wait_for_response(
             localns_address,
@@ -0,0 +1,846 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail};
+use figment::value::magic::RelativePathBuf;
+use futures::StreamExt;
+use hickory_client::{
+    client::{AsyncClient, ClientHandle},
+    op::ResponseCode,
+    proto::{iocompat::AsyncIoTokioAsStd, serialize::txt::Parser},
+    rr::{self, Name, RecordType},
+    tcp::TcpClientStream,
+};
+use reqwest::Client;
+use serde::Deserialize;
+use tokio::{
+    net::TcpStream,
+    time::{self, sleep},
+};
+use tracing::instrument;
+
+use crate::{
+    config::View,
+    dns::Fqdn,
+    run_loop::{LoopResult, RunLoop},
+    sources::{RecordStore, SourceConfig, SourceHandle, SourceId, SourceType},
+    util::{Address, Network},
+    watcher::{watch, FileEvent, WatchListener},
+    Error,
+};
+
+/// Zones transferred from a server are transferred again hourly by default.
+const REFRESH_INTERVAL_MS: u64 = 60 * 60 * 1000;
+
+/// How soon to try again after failing to transfer a zone.
+const RETRY_INTERVAL_MS: u64 = 60 * 1000;
+
+/// How long a zone transfer may take before it is abandoned.
+const TRANSFER_TIMEOUT_MS: u64 = 5 * 60 * 1000;
+
+const CLIENT_IP_LABEL: &[u8] = b"rpz-client-ip";
+const RESPONSE_IP_LABEL: &[u8] = b"rpz-ip";
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub(crate) struct RpzConfig {
+    zone: Fqdn,
+
+    #[serde(default)]
+    file: Option<RelativePathBuf>,
+
+    /// A server to transfer the zone from.
+    #[serde(default)]
+    server: Option<Address>,
+
+    #[serde(default)]
+    interval_ms: Option<u64>,
+}
+
+/// What to do with a response that matches a trigger.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Policy {
+    NxDomain,
+    NoData,
+    /// Leaves the response unchanged and stops looking for other triggers.
+    Passthru,
+    /// Replaces the response with these records, named for the trigger.
+    LocalData(Vec<rr::Record>),
+}
+
+/// A trigger that matched a response.
+#[derive(Debug)]
+pub(crate) struct PolicyMatch {
+    /// The position in the response's CNAME chain that the policy applies
+    /// from.
+    pub(crate) index: usize,
+    pub(crate) policy: Policy,
+    pub(crate) soa: Option<rr::Record>,
+}
+
+/// The triggers from a single response policy zone.
+#[derive(Debug, Default)]
+pub(crate) struct PolicyZone {
+    soa: Option<rr::Record>,
+    qnames: HashMap<Name, Policy>,
+    /// Triggers that match the names below a name.
+    wildcards: HashMap<Name, Policy>,
+    client_ips: Vec<(Network, Policy)>,
+    response_ips: Vec<(Network, Policy)>,
+}
+
+/// Parses the address encoded in the labels of an IP trigger, such as
+/// `24.0.2.0.192` for `192.0.2.0/24` or `48.zz.db8.2001` for `2001:db8::/48`.
+fn parse_network(labels: &[&[u8]]) -> Result<Network, Error> {
+    let labels = labels
+        .iter()
+        .map(|label| std::str::from_utf8(label))
+        .collect::<Result<Vec<&str>, _>>()?;
+
+    let Some((prefix, address)) = labels.split_first() else {
+        bail!("Missing prefix length");
+    };
+    let prefix: u8 = prefix.parse()?;
+
+    // Only exactly four decimal octets are an IPv4 address, IPv6 addresses
+    // may also have labels that look like octets.
+    let octets: Option<Vec<u8>> = address
+        .iter()
+        .rev()
+        .map(|label| label.parse().ok())
+        .collect();
+
+    let address = if let Some(Ok(octets)) = octets.map(<[u8; 4]>::try_from) {
+        IpAddr::V4(Ipv4Addr::from(octets))
+    } else {
+        let mut segments = Vec::new();
+        for label in address.iter().rev() {
+            if label.eq_ignore_ascii_case("zz") {
+                let zeros = (8 + 1_usize)
+                    .checked_sub(address.len())
+                    .ok_or_else(|| anyhow!("Too many labels for an address"))?;
+                segments.extend(std::iter::repeat_n(0, zeros));
+            } else {
+                segments.push(u16::from_str_radix(label, 16)?);
+            }
+        }
+
+        let segments: [u16; 8] = segments
+            .try_into()
+            .map_err(|_| anyhow!("Wrong number of labels for an address"))?;
+        IpAddr::V6(Ipv6Addr::from(segments))
+    };
+
+    let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+    if prefix > max_prefix {
+        bail!("Prefix length {prefix} is too long");
+    }
+
+    Ok(Network::containing(&address, prefix))
+}
+
+/// Works out the policy from the records for a trigger.
+fn parse_policy(records: Vec<rr::Record>) -> Result<Policy, Error> {
+    let mut local_data = Vec::new();
+
+    for record in records {
+        if let Some(rr::RData::CNAME(target)) = record.data() {
+            let target = &target.0;
+
+            if target.is_root() {
+                return Ok(Policy::NxDomain);
+            }
+
+            if target.is_wildcard() {
+                if target.base_name().is_root() {
+                    return Ok(Policy::NoData);
+                }
+                bail!("Unsupported wildcard target {target}");
+            }
+
+            match target.to_ascii().to_ascii_lowercase().as_str() {
+                "rpz-passthru." => return Ok(Policy::Passthru),
+                "rpz-drop." | "rpz-tcp-only." => bail!("Unsupported action {target}"),
+                _ => {}
+            }
+        }
+
+        local_data.push(record);
+    }
+
+    Ok(Policy::LocalData(local_data))
+}
+
+impl PolicyZone {
+    #[instrument(level = "debug", name = "rpz_parse", fields(%zone), skip(records))]
+    fn new(zone: &Fqdn, records: Vec<rr::Record>) -> Self {
+        let mut policy_zone = PolicyZone::default();
+        let mut triggers: HashMap<Name, Vec<rr::Record>> = HashMap::new();
+
+        for record in records {
+            if record.record_type() == RecordType::SOA {
+                policy_zone.soa = Some(record);
+                continue;
+            }
+
+            if matches!(
+                record.record_type(),
+                RecordType::NS | RecordType::RRSIG | RecordType::NSEC | RecordType::NSEC3
+            ) || !zone.zone_of(record.name())
+                || **zone == *record.name()
+            {
+                continue;
+            }
+
+            triggers
+                .entry(record.name().clone())
+                .or_default()
+                .push(record);
+        }
+
+        let origin_labels = zone.iter().count();
+
+        for (owner, records) in triggers {
+            let mut labels: Vec<&[u8]> = owner.iter().collect();
+            labels.truncate(labels.len() - origin_labels);
+
+            let result = parse_policy(records).and_then(|policy| {
+                match labels.split_last() {
+                    Some((last, address)) if last.eq_ignore_ascii_case(CLIENT_IP_LABEL) => {
+                        policy_zone
+                            .client_ips
+                            .push((parse_network(address)?, policy));
+                    }
+                    Some((last, address)) if last.eq_ignore_ascii_case(RESPONSE_IP_LABEL) => {
+                        policy_zone
+                            .response_ips
+                            .push((parse_network(address)?, policy));
+                    }
+                    Some((last, _)) if last.starts_with(b"rpz-") => {
+                        bail!("Unsupported trigger");
+                    }
+                    _ => {
+                        let mut name = Name::from_labels(labels)?;
+                        name.set_fqdn(true);
+                        if name.is_wildcard() {
+                            policy_zone.wildcards.insert(name.base_name(), policy);
+                        } else {
+                            policy_zone.qnames.insert(name, policy);
+                        }
+                    }
+                }
+
+                Ok(())
+            });
+
+            if let Err(e) = result {
+                tracing::warn!(error = %e, %owner, "Ignoring response policy");
+            }
+        }
+
+        tracing::debug!(
+            qnames = policy_zone.qnames.len() + policy_zone.wildcards.len(),
+            client_ips = policy_zone.client_ips.len(),
+            response_ips = policy_zone.response_ips.len(),
+            "Loaded response policy zone"
+        );
+
+        policy_zone
+    }
+
+    fn qname(&self, name: &Name) -> Option<&Policy> {
+        if let Some(policy) = self.qnames.get(name) {
+            return Some(policy);
+        }
+
+        let mut parent = name.base_name();
+        loop {
+            if let Some(policy) = self.wildcards.get(&parent) {
+                return Some(policy);
+            }
+
+            if parent.is_root() {
+                return None;
+            }
+            parent = parent.base_name();
+        }
+    }
+
+    fn ip<'a>(networks: &'a [(Network, Policy)], ip: &IpAddr) -> Option<&'a Policy> {
+        networks
+            .iter()
+            .filter(|(network, _)| network.contains(ip))
+            .max_by_key(|(network, _)| network.prefix)
+            .map(|(_, policy)| policy)
+    }
+
+    /// Finds the trigger that matches a response. Client IP triggers take
+    /// precedence over QNAME triggers which take precedence over response IP
+    /// triggers.
+    fn find(
+        &self,
+        client: Option<&IpAddr>,
+        chain: &[Name],
+        addresses: &[(usize, IpAddr)],
+    ) -> Option<(usize, &Policy)> {
+        if let Some(policy) = client.and_then(|client| Self::ip(&self.client_ips, client)) {
+            return Some((0, policy));
+        }
+
+        for (index, name) in chain.iter().enumerate() {
+            if let Some(policy) = self.qname(name) {
+                return Some((index, policy));
+            }
+        }
+
+        addresses
+            .iter()
+            .filter_map(|(index, ip)| Some((*index, Self::ip(&self.response_ips, ip)?)))
+            .min_by_key(|(index, _)| *index)
+    }
+}
+
+/// The response policy zones loaded by each rpz source. Zones are checked in
+/// the order of their source names and the first zone with a matching trigger
+/// decides the policy.
+#[derive(Debug, Default)]
+pub(crate) struct ResponsePolicies {
+    zones: RwLock<Vec<(SourceId, PolicyZone)>>,
+}
+
+impl ResponsePolicies {
+    fn replace(&self, source_id: &SourceId, policy_zone: PolicyZone) {
+        let mut zones = self.zones.write().unwrap();
+        zones.retain(|(id, _)| id != source_id);
+        zones.push((source_id.clone(), policy_zone));
+        zones.sort_by(|(a, _), (b, _)| a.source_name.cmp(&b.source_name));
+    }
+
+    pub(crate) fn prune_sources(&self, keep: &HashSet<SourceId>) {
+        self.zones
+            .write()
+            .unwrap()
+            .retain(|(source_id, _)| keep.contains(source_id));
+    }
+
+    /// Finds the policy for a response given the client's address, the names
+    /// in the response's CNAME chain starting with the queried name and the
+    /// addresses that the names in the chain resolved to.
+    pub(crate) fn find(
+        &self,
+        client: Option<&IpAddr>,
+        chain: &[Name],
+        addresses: &[(usize, IpAddr)],
+        view: Option<&View>,
+    ) -> Option<PolicyMatch> {
+        let zones = self.zones.read().unwrap();
+
+        zones
+            .iter()
+            .filter(|(source_id, _)| view.is_none_or(|view| view.includes_source(source_id)))
+            .find_map(|(_, zone)| {
+                let (index, policy) = zone.find(client, chain, addresses)?;
+
+                Some(PolicyMatch {
+                    index,
+                    policy: policy.clone(),
+                    soa: zone.soa.clone(),
+                })
+            })
+    }
+}
+
+fn parse_zone_file(zone: &Fqdn, path: &Path) -> Result<Vec<rr::Record>, Error> {
+    let contents = std::fs::read_to_string(path)?;
+    let (_, record_sets) =
+        Parser::new(contents, Some(path.to_owned()), Some(zone.name())).parse()?;
+
+    Ok(record_sets
+        .values()
+        .flat_map(|record_set| record_set.records_without_rrsigs().cloned())
+        .collect())
+}
+
+/// Transfers the zone from the server, giving up if the whole transfer takes
+/// longer than the timeout.
+#[instrument(level = "debug", name = "rpz_transfer", fields(%zone, %server), skip(timeout), err)]
+async fn transfer_zone(
+    zone: &Fqdn,
+    server: &Address,
+    timeout: Duration,
+) -> Result<Vec<rr::Record>, Error> {
+    let transfer = async {
+        let (stream, sender) =
+            TcpClientStream::<AsyncIoTokioAsStd<TcpStream>>::new(server.to_socket_address(53));
+        let (mut client, bg) = AsyncClient::new(stream, sender, None).await?;
+        tokio::spawn(bg);
+
+        let mut records = Vec::new();
+        let mut responses = client.zone_transfer(zone.name(), None);
+
+        while let Some(response) = responses.next().await {
+            let response = response?;
+            if response.response_code() != ResponseCode::NoError {
+                bail!("Zone transfer failed: {}", response.response_code());
+            }
+
+            records.extend_from_slice(response.answers());
+        }
+
+        Ok(records)
+    };
+
+    time::timeout(timeout, transfer)
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Timed out after {}ms", timeout.as_millis())))
+}
+
+struct ZoneFileWatcher {
+    source_id: SourceId,
+    zone: Fqdn,
+    zone_file: PathBuf,
+    record_store: RecordStore,
+}
+
+impl ZoneFileWatcher {
+    fn load(&self) {
+        match parse_zone_file(&self.zone, &self.zone_file) {
+            Ok(records) => self
+                .record_store
+                .policies
+                .replace(&self.source_id, PolicyZone::new(&self.zone, records)),
+            // The last zone that was read successfully remains in use.
+            Err(e) => tracing::warn!(error = %e, "Failed to read response policy zone file"),
+        }
+    }
+}
+
+impl WatchListener for ZoneFileWatcher {
+    async fn event(&mut self, _: FileEvent) {
+        self.load();
+    }
+}
+
+async fn transfer_loop(
+    record_store: RecordStore,
+    source_id: SourceId,
+    config: RpzConfig,
+    server: Address,
+) -> LoopResult {
+    // If the transfer fails the previous version of the zone remains in use.
+    let timeout = Duration::from_millis(TRANSFER_TIMEOUT_MS);
+    match transfer_zone(&config.zone, &server, timeout).await {
+        Ok(records) => record_store
+            .policies
+            .replace(&source_id, PolicyZone::new(&config.zone, records)),
+        Err(_) => return LoopResult::Backoff,
+    }
+
+    sleep(Duration::from_millis(
+        config.interval_ms.unwrap_or(REFRESH_INTERVAL_MS),
+    ))
+    .await;
+
+    LoopResult::Sleep
+}
+
+impl SourceConfig for RpzConfig {
+    fn source_type() -> SourceType {
+        SourceType::Rpz
+    }
+
+    async fn spawn(
+        self,
+        source_id: SourceId,
+        record_store: &RecordStore,
+        _: &Client,
+    ) -> Result<SourceHandle, Error> {
+        match (&self.file, &self.server) {
+            (Some(file), None) => {
+                let listener = ZoneFileWatcher {
+                    source_id,
+                    zone: self.zone.clone(),
+                    zone_file: file.relative(),
+                    record_store: record_store.clone(),
+                };
+                listener.load();
+
+                let watcher = watch(&file.relative(), listener).await?;
+                Ok(watcher.into())
+            }
+            (None, Some(server)) => {
+                let backoff = RunLoop::new(RETRY_INTERVAL_MS);
+                let config = self.clone();
+                let server = server.clone();
+
+                let handle = tokio::spawn(backoff.run(
+                    record_store.clone(),
+                    source_id,
+                    move |record_store, source_id| {
+                        transfer_loop(record_store, source_id, config.clone(), server.clone())
+                    },
+                ));
+
+                Ok(handle.into())
+            }
+            _ => bail!("A response policy zone needs either a file or a server"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        net::{IpAddr, SocketAddr},
+        time::Duration,
+    };
+
+    use hickory_server::proto::rr::{self, Name};
+    use tempfile::TempDir;
+    use tokio::{net::TcpListener, sync::watch::channel};
+    use uuid::Uuid;
+
+    use crate::{
+        config::Config,
+        dns::{DnsServer, RData, Record, RecordSet, ServerState},
+        sources::{
+            rpz::{
+                parse_zone_file, transfer_zone, Policy, PolicyZone, ResponsePolicies,
+                ZoneFileWatcher,
+            },
+            RecordStore, SourceId, SourceType,
+        },
+        test::{fqdn, name, write_file},
+        util::Address,
+    };
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn chain(names: &[&str]) -> Vec<Name> {
+        names.iter().map(|n| name(n)).collect()
+    }
+
+    fn local_data(policy: Option<(usize, &Policy)>) -> Vec<String> {
+        match policy {
+            Some((_, Policy::LocalData(records))) => records
+                .iter()
+                .map(|record| record.data().unwrap().to_string())
+                .collect(),
+            _ => panic!("Expected local data"),
+        }
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn parse() {
+        let temp = TempDir::new().unwrap();
+        let zone_file = temp.path().join("rpz.zone");
+
+        write_file(
+            &zone_file,
+            r#"
+$TTL 300
+@ IN SOA localhost. admin.localhost. 1 3600 600 86400 300
+  IN NS localhost.
+blocked.example.com CNAME .
+*.blocked.example.com CNAME .
+*.nodata.example.com CNAME *.
+safe.nodata.example.com CNAME rpz-passthru.
+local.example.com A 10.10.5.10
+local.example.com TXT "replaced"
+dropped.example.com CNAME rpz-drop.
+ns.example.com.rpz-nsdname CNAME .
+24.0.5.10.10.rpz-ip CNAME .
+32.3.5.10.10.rpz-ip A 10.10.5.11
+48.zz.db8.2001.rpz-ip CNAME *.
+64.zz.4.3.2.1.rpz-ip CNAME .
+32.1.1.10.10.rpz-client-ip CNAME rpz-passthru.
+"#,
+        )
+        .await;
+
+        let zone = fqdn("rpz.local.");
+        let policy_zone = PolicyZone::new(&zone, parse_zone_file(&zone, &zone_file).unwrap());
+
+        assert_eq!(
+            *policy_zone.soa.as_ref().unwrap().name(),
+            name("rpz.local.")
+        );
+
+        let find = |client: Option<&str>, names: &[&str], addresses: &[(usize, &str)]| {
+            let addresses: Vec<(usize, IpAddr)> = addresses
+                .iter()
+                .map(|(index, address)| (*index, ip(address)))
+                .collect();
+            let client = client.map(ip);
+            policy_zone
+                .find(client.as_ref(), &chain(names), &addresses)
+                .map(|(index, policy)| (index, policy.clone()))
+        };
+
+        assert_eq!(
+            find(None, &["blocked.example.com."], &[]),
+            Some((0, Policy::NxDomain))
+        );
+        assert_eq!(
+            find(None, &["www.BLOCKED.example.com."], &[]),
+            Some((0, Policy::NxDomain))
+        );
+        assert_eq!(find(None, &["example.com."], &[]), None);
+        assert_eq!(find(None, &["nodata.example.com."], &[]), None);
+        assert_eq!(
+            find(None, &["a.b.nodata.example.com."], &[]),
+            Some((0, Policy::NoData))
+        );
+        assert_eq!(
+            find(None, &["safe.nodata.example.com."], &[]),
+            Some((0, Policy::Passthru))
+        );
+
+        // Later names in the CNAME chain are checked too.
+        assert_eq!(
+            find(
+                None,
+                &[
+                    "www.home.local.",
+                    "cdn.example.org.",
+                    "blocked.example.com."
+                ],
+                &[]
+            ),
+            Some((2, Policy::NxDomain))
+        );
+
+        let mut data = local_data(policy_zone.find(None, &chain(&["local.example.com."]), &[]));
+        data.sort();
+        assert_eq!(data, vec!["10.10.5.10", "replaced"]);
+
+        // Unsupported actions and triggers are ignored.
+        assert_eq!(find(None, &["dropped.example.com."], &[]), None);
+        assert_eq!(find(None, &["ns.example.com.rpz-nsdname."], &[]), None);
+
+        // The longest matching network wins.
+        assert_eq!(
+            find(None, &["www.example.org."], &[(0, "10.10.5.4")]),
+            Some((0, Policy::NxDomain))
+        );
+        assert_eq!(
+            local_data(policy_zone.find(
+                None,
+                &chain(&["www.example.org."]),
+                &[(0, ip("10.10.5.3"))]
+            )),
+            vec!["10.10.5.11"]
+        );
+        assert_eq!(find(None, &["www.example.org."], &[(0, "10.10.6.4")]), None);
+        assert_eq!(
+            find(
+                None,
+                &["www.example.org.", "cdn.example.org."],
+                &[(1, "2001:db8:0:ff::1")]
+            ),
+            Some((1, Policy::NoData))
+        );
+        assert_eq!(
+            find(None, &["www.example.org."], &[(0, "2001:db8:1::1")]),
+            None
+        );
+        assert_eq!(
+            find(None, &["www.example.org."], &[(0, "1:2:3:4::5")]),
+            Some((0, Policy::NxDomain))
+        );
+        assert_eq!(find(None, &["www.example.org."], &[(0, "1.2.3.4")]), None);
+
+        // Client triggers take precedence.
+        assert_eq!(
+            find(Some("10.10.1.1"), &["blocked.example.com."], &[]),
+            Some((0, Policy::Passthru))
+        );
+        assert_eq!(
+            find(Some("10.10.1.2"), &["blocked.example.com."], &[]),
+            Some((0, Policy::NxDomain))
+        );
+    }
+
+    #[test]
+    fn zone_order() {
+        let server_id = Uuid::new_v4();
+        let policies = ResponsePolicies::default();
+
+        let zone = |records: Vec<(&str, RData)>| {
+            let zone = fqdn("rpz.local.");
+            let records = records
+                .into_iter()
+                .map(|(owner, rdata)| {
+                    rr::Record::from_rdata(name(owner), 300, rdata.try_into().unwrap())
+                })
+                .collect();
+            PolicyZone::new(&zone, records)
+        };
+
+        policies.replace(
+            &SourceId::new(&server_id, SourceType::Rpz, "b"),
+            zone(vec![(
+                "www.example.com.rpz.local.",
+                RData::Cname(fqdn("rpz-passthru.")),
+            )]),
+        );
+        policies.replace(
+            &SourceId::new(&server_id, SourceType::Rpz, "a"),
+            zone(vec![(
+                "*.example.com.rpz.local.",
+                RData::A("10.10.5.10".parse().unwrap()),
+            )]),
+        );
+
+        let found = policies
+            .find(None, &chain(&["www.example.com."]), &[], None)
+            .unwrap();
+        assert!(matches!(found.policy, Policy::LocalData(_)));
+
+        policies.prune_sources(&HashSet::from([SourceId::new(
+            &server_id,
+            SourceType::Rpz,
+            "b",
+        )]));
+        let found = policies
+            .find(None, &chain(&["www.example.com."]), &[], None)
+            .unwrap();
+        assert_eq!(found.policy, Policy::Passthru);
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn keep_last_zone() {
+        let temp = TempDir::new().unwrap();
+        let zone_file = temp.path().join("rpz.zone");
+
+        let watcher = ZoneFileWatcher {
+            source_id: SourceId::new(&Uuid::new_v4(), SourceType::Rpz, "rpz"),
+            zone: fqdn("rpz.local."),
+            zone_file: zone_file.clone(),
+            record_store: RecordStore::new(),
+        };
+        let blocked = || {
+            watcher
+                .record_store
+                .policies
+                .find(None, &chain(&["blocked.example.com."]), &[], None)
+                .map(|found| found.policy)
+        };
+
+        write_file(
+            &zone_file,
+            r#"
+$TTL 300
+@ IN SOA localhost. admin.localhost. 1 3600 600 86400 300
+blocked.example.com CNAME .
+"#,
+        )
+        .await;
+        watcher.load();
+        assert_eq!(blocked(), Some(Policy::NxDomain));
+
+        // A broken file leaves the previous zone in place.
+        write_file(&zone_file, "blocked.example.com CNAME (").await;
+        watcher.load();
+        assert!(logs_contain("Failed to read response policy zone file"));
+        assert_eq!(blocked(), Some(Policy::NxDomain));
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn transfer() {
+        let temp = TempDir::new().unwrap();
+
+        let address: SocketAddr = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+
+        let config_file = temp.path().join("config.yml");
+        write_file(
+            &config_file,
+            format!(
+                r#"
+server:
+  listen:
+    - "{address}"
+
+zones:
+  rpz.local:
+    allow_transfer: [127.0.0.1]
+"#
+            ),
+        )
+        .await;
+
+        let config = Config::from_file(&config_file).unwrap();
+
+        let mut records = RecordSet::new();
+        records.insert(Record::new(
+            fqdn("ads.example.com.rpz.local."),
+            RData::Cname(fqdn("rpz-passthru.")),
+        ));
+        records.insert(Record::new(
+            fqdn("local.example.com.rpz.local."),
+            RData::A("10.10.5.10".parse().unwrap()),
+        ));
+
+        let (_sender, receiver) = channel(records);
+        let server_state = ServerState::new(receiver, config.zones.clone());
+        let mut dns_server = DnsServer::new(&config.server, server_state).await.unwrap();
+
+        let zone = fqdn("rpz.local.");
+        let server = Address {
+            host: address.ip(),
+            port: Some(address.port()),
+        };
+        let policy_zone = PolicyZone::new(
+            &zone,
+            transfer_zone(&zone, &server, Duration::from_secs(5))
+                .await
+                .unwrap(),
+        );
+
+        assert!(policy_zone.soa.is_some());
+        assert_eq!(
+            policy_zone.find(None, &chain(&["ads.example.com."]), &[]),
+            Some((0, &Policy::Passthru))
+        );
+        assert_eq!(
+            local_data(policy_zone.find(None, &chain(&["local.example.com."]), &[])),
+            vec!["10.10.5.10"]
+        );
+
+        dns_server.shutdown().await;
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn stalled_transfer() {
+        // Accepts connections but never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+
+        let server = Address {
+            host: address.ip(),
+            port: Some(address.port()),
+        };
+        let result = transfer_zone(&fqdn("rpz.local."), &server, Duration::from_millis(200)).await;
+        assert!(result.is_err());
+    }
+}
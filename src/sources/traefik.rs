@@ -81,6 +81,116 @@ fn parse_hosts(rule: &str) -> Result<Vec<Fqdn>, Error> {
 
 #[instrument(level = "trace", err)]
 fn parse_single_host(rule: &str) -> Result<Vec<Fqdn>, Error> {
+    let (args, to_host): (&str, fn(&str) -> Option<String>) =
+        if let Some(args) = rule.strip_prefix("Host(") {
+            (args, |host| Some(host.to_owned()))
+        } else if let Some(args) = rule.strip_prefix("HostRegexp(") {
+            (args, host_pattern)
+        } else {
+            return Ok(Vec::new());
+        };
+
+    let Some(args) = args.strip_suffix(')') else {
+        return Ok(Vec::new());
+    };
+
+    let mut hosts = Vec::new();
+
+    for st in parse_strings(args)? {
+        let Some(host) = to_host(&st) else {
+            tracing::warn!(pattern = st, "Unable to find a domain in host pattern");
+            continue;
+        };
+
+        match Fqdn::try_from(host.as_str()) {
+            Ok(fqdn) => hosts.push(fqdn),
+            Err(e) => {
+                tracing::warn!(error=%e, hostname = host, "Invalid hostname");
+            }
+        }
+    }
+
+    Ok(hosts)
+}
+
+/// Whether a label of a pattern only matches whole labels of any name, such as
+/// `[^.]+`, `.+` or `{subdomain:[a-z]+}`, rather than a partial label like
+/// `.+example`.
+fn is_any_label(label: &str) -> bool {
+    let mut depth = 0;
+    let mut escaped = false;
+
+    for ch in label.chars() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+
+        match ch {
+            '\\' => escaped = true,
+            '[' | '{' | '(' => depth += 1,
+            ']' | '}' | ')' => depth -= 1,
+            ch if depth == 0 && (ch.is_ascii_alphanumeric() || ch == '-') => return false,
+            _ => {}
+        }
+    }
+
+    !label.is_empty()
+}
+
+/// Converts a `HostRegexp` pattern into the name that it matches. A pattern
+/// that starts with a label matching any name followed by a literal domain of
+/// at least two labels becomes a wildcard for that domain. Both Traefik v2
+/// templates such as `{subdomain:[a-z]+}.example.com` and v3 regular
+/// expressions such as `^.+\.example\.com$` are understood.
+fn host_pattern(pattern: &str) -> Option<String> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut label = String::new();
+
+    if pattern.contains('{') {
+        let mut depth = 0;
+        for ch in pattern.chars() {
+            match ch {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                '.' if depth == 0 => {
+                    labels.push(label);
+                    label = String::new();
+                    continue;
+                }
+                _ => {}
+            }
+            label.push(ch);
+        }
+        labels.push(label);
+    } else {
+        let pattern = pattern.strip_prefix('^').unwrap_or(pattern);
+        let pattern = pattern.strip_suffix('$').unwrap_or(pattern);
+        labels.extend(pattern.split("\\.").map(|label| label.to_owned()));
+    }
+
+    let literal = labels
+        .iter()
+        .rev()
+        .take_while(|label| {
+            !label.is_empty()
+                && label
+                    .chars()
+                    .all(|ch| ch.is_ascii_alphanumeric() || ch == '-')
+        })
+        .count();
+
+    if literal == labels.len() {
+        Some(labels.join("."))
+    } else if literal >= 2 && labels.len() == literal + 1 && is_any_label(&labels[0]) {
+        Some(format!("*.{}", labels[1..].join(".")))
+    } else {
+        None
+    }
+}
+
+/// Parses the comma separated list of quoted strings given to a rule.
+fn parse_strings(args: &str) -> Result<Vec<String>, Error> {
     #[derive(Debug, PartialEq, Eq)]
     enum State {
         Pre,
@@ -90,14 +200,10 @@ fn parse_single_host(rule: &str) -> Result<Vec<Fqdn>, Error> {
         Post,
     }
 
-    let mut hosts = Vec::new();
-    if !rule.starts_with("Host(") || !rule.ends_with(')') {
-        return Ok(hosts);
-    }
-
+    let mut strings = Vec::new();
     let mut state = State::Pre;
 
-    for char in rule[5..rule.len() - 1].chars() {
+    for char in args.chars() {
         state = match (state, char) {
             (State::Pre, ' ' | '\t') => State::Pre,
             (State::Pre, '`') => State::Backtick("".into()),
@@ -107,29 +213,20 @@ fn parse_single_host(rule: &str) -> Result<Vec<Fqdn>, Error> {
             }
 
             (State::Backtick(st), '`') => {
-                match Fqdn::try_from(st.as_str()) {
-                    Ok(fqdn) => hosts.push(fqdn),
-                    Err(e) => {
-                        tracing::warn!(error=%e, hostname = st, "Invalid hostname");
-                    }
-                }
+                strings.push(st);
                 State::Post
             }
             (State::Backtick(st), ch) => State::Backtick(format!("{}{}", st, ch)),
 
             (State::Quote(st), '"') => {
-                match Fqdn::try_from(st.as_str()) {
-                    Ok(fqdn) => hosts.push(fqdn),
-                    Err(e) => {
-                        tracing::warn!(error=%e, hostname = st, "Invalid hostname");
-                    }
-                }
+                strings.push(st);
                 State::Post
             }
             (State::Quote(st), '\\') => State::EscapedQuote(st),
             (State::Quote(st), ch) => State::Quote(format!("{}{}", st, ch)),
 
             (State::EscapedQuote(st), '"') => State::Quote(format!("{}\"", st)),
+            (State::EscapedQuote(st), '\\') => State::Quote(format!("{}\\", st)),
             (State::EscapedQuote(_), ch) => {
                 bail!("Unexpected character '{}' when a control character", ch);
             }
@@ -146,7 +243,7 @@ fn parse_single_host(rule: &str) -> Result<Vec<Fqdn>, Error> {
     }
 
     if state == State::Post || state == State::Pre {
-        Ok(hosts)
+        Ok(strings)
     } else {
         bail!("Unexpected end of rule (in state {:?})", state);
     }
@@ -373,6 +470,48 @@ mod tests {
             do_parse("Host(`allthethings.dev`) || Host(`foo.example.com`)"),
             vec!["allthethings.dev.", "foo.example.com."]
         );
+
+        assert_eq!(
+            do_parse("HostRegexp(`{subdomain:[a-z.]+}.dev.home.local`)"),
+            vec!["*.dev.home.local."]
+        );
+
+        assert_eq!(
+            do_parse(r"HostRegexp(`^.+\.dev\.home\.local$`)"),
+            vec!["*.dev.home.local."]
+        );
+
+        assert_eq!(
+            do_parse(r#"HostRegexp("^[a-z]+\\.example\\.com$", `^www\.example\.org$`)"#),
+            vec!["*.example.com.", "www.example.org."]
+        );
+
+        assert_eq!(
+            do_parse(r"Host(`allthethings.dev`) || HostRegexp(`^.*$`)"),
+            vec!["allthethings.dev."]
+        );
+
+        // Patterns that don't match whole labels of a domain, or would match
+        // a whole top level domain, are skipped.
+        assert_eq!(
+            do_parse(r"HostRegexp(`^.+example\.com$`)"),
+            Vec::<String>::new()
+        );
+
+        assert_eq!(
+            do_parse(r"HostRegexp(`^[^.]+\.com$`, `{name:[a-z]+}.org`)"),
+            Vec::<String>::new()
+        );
+
+        assert_eq!(
+            do_parse(r"HostRegexp(`^.+foo\.example\.com$`, `^[a-z]+\.[a-z]+\.example\.com$`)"),
+            Vec::<String>::new()
+        );
+
+        assert_eq!(
+            do_parse(r"HostRegexp(`^[^.]+\.example\.com$`)"),
+            vec!["*.example.com."]
+        );
     }
 
     #[tracing_test::traced_test]
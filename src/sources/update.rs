@@ -0,0 +1,930 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use figment::value::magic::RelativePathBuf;
+use hickory_server::proto::{
+    op::ResponseCode,
+    rr::{self, DNSClass, Name, RecordType},
+    serialize::binary::{BinDecodable, BinEncodable},
+};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    sync::{mpsc, oneshot},
+};
+use tracing::instrument;
+
+use crate::{
+    dns::{Fqdn, RData, Record, RecordSet},
+    sources::{RecordStore, SourceConfig, SourceHandle, SourceId, SourceType},
+    Error,
+};
+
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+pub(crate) struct UpdateConfig {
+    zone: Fqdn,
+
+    #[serde(default)]
+    file: Option<RelativePathBuf>,
+}
+
+/// The sections of an RFC 2136 UPDATE message.
+#[derive(Debug)]
+pub(crate) struct Update {
+    pub(crate) key: Fqdn,
+    pub(crate) prerequisites: Vec<rr::Record>,
+    pub(crate) updates: Vec<rr::Record>,
+}
+
+type UpdateSender = mpsc::UnboundedSender<(Update, oneshot::Sender<ResponseCode>)>;
+type UpdateReceiver = mpsc::UnboundedReceiver<(Update, oneshot::Sender<ResponseCode>)>;
+
+/// Routes dynamic updates to the source that accepts updates for the zone.
+#[derive(Debug, Default)]
+pub(crate) struct UpdateZones {
+    zones: Mutex<HashMap<Fqdn, UpdateSender>>,
+}
+
+impl UpdateZones {
+    fn register(&self, zone: Fqdn, sender: UpdateSender) {
+        let mut zones = self.zones.lock().unwrap();
+        zones.retain(|_, sender| !sender.is_closed());
+
+        if zones.insert(zone.clone(), sender).is_some() {
+            tracing::warn!(%zone, "Multiple sources accept updates for the same zone");
+        }
+    }
+
+    pub(crate) async fn update(&self, zone: &Fqdn, update: Update) -> ResponseCode {
+        let sender = self
+            .zones
+            .lock()
+            .unwrap()
+            .get(zone)
+            .filter(|sender| !sender.is_closed())
+            .cloned();
+
+        let Some(sender) = sender else {
+            tracing::debug!(%zone, "No source accepts updates for zone");
+            return ResponseCode::NotAuth;
+        };
+
+        let (response_sender, response) = oneshot::channel();
+        if sender.send((update, response_sender)).is_err() {
+            return ResponseCode::NotAuth;
+        }
+
+        response.await.unwrap_or(ResponseCode::ServFail)
+    }
+}
+
+fn rrset<'a>(
+    records: &'a RecordSet,
+    name: &'a Name,
+    record_type: RecordType,
+) -> impl Iterator<Item = &'a Record> {
+    records.records().filter(move |record| {
+        **record.name() == *name
+            && (record_type == RecordType::ANY || record.rdata().record_type() == record_type)
+    })
+}
+
+fn opaque_rrset<'a>(
+    opaque: &'a [rr::Record],
+    name: &'a Name,
+    record_type: RecordType,
+) -> impl Iterator<Item = &'a rr::Record> {
+    opaque.iter().filter(move |record| {
+        record.name() == name
+            && (record_type == RecordType::ANY || record.record_type() == record_type)
+    })
+}
+
+/// Checks the prerequisite section of an update against the current records
+/// from all sources and the opaque records held by the update source (RFC
+/// 2136 section 3.2).
+fn check_prerequisites(
+    zone: &Fqdn,
+    records: &RecordSet,
+    opaque: &[rr::Record],
+    prerequisites: &[rr::Record],
+) -> Result<(), ResponseCode> {
+    let mut required: HashMap<(Name, RecordType), HashSet<RData>> = HashMap::new();
+    let mut required_opaque: HashMap<(Name, RecordType), Vec<rr::RData>> = HashMap::new();
+
+    for prerequisite in prerequisites {
+        let name = prerequisite.name();
+        let record_type = prerequisite.record_type();
+
+        if !zone.zone_of(name) {
+            return Err(ResponseCode::NotZone);
+        }
+
+        let exists = || {
+            rrset(records, name, record_type).next().is_some()
+                || opaque_rrset(opaque, name, record_type).next().is_some()
+        };
+
+        match prerequisite.dns_class() {
+            DNSClass::ANY | DNSClass::NONE
+                if prerequisite.ttl() != 0 || prerequisite.data().is_some() =>
+            {
+                return Err(ResponseCode::FormErr);
+            }
+            DNSClass::ANY => {
+                if !exists() {
+                    return Err(if record_type == RecordType::ANY {
+                        ResponseCode::NXDomain
+                    } else {
+                        ResponseCode::NXRRSet
+                    });
+                }
+            }
+            DNSClass::NONE => {
+                if exists() {
+                    return Err(if record_type == RecordType::ANY {
+                        ResponseCode::YXDomain
+                    } else {
+                        ResponseCode::YXRRSet
+                    });
+                }
+            }
+            DNSClass::IN if prerequisite.ttl() == 0 => {
+                let Some(data) = prerequisite.data() else {
+                    return Err(ResponseCode::FormErr);
+                };
+
+                match RData::try_from(data) {
+                    Ok(rdata) => {
+                        required
+                            .entry((name.clone(), record_type))
+                            .or_default()
+                            .insert(rdata);
+                    }
+                    Err(_) => {
+                        let rdatas = required_opaque
+                            .entry((name.clone(), record_type))
+                            .or_default();
+                        if !rdatas.contains(data) {
+                            rdatas.push(data.clone());
+                        }
+                    }
+                }
+            }
+            _ => return Err(ResponseCode::FormErr),
+        }
+    }
+
+    // Value dependent prerequisites must exactly match the existing records.
+    for ((name, record_type), rdata) in required {
+        let existing: HashSet<RData> = rrset(records, &name, record_type)
+            .map(|record| record.rdata().clone())
+            .collect();
+
+        if existing != rdata {
+            return Err(ResponseCode::NXRRSet);
+        }
+    }
+
+    for ((name, record_type), rdata) in required_opaque {
+        let existing: Vec<&rr::RData> = opaque_rrset(opaque, &name, record_type)
+            .filter_map(|record| record.data())
+            .collect();
+
+        if existing.len() != rdata.len() || !rdata.iter().all(|data| existing.contains(&data)) {
+            return Err(ResponseCode::NXRRSet);
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the update section of an update (RFC 2136 section 3.4.1).
+fn check_updates(zone: &Fqdn, updates: &[rr::Record]) -> Result<(), ResponseCode> {
+    for update in updates {
+        if !zone.zone_of(update.name()) {
+            return Err(ResponseCode::NotZone);
+        }
+
+        let valid = match update.dns_class() {
+            DNSClass::IN => {
+                update.data().is_some()
+                    && !matches!(
+                        update.record_type(),
+                        RecordType::ANY | RecordType::AXFR | RecordType::IXFR
+                    )
+            }
+            DNSClass::ANY => {
+                update.ttl() == 0
+                    && update.data().is_none()
+                    && !matches!(update.record_type(), RecordType::AXFR | RecordType::IXFR)
+            }
+            DNSClass::NONE => update.ttl() == 0 && update.record_type() != RecordType::ANY,
+            _ => false,
+        };
+
+        if !valid {
+            return Err(ResponseCode::FormErr);
+        }
+    }
+
+    Ok(())
+}
+
+/// The contents of the update source's file. Older files only contain the
+/// list of records.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum StoredRecords {
+    Records(Vec<Record>),
+    Full {
+        records: Vec<Record>,
+        /// Opaque records in base64 encoded wire format.
+        #[serde(default)]
+        opaque: Vec<String>,
+    },
+}
+
+async fn load_records(file: &Path) -> Result<(Vec<Record>, Vec<rr::Record>), Error> {
+    let stored = match fs::read_to_string(file).await {
+        Ok(data) => serde_yaml::from_str(&data)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok((Vec::new(), Vec::new())),
+        Err(e) => return Err(e.into()),
+    };
+
+    match stored {
+        StoredRecords::Records(records) => Ok((records, Vec::new())),
+        StoredRecords::Full { records, opaque } => {
+            let opaque = opaque
+                .iter()
+                .map(|data| Ok(rr::Record::from_bytes(&STANDARD.decode(data)?)?))
+                .collect::<Result<Vec<rr::Record>, Error>>()?;
+
+            Ok((records, opaque))
+        }
+    }
+}
+
+/// Writes the records to a temporary file which then replaces the real file so
+/// that the stored updates are never left partially written.
+async fn write_records(file: &Path, stored: &StoredRecords) -> Result<(), Error> {
+    let data = serde_yaml::to_string(stored)?;
+
+    let mut temp_file = file.as_os_str().to_owned();
+    temp_file.push(".tmp");
+    let temp_file = PathBuf::from(temp_file);
+
+    fs::write(&temp_file, data).await?;
+    fs::rename(&temp_file, file).await?;
+    Ok(())
+}
+
+struct UpdateSource {
+    source_id: SourceId,
+    zone: Fqdn,
+    file: Option<PathBuf>,
+    record_store: RecordStore,
+    records: Vec<Record>,
+    /// Records with data that cannot be served, such as the DHCID records
+    /// used by DHCP servers (RFC 4701). These are only used to check the
+    /// prerequisites of later updates.
+    opaque: Vec<rr::Record>,
+}
+
+impl UpdateSource {
+    async fn run(mut self, mut receiver: UpdateReceiver) {
+        while let Some((update, response)) = receiver.recv().await {
+            let response_code = match self.apply(update) {
+                Ok(true) => {
+                    self.publish().await;
+                    self.persist().await;
+                    ResponseCode::NoError
+                }
+                Ok(false) => ResponseCode::NoError,
+                Err(response_code) => response_code,
+            };
+
+            let _ = response.send(response_code);
+        }
+    }
+
+    /// Applies an update to the records, returning whether anything changed.
+    #[instrument(level = "debug", name = "dynamic_update", fields(source_id = %self.source_id, key = %update.key), skip_all, err(Debug))]
+    fn apply(&mut self, update: Update) -> Result<bool, ResponseCode> {
+        check_prerequisites(
+            &self.zone,
+            &self.record_store.receiver().borrow(),
+            &self.opaque,
+            &update.prerequisites,
+        )?;
+        check_updates(&self.zone, &update.updates)?;
+
+        let previous = self.records.clone();
+        let previous_opaque = self.opaque.clone();
+
+        for update in update.updates {
+            let name = Fqdn::from(update.name().clone());
+            let record_type = update.record_type();
+
+            match update.dns_class() {
+                DNSClass::ANY => {
+                    self.records.retain(|record| {
+                        *record.name() != name
+                            || (record_type != RecordType::ANY
+                                && record.rdata().record_type() != record_type)
+                    });
+                    self.opaque.retain(|record| {
+                        record.name() != update.name()
+                            || (record_type != RecordType::ANY
+                                && record.record_type() != record_type)
+                    });
+                }
+                _ => {
+                    let Some(data) = update.data() else {
+                        continue;
+                    };
+
+                    let rdata = match RData::try_from(data) {
+                        Ok(rdata) => rdata,
+                        Err(e) => {
+                            tracing::debug!(error = %e, %name, "Storing opaque record");
+
+                            self.opaque.retain(|record| {
+                                record.name() != update.name() || record.data() != Some(data)
+                            });
+
+                            if update.dns_class() == DNSClass::IN {
+                                if self.conflicts_with_cname(&name, record_type) {
+                                    tracing::debug!(%name, "Ignoring record alongside a CNAME");
+                                    continue;
+                                }
+
+                                self.opaque.push(update.clone());
+                            }
+                            continue;
+                        }
+                    };
+
+                    self.records
+                        .retain(|record| *record.name() != name || *record.rdata() != rdata);
+
+                    if update.dns_class() == DNSClass::IN {
+                        if rdata == RData::Cname(name.clone()) {
+                            continue;
+                        }
+
+                        if self.conflicts_with_cname(&name, record_type) {
+                            tracing::debug!(%name, %record_type, "Ignoring record that conflicts with a CNAME");
+                            continue;
+                        }
+
+                        // A name only has a single CNAME.
+                        if record_type == RecordType::CNAME {
+                            self.records.retain(|record| {
+                                *record.name() != name
+                                    || record.rdata().record_type() != RecordType::CNAME
+                            });
+                        }
+
+                        let mut record = Record::new(name, rdata);
+                        record.ttl = Some(update.ttl());
+                        self.records.push(record);
+                    }
+                }
+            }
+        }
+
+        let changed = self.records != previous || self.opaque != previous_opaque;
+        if changed {
+            tracing::info!(records = self.records.len(), "Applied dynamic update");
+        }
+
+        Ok(changed)
+    }
+
+    /// Whether adding a record of the given type would leave a CNAME alongside
+    /// other data for the name. Such updates are ignored (RFC 2136 section
+    /// 3.4.2.2).
+    fn conflicts_with_cname(&self, name: &Fqdn, record_type: RecordType) -> bool {
+        let mut existing = self
+            .records
+            .iter()
+            .filter(|record| record.name() == name)
+            .map(|record| record.rdata().record_type())
+            .chain(
+                self.opaque
+                    .iter()
+                    .filter(|record| Fqdn::from(record.name().clone()) == *name)
+                    .map(|record| record.record_type()),
+            );
+
+        if record_type == RecordType::CNAME {
+            existing.any(|existing| existing != RecordType::CNAME)
+        } else {
+            existing.any(|existing| existing == RecordType::CNAME)
+        }
+    }
+
+    async fn publish(&self) {
+        self.record_store
+            .add_source_records(
+                &self.source_id,
+                self.records.iter().cloned().collect::<RecordSet>(),
+            )
+            .await;
+    }
+
+    async fn persist(&self) {
+        let Some(ref file) = self.file else {
+            return;
+        };
+
+        let stored = StoredRecords::Full {
+            records: self.records.clone(),
+            opaque: self
+                .opaque
+                .iter()
+                .filter_map(|record| record.to_bytes().ok())
+                .map(|data| STANDARD.encode(data))
+                .collect(),
+        };
+
+        if let Err(e) = write_records(file, &stored).await {
+            tracing::warn!(source_id = %self.source_id, error = %e, "Failed to store updated records");
+        }
+    }
+}
+
+impl SourceConfig for UpdateConfig {
+    fn source_type() -> SourceType {
+        SourceType::Update
+    }
+
+    async fn spawn(
+        self,
+        source_id: SourceId,
+        record_store: &RecordStore,
+        _: &Client,
+    ) -> Result<SourceHandle, Error> {
+        let file = self.file.as_ref().map(|file| file.relative());
+        let (records, opaque) = match file {
+            Some(ref file) => load_records(file).await?,
+            None => (Vec::new(), Vec::new()),
+        };
+
+        let source = UpdateSource {
+            source_id,
+            zone: self.zone,
+            file,
+            record_store: record_store.clone(),
+            records,
+            opaque,
+        };
+
+        source.publish().await;
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        record_store.updates.register(source.zone.clone(), sender);
+
+        Ok(tokio::spawn(source.run(receiver)).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use hickory_client::{
+        client::{AsyncClient, ClientHandle},
+        op::{Message, MessageType, OpCode, Query, ResponseCode, UpdateMessage},
+        proto::{
+            rr::dnssec::{rdata::tsig::TsigAlgorithm, tsig::TSigner},
+            xfer::{DnsHandle, FirstAnswer},
+        },
+        rr::{
+            self,
+            rdata::{CNAME, NULL},
+            DNSClass, RecordType,
+        },
+        udp::UdpClientStream,
+    };
+    use reqwest::Client;
+    use tempfile::TempDir;
+    use tokio::net::UdpSocket;
+
+    use crate::{
+        config::Config,
+        dns::{DnsServer, RData, ServerState},
+        sources::{update::load_records, RecordStore, SourceConfig, SourceId, SourceType, Sources},
+        test::{fqdn, name, rdata_a, write_file},
+    };
+
+    const SECRET: &[u8] = b"a secret used for testing updates";
+
+    async fn client(address: SocketAddr, key: Option<&str>) -> AsyncClient {
+        let signer = key.map(|key| {
+            Arc::new(
+                TSigner::new(SECRET.to_vec(), TsigAlgorithm::HmacSha256, name(key), 300).unwrap(),
+            )
+        });
+
+        let stream = UdpClientStream::<UdpSocket, TSigner>::with_timeout_and_signer(
+            address,
+            Duration::from_secs(5),
+            signer,
+        );
+        let (client, bg) = AsyncClient::connect(stream).await.unwrap();
+        tokio::spawn(bg);
+
+        client
+    }
+
+    fn a(host: &str, ip: &str) -> rr::Record {
+        rr::Record::from_rdata(name(host), 60, rdata_a(ip))
+    }
+
+    fn cname(host: &str, target: &str) -> rr::Record {
+        rr::Record::from_rdata(name(host), 60, rr::RData::CNAME(CNAME(name(target))))
+    }
+
+    fn dhcid(host: &str, id: &[u8]) -> rr::Record {
+        rr::Record::from_rdata(
+            name(host),
+            60,
+            rr::RData::Unknown {
+                code: RecordType::Unknown(49),
+                rdata: NULL::with(id.to_vec()),
+            },
+        )
+    }
+
+    /// A prerequisite that only checks whether an RRset exists.
+    fn exists(host: &str, record_type: RecordType, dns_class: DNSClass) -> rr::Record {
+        let mut record = rr::Record::with(name(host), record_type, 0);
+        record.set_dns_class(dns_class);
+        record
+    }
+
+    async fn send_update(
+        client: &mut AsyncClient,
+        zone: &str,
+        prerequisites: Vec<rr::Record>,
+        updates: Vec<rr::Record>,
+    ) -> ResponseCode {
+        let mut query = Query::new();
+        query
+            .set_name(name(zone))
+            .set_query_class(DNSClass::IN)
+            .set_query_type(RecordType::SOA);
+
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Update);
+        message.add_zone(query);
+        message.add_pre_requisites(prerequisites);
+        message.add_updates(updates);
+
+        client
+            .send(message)
+            .first_answer()
+            .await
+            .unwrap()
+            .response_code()
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dynamic_update() {
+        let temp = TempDir::new().unwrap();
+
+        let address = {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            socket.local_addr().unwrap()
+        };
+
+        let config_file = temp.path().join("config.yml");
+        write_file(
+            &config_file,
+            &format!(
+                r#"
+server:
+  listen:
+    - address: "{address}"
+      protocols: [udp]
+
+tsig_keys:
+  update.key.:
+    algorithm: hmac-sha256
+    secret: {secret}
+  other.key.:
+    secret: {secret}
+
+zones:
+  home.local:
+    update_keys: [update.key]
+  other.local:
+    update_keys: [update.key]
+
+sources:
+  update:
+    dhcp:
+      zone: home.local
+      file: updates.yml
+"#,
+                secret = STANDARD.encode(SECRET)
+            ),
+        )
+        .await;
+
+        let config = Config::from_file(&config_file).unwrap();
+
+        let record_store = RecordStore::new();
+        let server_state = ServerState::new(record_store.receiver(), config.zones.clone())
+            .with_record_store(&record_store);
+        let mut dns_server = DnsServer::new(&config.server, server_state).await.unwrap();
+        let mut sources = Sources::new(record_store.clone(), Client::new());
+        sources.install_sources(config.clone(), None).await;
+
+        let mut signed = client(address, Some("update.key.")).await;
+        let zone = name("home.local.");
+
+        let response = signed
+            .create(a("www.home.local.", "10.10.4.5"), zone.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+
+        let records = record_store.records();
+        assert!(records.contains(
+            &fqdn("www.home.local."),
+            &RData::A("10.10.4.5".parse().unwrap())
+        ));
+
+        // The record already exists.
+        let response = signed
+            .create(a("www.home.local.", "10.10.4.6"), zone.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.response_code(), ResponseCode::YXRRSet);
+
+        let response = signed
+            .append(a("www.home.local.", "10.10.4.6"), zone.clone(), true)
+            .await
+            .unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+
+        let response = signed
+            .delete_by_rdata(a("www.home.local.", "10.10.4.5"), zone.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+
+        let records = record_store.records();
+        assert_eq!(records.len(), 1);
+        assert!(records.contains(
+            &fqdn("www.home.local."),
+            &RData::A("10.10.4.6".parse().unwrap())
+        ));
+
+        // A CNAME cannot be added alongside other data, or other data
+        // alongside a CNAME.
+        let response = send_update(
+            &mut signed,
+            "home.local.",
+            Vec::new(),
+            vec![cname("www.home.local.", "other.home.local.")],
+        )
+        .await;
+        assert_eq!(response, ResponseCode::NoError);
+        assert_eq!(record_store.records().len(), 1);
+
+        let response = send_update(
+            &mut signed,
+            "home.local.",
+            Vec::new(),
+            vec![
+                cname("alias.home.local.", "www.home.local."),
+                a("alias.home.local.", "10.10.4.8"),
+            ],
+        )
+        .await;
+        assert_eq!(response, ResponseCode::NoError);
+
+        let records = record_store.records();
+        assert_eq!(records.len(), 2);
+        assert!(records.contains(
+            &fqdn("alias.home.local."),
+            &RData::Cname(fqdn("www.home.local."))
+        ));
+
+        // A new CNAME replaces the old one.
+        let response = send_update(
+            &mut signed,
+            "home.local.",
+            Vec::new(),
+            vec![cname("alias.home.local.", "other.home.local.")],
+        )
+        .await;
+        assert_eq!(response, ResponseCode::NoError);
+
+        let records = record_store.records();
+        assert_eq!(records.len(), 2);
+        assert!(records.contains(
+            &fqdn("alias.home.local."),
+            &RData::Cname(fqdn("other.home.local."))
+        ));
+
+        let response = send_update(
+            &mut signed,
+            "home.local.",
+            Vec::new(),
+            vec![exists("alias.home.local.", RecordType::ANY, DNSClass::ANY)],
+        )
+        .await;
+        assert_eq!(response, ResponseCode::NoError);
+        assert_eq!(record_store.records().len(), 1);
+
+        // The file is replaced rather than written in place.
+        assert!(temp.path().join("updates.yml").exists());
+        assert!(!temp.path().join("updates.yml.tmp").exists());
+
+        // Zones without an update source cannot be updated.
+        let response = signed
+            .create(a("www.other.local.", "10.10.4.7"), name("other.local."))
+            .await
+            .unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NotAuth);
+
+        // Updates must be signed with an allowed key.
+        let mut other = client(address, Some("other.key.")).await;
+        let response = other
+            .create(a("new.home.local.", "10.10.4.7"), zone.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+
+        let mut unsigned = client(address, None).await;
+        let response = unsigned
+            .create(a("new.home.local.", "10.10.4.7"), zone.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+
+        assert_eq!(record_store.records().len(), 1);
+
+        sources.shutdown().await;
+        dns_server.shutdown().await;
+
+        // Updated records are restored from the file.
+        let record_store = RecordStore::new();
+        let source_id = SourceId::new(&uuid::Uuid::new_v4(), SourceType::Update, "dhcp");
+        let handle = config
+            .sources
+            .update
+            .get("dhcp")
+            .unwrap()
+            .clone()
+            .spawn(source_id, &record_store, &Client::new())
+            .await
+            .unwrap();
+
+        let records = record_store.records();
+        assert_eq!(records.len(), 1);
+        assert!(records.contains(
+            &fqdn("www.home.local."),
+            &RData::A("10.10.4.6".parse().unwrap())
+        ));
+
+        handle.drop().await;
+    }
+
+    #[tracing_test::traced_test]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dhcid_prerequisites() {
+        let temp = TempDir::new().unwrap();
+
+        let address = {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            socket.local_addr().unwrap()
+        };
+
+        let config_file = temp.path().join("config.yml");
+        write_file(
+            &config_file,
+            &format!(
+                r#"
+server:
+  listen:
+    - address: "{address}"
+      protocols: [udp]
+
+tsig_keys:
+  update.key.:
+    secret: {secret}
+
+zones:
+  home.local:
+    update_keys: [update.key]
+
+sources:
+  update:
+    dhcp:
+      zone: home.local
+      file: updates.yml
+"#,
+                secret = STANDARD.encode(SECRET)
+            ),
+        )
+        .await;
+
+        let config = Config::from_file(&config_file).unwrap();
+
+        let record_store = RecordStore::new();
+        let server_state = ServerState::new(record_store.receiver(), config.zones.clone())
+            .with_record_store(&record_store);
+        let mut dns_server = DnsServer::new(&config.server, server_state).await.unwrap();
+        let mut sources = Sources::new(record_store.clone(), Client::new());
+        sources.install_sources(config.clone(), None).await;
+
+        let mut client = client(address, Some("update.key.")).await;
+
+        // Kea adds a new name along with the DHCID of the client when the
+        // name is not in use (RFC 4703 section 5.3.1).
+        let response = send_update(
+            &mut client,
+            "home.local.",
+            vec![exists(
+                "laptop.home.local.",
+                RecordType::ANY,
+                DNSClass::NONE,
+            )],
+            vec![
+                a("laptop.home.local.", "10.10.4.5"),
+                dhcid("laptop.home.local.", b"client one"),
+            ],
+        )
+        .await;
+        assert_eq!(response, ResponseCode::NoError);
+
+        // Another client cannot take over the name.
+        let response = send_update(
+            &mut client,
+            "home.local.",
+            vec![
+                exists("laptop.home.local.", RecordType::ANY, DNSClass::ANY),
+                dhcid("laptop.home.local.", b"client two")
+                    .set_ttl(0)
+                    .clone(),
+            ],
+            vec![
+                exists("laptop.home.local.", RecordType::A, DNSClass::ANY),
+                a("laptop.home.local.", "10.10.4.6"),
+            ],
+        )
+        .await;
+        assert_eq!(response, ResponseCode::NXRRSet);
+
+        // The same client can update its address (RFC 4703 section 5.3.2).
+        let response = send_update(
+            &mut client,
+            "home.local.",
+            vec![
+                exists("laptop.home.local.", RecordType::ANY, DNSClass::ANY),
+                dhcid("laptop.home.local.", b"client one")
+                    .set_ttl(0)
+                    .clone(),
+            ],
+            vec![
+                exists("laptop.home.local.", RecordType::A, DNSClass::ANY),
+                a("laptop.home.local.", "10.10.4.7"),
+            ],
+        )
+        .await;
+        assert_eq!(response, ResponseCode::NoError);
+
+        let records = record_store.records();
+        assert_eq!(records.len(), 1);
+        assert!(records.contains(
+            &fqdn("laptop.home.local."),
+            &RData::A("10.10.4.7".parse().unwrap())
+        ));
+
+        // The DHCID is stored alongside the records.
+        let (records, opaque) = load_records(&temp.path().join("updates.yml"))
+            .await
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(opaque, vec![dhcid("laptop.home.local.", b"client one")]);
+
+        sources.shutdown().await;
+        dns_server.shutdown().await;
+    }
+}
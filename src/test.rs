@@ -3,15 +3,22 @@ use std::{
     net::{Ipv4Addr, Ipv6Addr, SocketAddr},
     path::Path,
     str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
 use futures::StreamExt;
 use hickory_client::{
     client::AsyncClient,
-    op::{DnsResponse, Query, ResponseCode},
-    proto::xfer::{DnsHandle, DnsRequestOptions},
-    rr::RecordType,
+    op::{DnsResponse, Message, MessageType, Query, ResponseCode},
+    proto::{
+        serialize::binary::{BinDecodable, BinEncodable},
+        xfer::{DnsHandle, DnsRequestOptions},
+    },
+    rr::{Record, RecordType},
     udp::UdpClientStream,
 };
 use hickory_server::proto::rr::{domain::Name, rdata, RData};
@@ -103,6 +110,59 @@ impl Container {
     }
 }
 
+/// Builds a response to the request, answering with the given address or
+/// with a truncated response if there is none.
+pub(crate) fn fake_response(request: &[u8], answer: Option<&str>) -> Vec<u8> {
+    let request = Message::from_bytes(request).unwrap();
+    let mut response = Message::new();
+    response
+        .set_id(request.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request.op_code())
+        .set_recursion_desired(request.recursion_desired())
+        .add_queries(request.queries().to_vec());
+
+    match answer {
+        Some(ip) => {
+            let query = request.queries().first().unwrap();
+            response.add_answer(Record::from_rdata(
+                query.name().clone(),
+                300,
+                RData::A(ip.parse().unwrap()),
+            ));
+        }
+        None => {
+            response.set_truncated(true);
+        }
+    }
+
+    response.to_bytes().unwrap()
+}
+
+/// Answers queries over UDP after a delay, counting the queries received.
+pub(crate) async fn udp_server(
+    answer: &'static str,
+    delay: Duration,
+) -> (SocketAddr, Arc<AtomicUsize>) {
+    let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = udp.local_addr().unwrap();
+    let received = Arc::new(AtomicUsize::new(0));
+
+    let counter = received.clone();
+    tokio::spawn(async move {
+        let mut buffer = [0_u8; 512];
+        loop {
+            let (len, source) = udp.recv_from(&mut buffer).await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            let response = fake_response(&buffer[..len], Some(answer));
+            sleep(delay).await;
+            udp.send_to(&response, source).await.unwrap();
+        }
+    });
+
+    (address, received)
+}
+
 pub(crate) async fn write_file<D: AsRef<[u8]>>(path: &Path, data: D) {
     let mut file = fs::File::create(path).await.unwrap();
     file.write_all(data.as_ref()).await.unwrap();
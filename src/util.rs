@@ -1,12 +1,16 @@
 use std::{
     fmt::{self, Display},
     hash::Hash,
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     str::FromStr,
 };
 
+use anyhow::{anyhow, bail, Error};
+use hickory_server::proto::rr::Name;
 use serde::Deserialize;
 
+use crate::dns::Fqdn;
+
 pub(crate) type Host = IpAddr;
 
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Hash)]
@@ -62,6 +66,122 @@ impl From<Host> for Address {
     }
 }
 
+/// A network in CIDR notation. A bare address is a network containing just
+/// that address.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Hash)]
+#[serde(try_from = "String")]
+pub(crate) struct Network {
+    pub address: IpAddr,
+    pub prefix: u8,
+}
+
+impl Network {
+    fn max_prefix(address: &IpAddr) -> u8 {
+        match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+
+    fn bits(address: &IpAddr) -> u128 {
+        match address {
+            IpAddr::V4(ip) => u32::from(*ip).into(),
+            IpAddr::V6(ip) => u128::from(*ip),
+        }
+    }
+
+    /// The network with the given prefix length that contains an address.
+    pub(crate) fn containing(address: &IpAddr, prefix: u8) -> Self {
+        let address = address.to_canonical();
+        let prefix = prefix.min(Self::max_prefix(&address));
+
+        let shift = Self::max_prefix(&address) - prefix;
+        let mask = u128::MAX.checked_shl(shift.into()).unwrap_or(0);
+        let bits = Self::bits(&address) & mask;
+
+        let address = match address {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from(bits as u32)),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(bits)),
+        };
+
+        Self { address, prefix }
+    }
+
+    pub(crate) fn contains(&self, ip: &IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if self.address.is_ipv4() != ip.is_ipv4() {
+            return false;
+        }
+
+        let shift = Self::max_prefix(&ip) - self.prefix;
+        let mask = u128::MAX.checked_shl(shift.into()).unwrap_or(0);
+
+        Self::bits(&self.address) & mask == Self::bits(&ip) & mask
+    }
+
+    /// The origin of the reverse zone that holds the PTR records for this
+    /// network. The prefix must fall on an octet boundary for IPv4 and a nibble
+    /// boundary for IPv6.
+    pub(crate) fn reverse_zone(&self) -> Result<Fqdn, Error> {
+        let label_bits = match self.address {
+            IpAddr::V4(_) => 8,
+            IpAddr::V6(_) => 4,
+        };
+
+        if !self.prefix.is_multiple_of(label_bits) {
+            bail!("Network {self} cannot be mapped to a reverse zone, the prefix must be a multiple of {label_bits}");
+        }
+
+        // The address labels followed by in-addr.arpa or ip6.arpa.
+        let labels = self.prefix / label_bits + 2;
+        Ok(Name::from(self.address).trim_to(labels.into()).into())
+    }
+}
+
+impl Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(&format!("{}/{}", self.address, self.prefix))
+    }
+}
+
+impl FromStr for Network {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+
+        let address = address
+            .parse::<IpAddr>()
+            .map_err(|e| anyhow!("Invalid network '{value}': {e}"))?
+            .to_canonical();
+        let max_prefix = Self::max_prefix(&address);
+
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .map_err(|e| anyhow!("Invalid network '{value}': {e}"))?,
+            None => max_prefix,
+        };
+
+        if prefix > max_prefix {
+            bail!("Invalid network '{value}': prefix is too long");
+        }
+
+        Ok(Self { address, prefix })
+    }
+}
+
+impl TryFrom<String> for Network {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
 #[macro_export]
 macro_rules! event_lvl {
     ($lvl:ident, $($arg:tt)+) => {
@@ -74,3 +194,70 @@ macro_rules! event_lvl {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use crate::{test::fqdn, util::Network};
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn network() {
+        let network: Network = "10.10.0.0/16".parse().unwrap();
+        assert!(network.contains(&ip("10.10.0.1")));
+        assert!(network.contains(&ip("10.10.255.255")));
+        assert!(network.contains(&ip("::ffff:10.10.3.4")));
+        assert!(!network.contains(&ip("10.11.0.1")));
+        assert!(!network.contains(&ip("fe80::1")));
+
+        let network: Network = "192.168.1.5".parse().unwrap();
+        assert_eq!(network.to_string(), "192.168.1.5/32");
+        assert!(network.contains(&ip("192.168.1.5")));
+        assert!(!network.contains(&ip("192.168.1.6")));
+
+        let network: Network = "fd00::/8".parse().unwrap();
+        assert!(network.contains(&ip("fd12:3456::1")));
+        assert!(!network.contains(&ip("fe80::1")));
+
+        let network: Network = "0.0.0.0/0".parse().unwrap();
+        assert!(network.contains(&ip("8.8.8.8")));
+        assert!(!network.contains(&ip("::1")));
+
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("10.0.0/8".parse::<Network>().is_err());
+        assert!("10.0.0.0/x".parse::<Network>().is_err());
+
+        let network = Network::containing(&ip("10.10.5.3"), 24);
+        assert_eq!(network.to_string(), "10.10.5.0/24");
+        let network = Network::containing(&ip("::ffff:10.10.5.3"), 16);
+        assert_eq!(network.to_string(), "10.10.0.0/16");
+        let network = Network::containing(&ip("fd12:3456:789a::1"), 40);
+        assert_eq!(network.to_string(), "fd12:3456:7800::/40");
+    }
+
+    #[test]
+    fn reverse_zone() {
+        let zone = |network: &str| network.parse::<Network>().unwrap().reverse_zone();
+
+        assert_eq!(zone("10.0.0.0/8").unwrap(), fqdn("10.in-addr.arpa."));
+        assert_eq!(zone("10.10.0.0/16").unwrap(), fqdn("10.10.in-addr.arpa."));
+        assert_eq!(
+            zone("192.168.1.0/24").unwrap(),
+            fqdn("1.168.192.in-addr.arpa.")
+        );
+        assert_eq!(zone("0.0.0.0/0").unwrap(), fqdn("in-addr.arpa."));
+        assert_eq!(zone("fd00::/8").unwrap(), fqdn("d.f.ip6.arpa."));
+        assert_eq!(
+            zone("2001:db8::/32").unwrap(),
+            fqdn("8.b.d.0.1.0.0.2.ip6.arpa.")
+        );
+        assert_eq!(zone("fd12:3400::/20").unwrap(), fqdn("3.2.1.d.f.ip6.arpa."));
+
+        assert!(zone("10.10.0.0/20").is_err());
+        assert!(zone("fd00::/10").is_err());
+    }
+}